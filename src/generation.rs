@@ -1,16 +1,40 @@
+use crate::generation::choice_log::ChoiceLog;
+use crate::generation::count::DerivationCount;
+use crate::generation::decision::DecisionSource;
+use crate::generation::diagnostic::Diagnostic;
+use crate::generation::document::{GrammarDocument, RuleDefinition};
+use crate::generation::enumeration::Enumeration;
 use crate::generation::evaluation::{EvaluationContext, Registry};
-use crate::generation::expansion_tree::{ExpansionTree, ExpansionType};
-use std::collections::HashMap;
+use crate::generation::expansion_tree::{ExpansionTree, ExpansionType, NodeSelector};
+use crate::generation::filter::Filter;
+use crate::generation::lint::LintWarning;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
+mod chart;
+pub mod choice_log;
+pub mod count;
+pub mod decision;
+pub mod diagnostic;
+pub mod document;
+pub mod enumeration;
 mod evaluation;
 pub mod expansion_tree;
 pub mod filter;
+pub mod lint;
+mod probability;
 mod production;
+mod recognizer;
+#[cfg(test)]
+mod test_support;
 
 /// Contains options for grammar generation.
 pub struct Options {
     strict: bool,
-    random_source: Box<dyn rand::Rng>,
+    decision_source: Box<dyn DecisionSource>,
+    max_depth: usize,
+    max_nodes: Option<usize>,
+    depth_budget: Option<usize>,
 }
 
 /// Core struct for Calyx grammars. See the README for more guidance on the format of productions.
@@ -22,12 +46,90 @@ pub struct Grammar {
 /// Defines the possible set of errors that may occur during grammar generation.
 #[derive(Debug)]
 pub enum CalyxError {
-    UndefinedRule { rule_name: String },
-    UndefinedFilter { filter_name: String },
-    DuplicateRule { rule_name: String },
+    UndefinedRule {
+        rule_name: String,
+    },
+    UndefinedFilter {
+        filter_name: String,
+    },
+    DuplicateRule {
+        rule_name: String,
+    },
+    DuplicateFilter {
+        filter_name: String,
+    },
     ExpandedEmptyBranch,
-    InvalidExpression { expression: String },
-    InvalidWeight { weight: f64 },
+    InvalidExpression {
+        expression: String,
+        diagnostic: Box<Diagnostic>,
+    },
+    InvalidFilterArguments {
+        filter_name: String,
+        message: String,
+    },
+    InvalidRegex {
+        pattern: String,
+        message: String,
+    },
+    EmptyMapping {
+        rule_name: String,
+    },
+    InvalidWeight {
+        weight: f64,
+    },
+    InvalidDocument {
+        message: String,
+    },
+    InvalidRule {
+        rule_name: String,
+        cause: Box<CalyxError>,
+    },
+    RecursionLimitExceeded {
+        rule_path: Vec<String>,
+    },
+    NodeNotFound {
+        selector: NodeSelector,
+    },
+    InvalidChoiceLog {
+        message: String,
+    },
+    ChoiceLogMismatch {
+        position: usize,
+        rule_name: String,
+    },
+    Unsupported {
+        expression: String,
+        message: String,
+    },
+}
+
+impl CalyxError {
+    /// Records the rule that an invalid template belongs to.
+    pub(crate) fn in_rule(mut self, rule_name: &str) -> Self {
+        if let CalyxError::InvalidExpression { diagnostic, .. } = &mut self {
+            diagnostic.set_rule_name(rule_name);
+        }
+
+        self
+    }
+
+    /// Records the index of an invalid template within its rule.
+    pub(crate) fn in_template(mut self, index: usize) -> Self {
+        if let CalyxError::InvalidExpression { diagnostic, .. } = &mut self {
+            diagnostic.set_template_index(index);
+        }
+
+        self
+    }
+}
+
+impl From<Diagnostic> for CalyxError {
+    fn from(diagnostic: Diagnostic) -> Self {
+        CalyxError::InvalidExpression {
+            expression: diagnostic.fragment().to_string(),
+            diagnostic: Box::new(diagnostic),
+        }
+    }
 }
 
 impl Grammar {
@@ -63,11 +165,216 @@ impl Grammar {
         }
     }
 
+    /// Creates a new lenient grammar from a JSON document in the format read by Ruby Calyx.
+    ///
+    /// See [Self::define_json_str] for the format of the document.
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidDocument] if the document is not valid JSON or is not an object.
+    /// - [CalyxError::InvalidRule] if a rule in the document could not be parsed.
+    ///
+    pub fn from_json_str(json: &str) -> Result<Grammar, CalyxError> {
+        let mut grammar = Grammar::new();
+        grammar.define_json_str(json)?;
+        Ok(grammar)
+    }
+
+    /// Creates a new lenient grammar from a JSON file in the format read by Ruby Calyx.
+    ///
+    /// See [Self::define_json_str] for the format of the document.
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidDocument] if the file could not be read, is not valid JSON, or is
+    ///   not an object.
+    /// - [CalyxError::InvalidRule] if a rule in the document could not be parsed.
+    ///
+    pub fn load_json<P: AsRef<Path>>(path: P) -> Result<Grammar, CalyxError> {
+        let json = document::read_file(path)?;
+        Self::from_json_str(&json)
+    }
+
+    /// Defines every rule in a JSON document in the format read by Ruby Calyx.
+    ///
+    /// The document is an object mapping rule names to productions. A string value defines a
+    /// single expansion, an array of strings defines a uniform expansion, and an object of
+    /// templates to weights defines a weighted expansion.
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidDocument] if the document is not valid JSON or is not an object.
+    /// - [CalyxError::InvalidRule] if a rule in the document could not be parsed.
+    /// - [CalyxError::DuplicateRule] if a rule in the document is already defined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::Grammar;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// let json = r#"{ "start": "{greeting} world.", "greeting": ["Hello"] }"#;
+    /// assert!(grammar.define_json_str(json).is_ok());
+    ///
+    /// let text: String = grammar.generate().expect("Error during generation").flatten();
+    /// assert_eq!(text, "Hello world.");
+    /// ```
+    ///
+    pub fn define_json_str(&mut self, json: &str) -> Result<(), CalyxError> {
+        self.define_document(&GrammarDocument::from_json_str(json)?)
+    }
+
+    /// Creates a new lenient grammar from a YAML document.
+    ///
+    /// See [Self::define_yaml_str] for the format of the document.
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidDocument] if the document is not valid YAML or is not a mapping.
+    /// - [CalyxError::InvalidRule] if a rule in the document could not be parsed.
+    ///
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml_str(yaml: &str) -> Result<Grammar, CalyxError> {
+        let mut grammar = Grammar::new();
+        grammar.define_yaml_str(yaml)?;
+        Ok(grammar)
+    }
+
+    /// Creates a new lenient grammar from a YAML file.
+    ///
+    /// See [Self::define_yaml_str] for the format of the document.
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidDocument] if the file could not be read, is not valid YAML, or is
+    ///   not a mapping.
+    /// - [CalyxError::InvalidRule] if a rule in the document could not be parsed.
+    ///
+    #[cfg(feature = "yaml")]
+    pub fn load_yaml<P: AsRef<Path>>(path: P) -> Result<Grammar, CalyxError> {
+        let yaml = document::read_file(path)?;
+        Self::from_yaml_str(&yaml)
+    }
+
+    /// Defines every rule in a YAML document.
+    ///
+    /// The document has the same structure as the JSON documents read by [Self::define_json_str],
+    /// and may use any YAML string syntax for templates, including multi-line block scalars.
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidDocument] if the document is not valid YAML or is not a mapping.
+    /// - [CalyxError::InvalidRule] if a rule in the document could not be parsed.
+    /// - [CalyxError::DuplicateRule] if a rule in the document is already defined.
+    ///
+    #[cfg(feature = "yaml")]
+    pub fn define_yaml_str(&mut self, yaml: &str) -> Result<(), CalyxError> {
+        self.define_document(&GrammarDocument::from_yaml_str(yaml)?)
+    }
+
+    /// Creates a new lenient grammar from a TOML document.
+    ///
+    /// See [Self::define_toml_str] for the format of the document.
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidDocument] if the document is not valid TOML.
+    /// - [CalyxError::InvalidRule] if a rule in the document could not be parsed.
+    ///
+    #[cfg(feature = "toml")]
+    pub fn from_toml_str(toml: &str) -> Result<Grammar, CalyxError> {
+        let mut grammar = Grammar::new();
+        grammar.define_toml_str(toml)?;
+        Ok(grammar)
+    }
+
+    /// Creates a new lenient grammar from a TOML file.
+    ///
+    /// See [Self::define_toml_str] for the format of the document.
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidDocument] if the file could not be read or is not valid TOML.
+    /// - [CalyxError::InvalidRule] if a rule in the document could not be parsed.
+    ///
+    #[cfg(feature = "toml")]
+    pub fn load_toml<P: AsRef<Path>>(path: P) -> Result<Grammar, CalyxError> {
+        let toml = document::read_file(path)?;
+        Self::from_toml_str(&toml)
+    }
+
+    /// Defines every rule in a TOML document.
+    ///
+    /// The document has the same structure as the JSON documents read by [Self::define_json_str]:
+    /// weighted rules are written as tables, and multi-line templates may use TOML's
+    /// triple-quoted strings.
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidDocument] if the document is not valid TOML.
+    /// - [CalyxError::InvalidRule] if a rule in the document could not be parsed.
+    /// - [CalyxError::DuplicateRule] if a rule in the document is already defined.
+    ///
+    #[cfg(feature = "toml")]
+    pub fn define_toml_str(&mut self, toml: &str) -> Result<(), CalyxError> {
+        self.define_document(&GrammarDocument::from_toml_str(toml)?)
+    }
+
+    /// Creates a new lenient grammar from the rules of a [GrammarDocument].
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidRule] if a rule in the document could not be parsed.
+    ///
+    pub fn from_document(document: &GrammarDocument) -> Result<Grammar, CalyxError> {
+        let mut grammar = Grammar::new();
+        grammar.define_document(document)?;
+        Ok(grammar)
+    }
+
+    /// Defines every rule of a [GrammarDocument].
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidRule] if a rule in the document could not be parsed.
+    /// - [CalyxError::DuplicateRule] if a rule in the document is already defined.
+    ///
+    pub fn define_document(&mut self, document: &GrammarDocument) -> Result<(), CalyxError> {
+        for (term, definition) in document.rules() {
+            definition.define(term, self)?;
+        }
+
+        Ok(())
+    }
+
+    /// Exports every rule of this grammar to a [GrammarDocument], using the original templates and
+    /// weights the rules were defined with.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::Grammar;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// assert!(grammar.start_single(String::from("{greeting} world.")).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("greeting"), &vec![String::from("Hello"), String::from("Hi")]).is_ok());
+    ///
+    /// let json: String = grammar.to_document().to_json_string();
+    /// let copy: Grammar = Grammar::from_json_str(&json).expect("Error loading grammar");
+    /// assert_eq!(grammar.to_document(), copy.to_document());
+    /// ```
+    ///
+    pub fn to_document(&self) -> GrammarDocument {
+        let mut document = GrammarDocument::new();
+
+        for (term, rule) in self.registry.rules() {
+            document.insert(term.clone(), rule.to_definition());
+        }
+
+        for (term, table) in self.registry.mappings() {
+            document.insert(term.clone(), RuleDefinition::Mapping(table.to_pairs()));
+        }
+
+        document
+    }
+
     /// Defines a new single expansion of the `start` rule.
     ///
     /// # Errors
     /// - [CalyxError::DuplicateRule] if the start rule is already defined.
-    /// - [CalyxError::InvalidExpression] if the production could not be parsed.
+    /// - [CalyxError::InvalidExpression] if the production could not be parsed. Its diagnostic
+    ///   locates the problem within the template.
+    /// - [CalyxError::InvalidFilterArguments] if the production calls a filter with the wrong
+    ///   number of arguments.
     ///
     pub fn start_single(&mut self, production: String) -> Result<(), CalyxError> {
         self.single_rule(String::from("start"), production)
@@ -77,7 +384,10 @@ impl Grammar {
     ///
     /// # Errors
     /// - [CalyxError::DuplicateRule] if the start rule is already defined.
-    /// - [CalyxError::InvalidExpression] if the production could not be parsed.
+    /// - [CalyxError::InvalidExpression] if the production could not be parsed. Its diagnostic
+    ///   locates the problem within the template.
+    /// - [CalyxError::InvalidFilterArguments] if the production calls a filter with the wrong
+    ///   number of arguments.
     ///
     pub fn start_uniform(&mut self, production: &Vec<String>) -> Result<(), CalyxError> {
         self.uniform_rule(String::from("start"), production)
@@ -87,7 +397,10 @@ impl Grammar {
     ///
     /// # Errors
     /// - [CalyxError::DuplicateRule] if the start rule is already defined.
-    /// - [CalyxError::InvalidExpression] if the production could not be parsed.
+    /// - [CalyxError::InvalidExpression] if the production could not be parsed. Its diagnostic
+    ///   locates the problem within the template.
+    /// - [CalyxError::InvalidFilterArguments] if the production calls a filter with the wrong
+    ///   number of arguments.
     ///
     pub fn start_weighted(&mut self, production: &HashMap<String, f64>) -> Result<(), CalyxError> {
         self.weighted_rule(String::from("start"), production)
@@ -97,7 +410,10 @@ impl Grammar {
     ///
     /// # Errors
     /// - [CalyxError::DuplicateRule] if the term is already defined.
-    /// - [CalyxError::InvalidExpression] if the production could not be parsed.
+    /// - [CalyxError::InvalidExpression] if the production could not be parsed. Its diagnostic
+    ///   locates the problem within the template.
+    /// - [CalyxError::InvalidFilterArguments] if the production calls a filter with the wrong
+    ///   number of arguments.
     /// - [CalyxError::InvalidWeight] if any weight in the production is not [finite](f64::is_finite).
     ///
     pub fn single_rule(&mut self, term: String, production: String) -> Result<(), CalyxError> {
@@ -109,7 +425,10 @@ impl Grammar {
     ///
     /// # Errors
     /// - [CalyxError::DuplicateRule] if the term is already defined.
-    /// - [CalyxError::InvalidExpression] if the production could not be parsed.
+    /// - [CalyxError::InvalidExpression] if the production could not be parsed. Its diagnostic
+    ///   locates the problem within the template.
+    /// - [CalyxError::InvalidFilterArguments] if the production calls a filter with the wrong
+    ///   number of arguments.
     ///
     pub fn uniform_rule(
         &mut self,
@@ -123,7 +442,10 @@ impl Grammar {
     ///
     /// # Errors
     /// - [CalyxError::DuplicateRule] if the term is already defined.
-    /// - [CalyxError::InvalidExpression] if the production could not be parsed.
+    /// - [CalyxError::InvalidExpression] if the production could not be parsed. Its diagnostic
+    ///   locates the problem within the template.
+    /// - [CalyxError::InvalidFilterArguments] if the production calls a filter with the wrong
+    ///   number of arguments.
     /// - [CalyxError::InvalidWeight] if any weight in the production is not [finite](f64::is_finite).
     ///
     pub fn weighted_rule(
@@ -134,16 +456,195 @@ impl Grammar {
         self.registry.define_weighted_rule(term, production)
     }
 
+    /// Defines a new mapping rule, which rewrites the text of an expression with the `>` operator,
+    /// such as `{noun>plural}`.
+    ///
+    /// A mapping is an ordered list of regular expression patterns and replacements. The first
+    /// pattern that matches the text is used to replace every match in it, and text that matches
+    /// no pattern is left unchanged. Replacements may refer to capture groups, such as `${1}`.
+    ///
+    /// Mappings share the same names as rules, so a mapping cannot have the same name as a rule.
+    ///
+    /// # Errors
+    /// - [CalyxError::DuplicateRule] if a rule or mapping with the same name is already defined.
+    /// - [CalyxError::InvalidRegex] if any pattern is not a valid regular expression.
+    /// - [CalyxError::EmptyMapping] if the mapping has no patterns.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::Grammar;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// assert!(grammar.start_single(String::from("{animal>plural}")).is_ok());
+    /// assert!(grammar.single_rule(String::from("animal"), String::from("pony")).is_ok());
+    /// assert!(
+    ///     grammar
+    ///         .mapping_rule(
+    ///             String::from("plural"),
+    ///             &[
+    ///                 (String::from("(.+)y$"), String::from("${1}ies")),
+    ///                 (String::from("(.+)"), String::from("${1}s")),
+    ///             ]
+    ///         )
+    ///         .is_ok()
+    /// );
+    ///
+    /// let text: String = grammar.generate().expect("Error during generation").flatten();
+    /// assert_eq!(text, "ponies");
+    /// ```
+    ///
+    pub fn mapping_rule(
+        &mut self,
+        term: String,
+        mapping: &[(String, String)],
+    ) -> Result<(), CalyxError> {
+        self.registry.define_mapping(term, mapping)
+    }
+
+    /// Registers a custom filter, which can be applied to expressions with dot-notation in the same
+    /// way as the builtin filters.
+    ///
+    /// The filter may be any closure, so it can capture configuration such as lookup tables or
+    /// replacement maps.
+    ///
+    /// # Errors
+    /// - [CalyxError::DuplicateFilter] if a filter with the same name is already defined, including
+    ///   the builtin filters.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::Grammar;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// let suffix = String::from("!");
+    /// assert!(grammar.register_filter(String::from("exclaim"), move |s| format!("{s}{suffix}")).is_ok());
+    /// assert!(grammar.start_single(String::from("{greeting.exclaim}")).is_ok());
+    /// assert!(grammar.single_rule(String::from("greeting"), String::from("Hello")).is_ok());
+    ///
+    /// let text: String = grammar.generate().expect("Error during generation").flatten();
+    /// assert_eq!(text, "Hello!");
+    /// ```
+    ///
+    pub fn register_filter<F>(&mut self, filter_name: String, filter: F) -> Result<(), CalyxError>
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.register_shared_filter(filter_name, Filter::new(filter))
+    }
+
+    /// Registers a custom [Filter], which may accept arguments or be shared with other grammars.
+    ///
+    /// The argument count of a filter is checked when any rule that uses it is defined, so filters
+    /// should be registered before the rules that refer to them.
+    ///
+    /// # Errors
+    /// - [CalyxError::DuplicateFilter] if a filter with the same name is already defined, including
+    ///   the builtin filters.
+    ///
+    pub fn register_shared_filter(
+        &mut self,
+        filter_name: String,
+        filter: Filter,
+    ) -> Result<(), CalyxError> {
+        self.registry.define_filter(filter_name, filter)
+    }
+
+    /// Checks every rule of this grammar without generating anything, so that a broken grammar is
+    /// rejected deterministically rather than only when a broken branch happens to be chosen.
+    ///
+    /// Undefined rules and mappings are reported even if the grammar is lenient. Rules that are
+    /// only supplied as context during generation cannot be known here, and are reported as
+    /// undefined.
+    ///
+    /// # Errors
+    ///
+    /// Every problem found, ordered by rule name. Each is a [CalyxError::InvalidRule] naming the
+    /// rule that the problem was found in, with a cause of:
+    /// - [CalyxError::UndefinedRule] for a reference to a rule or mapping that is not defined.
+    /// - [CalyxError::UndefinedFilter] for a filter that is not defined.
+    /// - [CalyxError::InvalidFilterArguments] for a filter called with the wrong number of
+    ///   arguments.
+    /// - [CalyxError::ExpandedEmptyBranch] for a rule with no templates.
+    /// - [CalyxError::RecursionLimitExceeded] for a rule whose every template recurses forever,
+    ///   with a path of rules that leads back to itself.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::{CalyxError, Grammar};
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// assert!(grammar.start_single(String::from("{greeting.shout}, {name}")).is_ok());
+    /// assert!(grammar.single_rule(String::from("greeting"), String::from("Hello")).is_ok());
+    ///
+    /// let errors: Vec<CalyxError> = grammar.validate().expect_err("Expected an invalid grammar");
+    /// assert_eq!(errors.len(), 2);
+    /// ```
+    ///
+    pub fn validate(&self) -> Result<(), Vec<CalyxError>> {
+        let errors = self.registry.validate();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Looks for parts of this grammar that are valid but probably mistaken, starting from the rule
+    /// named `start`.
+    ///
+    /// The warnings are ordered by rule name, and report:
+    /// - [LintWarning::UnreachableRule] for rules and mappings that `start` never refers to.
+    /// - [LintWarning::EmptyOutput] for rules that can only ever produce the empty string.
+    /// - [LintWarning::DuplicateAlternative] for templates repeated within a uniform rule.
+    /// - [LintWarning::RedundantMemo] and [LintWarning::RedundantUnique] for `@` and `$`
+    ///   references to rules with only one template.
+    /// - [LintWarning::ShadowedRule] for references to rules whose name belongs to a mapping.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::Grammar;
+    /// use calyx_rs::generation::lint::LintWarning;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// assert!(grammar.start_single(String::from("Hello")).is_ok());
+    /// assert!(grammar.single_rule(String::from("unused"), String::from("Goodbye")).is_ok());
+    ///
+    /// let warnings: Vec<LintWarning> = grammar.lint();
+    /// assert_eq!(warnings[0].to_string(), "rule `unused` is never used");
+    /// ```
+    ///
+    pub fn lint(&self) -> Vec<LintWarning> {
+        self.lint_from(&[String::from("start")])
+    }
+
+    /// Looks for parts of this grammar that are valid but probably mistaken, treating every rule
+    /// reachable from any of the given roots as used.
+    ///
+    /// See [Self::lint] for the warnings reported.
+    ///
+    pub fn lint_from(&self, roots: &[String]) -> Vec<LintWarning> {
+        lint::lint(&self.registry, roots)
+    }
+
     /// Generate an expansion of this grammar, starting from the rule named `start`.
     ///
     /// # Errors
     ///
     /// - [CalyxError::UndefinedRule] if attempting to expand a rule that is not defined, and the
-    /// grammar options are [Options::strict].
+    ///   grammar options are [Options::strict].
     /// - [CalyxError::UndefinedFilter] if attempting to apply a filter to an expansion that does
-    /// not exist.
+    ///   not exist.
+    /// - [CalyxError::InvalidFilterArguments] if a filter is applied with arguments it does not
+    ///   accept.
     /// - [CalyxError::ExpandedEmptyBranch] if attempting to expand a branch production and that
-    /// production has no children.
+    ///   production has no children.
+    /// - [CalyxError::RecursionLimitExceeded] if rules are nested deeper than
+    ///   [Options::max_depth], or more rules are expanded than [Options::max_nodes].
     ///
     /// # Examples
     ///
@@ -172,11 +673,15 @@ impl Grammar {
     /// # Errors
     ///
     /// - [CalyxError::UndefinedRule] if attempting to expand a rule that is not defined, and the
-    /// grammar options are [Options::strict].
+    ///   grammar options are [Options::strict].
     /// - [CalyxError::UndefinedFilter] if attempting to apply a filter to an expansion that does
-    /// not exist.
+    ///   not exist.
+    /// - [CalyxError::InvalidFilterArguments] if a filter is applied with arguments it does not
+    ///   accept.
     /// - [CalyxError::ExpandedEmptyBranch] if attempting to expand a branch production and that
-    /// production has no children.
+    ///   production has no children.
+    /// - [CalyxError::RecursionLimitExceeded] if rules are nested deeper than
+    ///   [Options::max_depth], or more rules are expanded than [Options::max_nodes].
     ///
     /// # Examples
     ///
@@ -197,49 +702,775 @@ impl Grammar {
     /// ```
     ///
     pub fn generate_from(&mut self, start_symbol: &String) -> Result<ExpansionTree, CalyxError> {
-        let mut eval_context = EvaluationContext::new(self);
+        self.generate_from_with_context(start_symbol, &HashMap::new())
+    }
+
+    /// Generate an expansion of this grammar, starting from the rule named `start`, with additional
+    /// rules that apply only to this generation.
+    ///
+    /// Each entry of the context is a uniform expansion of a rule, and takes precedence over any
+    /// rule of the same name defined in the grammar.
+    ///
+    /// # Errors
+    ///
+    /// - [CalyxError::InvalidRule] if a rule in the context could not be parsed.
+    /// - Any of the errors returned by [Self::generate].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::Grammar;
+    /// use std::collections::HashMap;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// assert!(grammar.start_single(String::from("Welcome to {city}, {player}.")).is_ok());
+    /// assert!(grammar.single_rule(String::from("city"), String::from("Ankh-Morpork")).is_ok());
+    ///
+    /// let context = HashMap::from([(String::from("player"), vec![String::from("Rincewind")])]);
+    /// let text: String = grammar
+    ///     .generate_with_context(&context)
+    ///     .expect("Error during generation")
+    ///     .flatten();
+    /// assert_eq!(text, "Welcome to Ankh-Morpork, Rincewind.");
+    /// ```
+    ///
+    pub fn generate_with_context(
+        &mut self,
+        context: &HashMap<String, Vec<String>>,
+    ) -> Result<ExpansionTree, CalyxError> {
+        self.generate_from_with_context(&String::from("start"), context)
+    }
+
+    /// Generate an expansion of this grammar, starting from a given start symbol, with additional
+    /// rules that apply only to this generation.
+    ///
+    /// See [Self::generate_with_context] for how the context is used.
+    ///
+    /// # Errors
+    ///
+    /// - [CalyxError::InvalidRule] if a rule in the context could not be parsed.
+    /// - Any of the errors returned by [Self::generate_from].
+    ///
+    pub fn generate_from_with_context(
+        &mut self,
+        start_symbol: &String,
+        context: &HashMap<String, Vec<String>>,
+    ) -> Result<ExpansionTree, CalyxError> {
+        let context = self.registry.parse_context(context)?;
+
+        let mut eval_context = EvaluationContext::new(self, &context);
         let tree = eval_context.expand_and_evaluate(start_symbol)?;
 
-        Ok(ExpansionTree::chain(ExpansionType::Result, tree))
+        Ok(ExpansionTree::chain(
+            ExpansionType::Result {
+                rule_name: start_symbol.clone(),
+            },
+            tree,
+        ))
     }
-}
 
-impl Options {
-    /// Creates a new options struct with a defined [Self::strict] mode and random source.
-    pub fn new<R: rand::Rng + 'static>(strict: bool, random_source: R) -> Self {
-        Options {
-            strict,
-            random_source: Box::new(random_source),
-        }
+    /// Generate an expansion of this grammar, starting from the rule named `start`, along with a
+    /// log of every choice made, which can be given to [Self::replay] to generate the same
+    /// expansion again.
+    ///
+    /// # Errors
+    ///
+    /// - Any of the errors returned by [Self::generate].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::Grammar;
+    /// use calyx_rs::generation::choice_log::ChoiceLog;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// assert!(grammar.start_single(String::from("{greeting} world")).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("greeting"), &vec![String::from("Hello"), String::from("Hi")]).is_ok());
+    ///
+    /// let (tree, log) = grammar.generate_with_log().expect("Error during generation");
+    ///
+    /// // the log can be stored as a string, and read back later
+    /// let log: ChoiceLog = log.to_string().parse().expect("Invalid log");
+    /// let replayed = grammar.replay(&log).expect("Error during replay");
+    /// assert_eq!(replayed.flatten(), tree.flatten());
+    /// ```
+    ///
+    pub fn generate_with_log(&mut self) -> Result<(ExpansionTree, ChoiceLog), CalyxError> {
+        self.generate_from_with_log(&String::from("start"))
     }
 
-    /// Creates a new [Self::lenient] options struct,
-    pub fn with_rng<R: rand::Rng + 'static>(random_source: R) -> Self {
-        Options {
-            strict: false,
-            random_source: Box::new(random_source),
-        }
+    /// Generate an expansion of this grammar, starting from a given start symbol, along with a log
+    /// of every choice made.
+    ///
+    /// See [Self::generate_with_log] for how the log is used.
+    ///
+    /// # Errors
+    ///
+    /// - Any of the errors returned by [Self::generate_from].
+    ///
+    pub fn generate_from_with_log(
+        &mut self,
+        start_symbol: &String,
+    ) -> Result<(ExpansionTree, ChoiceLog), CalyxError> {
+        let context = HashMap::new();
+
+        let mut eval_context = EvaluationContext::new(self, &context);
+        let tree = eval_context.expand_and_evaluate(start_symbol)?;
+        let log = ChoiceLog::new(start_symbol.clone(), eval_context.choices().to_vec());
+
+        Ok((
+            ExpansionTree::chain(
+                ExpansionType::Result {
+                    rule_name: start_symbol.clone(),
+                },
+                tree,
+            ),
+            log,
+        ))
     }
 
-    /// Strict mode requires all rules to be defined in a grammar to successfully evaluate it.
+    /// Generates the same expansion as a previous generation of this grammar, by making the same
+    /// choices as recorded in its log rather than choosing at random. The decision source of the
+    /// grammar is not used.
     ///
-    /// Strict is the opposite of [Self::lenient].
-    pub fn strict(&self) -> bool {
-        self.strict
+    /// # Errors
+    ///
+    /// - [CalyxError::ChoiceLogMismatch] if the log does not match the rules of this grammar, such
+    ///   as when a rule has changed since the log was recorded. The position is the index of the
+    ///   first choice that could not be made, and the rule name is the rule being expanded at
+    ///   that point, or the rule of the first unused choice if the log has too many choices.
+    /// - Any of the errors returned by [Self::generate_from].
+    ///
+    pub fn replay(&mut self, log: &ChoiceLog) -> Result<ExpansionTree, CalyxError> {
+        let context = HashMap::new();
+        let start_symbol = log.start_symbol().to_string();
+
+        let mut eval_context = EvaluationContext::replay(self, &context, log.choices());
+        let tree = eval_context.expand_and_evaluate(&start_symbol)?;
+
+        let position = eval_context.choices().len();
+        if let Some(unused) = log.choices().get(position) {
+            return Err(CalyxError::ChoiceLogMismatch {
+                position,
+                rule_name: unused.rule_name().to_string(),
+            });
+        }
+
+        Ok(ExpansionTree::chain(
+            ExpansionType::Result {
+                rule_name: start_symbol,
+            },
+            tree,
+        ))
     }
 
-    /// In lenient mode, undefined rules in a grammar will evaluate to an empty string.
+    /// Lists every derivation of this grammar, starting from the rule named `start`.
     ///
-    /// Lenient is the opposite of [Self::strict]
-    pub fn lenient(&self) -> bool {
-        !self.strict()
+    /// See [Self::enumerate_from] for how derivations are listed.
+    ///
+    pub fn enumerate(&mut self) -> Enumeration<'_> {
+        self.enumerate_from("start")
     }
-}
 
-#[cfg(test)]
+    /// Lists every derivation of this grammar, starting from a given start symbol, without
+    /// choosing at random.
+    ///
+    /// Each derivation is a different combination of templates chosen for the rules that are
+    /// expanded, so every possible output is listed at least once. Memoized rules have the same
+    /// expansion everywhere in a derivation, and unique rules go through every template before
+    /// repeating one, as they do during generation. Derivations are generated lazily as the
+    /// iterator is advanced.
+    ///
+    /// The derivations of a recursive grammar never end, so the depth of nested rules should be
+    /// bounded with [Enumeration::with_max_depth].
+    ///
+    /// # Errors
+    ///
+    /// The iterator returns the first error that generation would return, and then ends. See
+    /// [Self::generate_from] for the possible errors.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::Grammar;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// assert!(grammar.start_single(String::from("{greeting} {name}")).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("greeting"), &vec![String::from("Hello"), String::from("Hi")]).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("name"), &vec![String::from("Ada"), String::from("Grace")]).is_ok());
+    ///
+    /// let outputs: Vec<String> = grammar
+    ///     .enumerate_from("start")
+    ///     .map(|tree| tree.expect("Error during enumeration").flatten())
+    ///     .collect();
+    ///
+    /// assert_eq!(outputs, vec!["Hello Ada", "Hello Grace", "Hi Ada", "Hi Grace"]);
+    /// ```
+    ///
+    pub fn enumerate_from(&mut self, start_symbol: &str) -> Enumeration<'_> {
+        Enumeration::new(self, start_symbol.to_string())
+    }
+
+    /// Counts the derivations of a rule: the combinations of templates that can be chosen to
+    /// expand it, as listed by [Self::enumerate_from].
+    ///
+    /// Templates that refer to rules that can never finish expanding have no derivations, nor do
+    /// undefined rules in [strict](Options::strict) grammars. A rule that can recurse any number
+    /// of times and still finish has [DerivationCount::Infinite] derivations.
+    ///
+    /// Memoized and unique expressions are counted exactly when they appear in a single
+    /// template. When several templates expanded in a derivation could share memoized or unique
+    /// expansions of the same rule, the count is [DerivationCount::AtMost] the number of
+    /// derivations.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::count::DerivationCount;
+    /// use calyx_rs::generation::Grammar;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// assert!(grammar.start_uniform(&vec![String::from("{first} {last}"), String::from("{first}")]).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("first"), &vec![String::from("Ada"), String::from("Grace")]).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("last"), &vec![String::from("Lovelace"), String::from("Hopper")]).is_ok());
+    ///
+    /// assert_eq!(grammar.count_derivations("start"), DerivationCount::Finite(6));
+    /// ```
+    ///
+    pub fn count_derivations(&self, rule_name: &str) -> DerivationCount {
+        count::count_derivations(&self.registry, self.options.strict, rule_name)
+    }
+
+    /// Estimates the number of distinct strings that a rule can expand to, which may be fewer
+    /// than its number of [derivations](Self::count_derivations) when different derivations
+    /// produce the same text.
+    ///
+    /// When the rule has at most `samples` derivations, they are all listed and the distinct
+    /// strings are counted exactly. Otherwise, the rule is generated `samples` times, and the
+    /// number of distinct strings is estimated from how many were generated only once or twice.
+    /// The estimate is never more than the number of derivations.
+    ///
+    /// # Errors
+    ///
+    /// - Any of the errors returned by [Self::generate_from].
+    ///
+    pub fn estimate_distinct_outputs(
+        &mut self,
+        rule_name: &str,
+        samples: usize,
+    ) -> Result<f64, CalyxError> {
+        let derivations = self.count_derivations(rule_name);
+
+        if let DerivationCount::Finite(count) | DerivationCount::AtMost(count) = derivations
+            && count <= samples as u128
+        {
+            let outputs = self
+                .enumerate_from(rule_name)
+                .map(|tree| tree.map(|tree| tree.flatten()))
+                .collect::<Result<HashSet<String>, CalyxError>>()?;
+
+            return Ok(outputs.len() as f64);
+        }
+
+        let mut frequencies: HashMap<String, usize> = HashMap::new();
+        for _ in 0..samples {
+            let text = self.generate_from(&rule_name.to_string())?.flatten();
+            *frequencies.entry(text).or_default() += 1;
+        }
+
+        // the bias-corrected Chao1 estimator, from the strings seen exactly once and twice
+        let seen_with = |frequency: usize| {
+            frequencies
+                .values()
+                .filter(|seen| **seen == frequency)
+                .count() as f64
+        };
+        let (once, twice) = (seen_with(1), seen_with(2));
+        let estimate =
+            frequencies.len() as f64 + once * (once - 1.0).max(0.0) / (2.0 * (twice + 1.0));
+
+        Ok(match derivations {
+            DerivationCount::Finite(count) | DerivationCount::AtMost(count) => {
+                estimate.min(count as f64)
+            }
+            DerivationCount::Infinite => estimate,
+        })
+    }
+
+    /// Finds the probability that generation makes the same choices as were made to generate a
+    /// tree, which may be a whole generated tree or the expansion of a rule within one.
+    ///
+    /// Each choice of a uniform rule has the same probability, each choice of a weighted rule is
+    /// in proportion to its weight, and each unique expansion is equally likely to be any
+    /// template of its rule that has not been used yet. Memoized expansions are only chosen
+    /// once. The [depth budget](Options::depth_budget) is ignored.
+    ///
+    /// # Errors
+    ///
+    /// - [CalyxError::NodeNotFound] if the tree is not the expansion of a rule.
+    /// - [CalyxError::ChoiceLogMismatch] if the tree was not generated by this grammar, or if an
+    ///   expression with filters or mappings expanded other rules, which the tree does not
+    ///   record. Use [Self::generate_with_log] and [Self::replay] for those instead.
+    /// - Any of the errors returned by [Self::generate_from].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::Grammar;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// assert!(grammar.start_single(String::from("{greeting} {name}")).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("greeting"), &vec![String::from("Hello"), String::from("Hi")]).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("name"), &vec![String::from("Ada"), String::from("Grace"), String::from("Alan")]).is_ok());
+    ///
+    /// let tree = grammar.generate().expect("Error during generation");
+    /// assert_eq!(grammar.derivation_probability(&tree).expect("Error finding the probability"), 1.0 / 6.0);
+    /// ```
+    ///
+    pub fn derivation_probability(&mut self, tree: &ExpansionTree) -> Result<f64, CalyxError> {
+        let (start_symbol, choices) =
+            probability::derivation_choices(tree).ok_or_else(|| CalyxError::NodeNotFound {
+                selector: NodeSelector::Path(Vec::new()),
+            })?;
+
+        let context = HashMap::new();
+        let mut eval_context = EvaluationContext::replay(self, &context, &choices);
+        eval_context.expand_and_evaluate(&start_symbol)?;
+
+        let position = eval_context.choices().len();
+        if let Some(unused) = choices.get(position) {
+            return Err(CalyxError::ChoiceLogMismatch {
+                position,
+                rule_name: unused.rule_name().to_string(),
+            });
+        }
+
+        Ok(eval_context.probability())
+    }
+
+    /// Finds the probability that a rule expands to a text, summed over every derivation that
+    /// produces it, ignoring the [depth budget](Options::depth_budget).
+    ///
+    /// When every expression that the rule can reach is plain, the probability is computed from
+    /// the ways that the templates can be split across the text. Otherwise, as memoized and
+    /// unique expressions depend on the rest of the generation, and filters and mappings
+    /// rewrite text, derivations are listed most likely first until those left are less likely
+    /// than one in a trillion altogether, or 65536 have been listed, whichever comes first.
+    /// Derivations listed this way that nest rules deeper than the
+    /// [maximum depth](Options::max_depth) are left out.
+    ///
+    /// # Errors
+    ///
+    /// - Any of the errors returned by [Self::generate_from], other than those for derivations
+    ///   that never finish, which have no output.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::Grammar;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// assert!(grammar.start_uniform(&vec![String::from("{a}{b}"), String::from("ab")]).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("a"), &vec![String::from("a"), String::from("")]).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("b"), &vec![String::from("b"), String::from("ab")]).is_ok());
+    ///
+    /// // "a" then "b", "" then "ab", or the literal "ab"
+    /// let probability = grammar.output_probability("start", "ab").expect("Error finding the probability");
+    /// assert_eq!(probability, 0.75);
+    /// ```
+    ///
+    pub fn output_probability(&mut self, rule_name: &str, text: &str) -> Result<f64, CalyxError> {
+        probability::output_probability(self, rule_name, text)
+    }
+
+    /// Lists the most likely outputs of a rule, most likely first, along with their
+    /// probabilities as given by [Self::output_probability]. Outputs that are as likely as each
+    /// other are ordered by their text.
+    ///
+    /// Derivations are listed most likely first, until the outputs found can no longer be
+    /// overtaken by those left. When every expression is plain, listing also stops once every
+    /// derivation left is less likely than each of the outputs found. Otherwise, an output may be
+    /// made of many unlikely derivations, so outputs are ranked by the derivations listed, which
+    /// stop after 65536 as for [Self::output_probability]. This gives fewer outputs than asked
+    /// for if the rule has fewer.
+    ///
+    /// # Errors
+    ///
+    /// - Any of the errors returned by [Self::output_probability].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use calyx_rs::generation::Grammar;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// let weights = HashMap::from([
+    ///     (String::from("a sword"), 6.0),
+    ///     (String::from("a shield"), 3.0),
+    ///     (String::from("the crown"), 1.0),
+    /// ]);
+    /// assert!(grammar.start_weighted(&weights).is_ok());
+    ///
+    /// let top = grammar.top_outputs("start", 2).expect("Error finding the outputs");
+    /// assert_eq!(top, vec![(String::from("a sword"), 0.6), (String::from("a shield"), 0.3)]);
+    /// ```
+    ///
+    pub fn top_outputs(
+        &mut self,
+        rule_name: &str,
+        count: usize,
+    ) -> Result<Vec<(String, f64)>, CalyxError> {
+        probability::top_outputs(self, rule_name, count)
+    }
+
+    /// Finds every derivation of this grammar that generates a text, starting from the rule
+    /// named `start`.
+    ///
+    /// See [Self::parse_from] for how text is parsed.
+    ///
+    /// # Errors
+    ///
+    /// - Any of the errors returned by [Self::parse_from].
+    ///
+    pub fn parse(&mut self, text: &str) -> Result<Vec<ExpansionTree>, CalyxError> {
+        self.parse_from("start", text)
+    }
+
+    /// Finds every derivation of a rule that generates a text, as the trees that generation
+    /// would return for them. The text could not have been generated by the rule if there are
+    /// none.
+    ///
+    /// Memoized expressions must expand the same way everywhere and unique expressions must not
+    /// repeat templates, as during generation. Derivations that nest rules deeper than the
+    /// [maximum depth](Options::max_depth), or that repeat a rule without generating any more
+    /// text, are left out, and at most 1024 parses are kept for any piece of the text.
+    ///
+    /// Filters and mappings cannot be undone in general, so an expression with them is parsed
+    /// by generating every output of its rule once it has to be matched against the text. Its
+    /// rule must have at most 4096 derivations, or the expression is left unmatched.
+    ///
+    /// # Errors
+    ///
+    /// - [CalyxError::Unsupported] if no derivation is found, and an expression with filters or
+    ///   mappings had to be left unmatched, as its rule has too many derivations to generate
+    ///   every output.
+    /// - Any of the errors returned by [Self::generate_from] while generating those outputs.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::Grammar;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// assert!(grammar.start_single(String::from("{greeting}, {name.uppercase}!")).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("greeting"), &vec![String::from("Hello"), String::from("Hi")]).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("name"), &vec![String::from("Ada"), String::from("Grace")]).is_ok());
+    ///
+    /// let trees = grammar.parse("Hi, ADA!").expect("Error during parsing");
+    /// assert_eq!(trees.len(), 1);
+    /// assert_eq!(trees[0].flatten(), "Hi, ADA!");
+    ///
+    /// assert!(grammar.parse("Hi, Alan!").expect("Error during parsing").is_empty());
+    /// ```
+    ///
+    pub fn parse_from(
+        &mut self,
+        rule_name: &str,
+        text: &str,
+    ) -> Result<Vec<ExpansionTree>, CalyxError> {
+        recognizer::parse(self, rule_name, text)
+    }
+
+    /// Generates a new tree from a previous generation of this grammar, in which only the
+    /// expression containing the selected node is expanded again.
+    ///
+    /// The expression that is expanded again is the one in the innermost template containing the
+    /// selected node, so that filters and mappings are applied to it as before. A node inside an
+    /// expression with filters or mappings selects that whole expression. Memoized rules keep
+    /// their expansions from the rest of the tree, unless the expression itself is a memoized
+    /// rule, in which case every memoized expansion of that rule in the tree is replaced.
+    /// Selecting a node outside of any template generates a whole new tree.
+    ///
+    /// # Errors
+    ///
+    /// - [CalyxError::NodeNotFound] if the selector does not match a node of the tree, or the
+    ///   tree was not generated by a grammar.
+    /// - Any of the errors returned by [Self::generate_from]. The tree must have been generated
+    ///   by this grammar, without a runtime context, or the rules it refers to may differ.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use calyx_rs::generation::expansion_tree::NodeSelector;
+    /// use calyx_rs::generation::Grammar;
+    ///
+    /// let mut grammar: Grammar = Grammar::new();
+    /// assert!(grammar.start_single(String::from("The {adjective} {noun}")).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("adjective"), &vec![String::from("quick"), String::from("lazy")]).is_ok());
+    /// assert!(grammar.uniform_rule(String::from("noun"), &vec![String::from("fox"), String::from("dog")]).is_ok());
+    ///
+    /// let tree = grammar.generate().expect("Error during generation");
+    /// let noun = tree.text_of("noun");
+    ///
+    /// let selector = NodeSelector::Rule(String::from("adjective"));
+    /// let rerolled = grammar.reroll(&tree, &selector).expect("Error during reroll");
+    /// assert_eq!(rerolled.text_of("noun"), noun);
+    /// ```
+    ///
+    pub fn reroll(
+        &mut self,
+        tree: &ExpansionTree,
+        selector: &NodeSelector,
+    ) -> Result<ExpansionTree, CalyxError> {
+        let not_found = || CalyxError::NodeNotFound {
+            selector: selector.clone(),
+        };
+
+        let mut path = selector.resolve(tree).ok_or_else(not_found)?;
+
+        // the text of filtered and mapped expressions no longer records the segments it was
+        // expanded from, so the whole expression is expanded again
+        let rewritten = |length: &usize| {
+            matches!(
+                tree.get(&path[..*length]).map(ExpansionTree::symbol),
+                Some(ExpansionType::ExpressionChain | ExpansionType::AffixTable)
+            )
+        };
+        if let Some(length) = (1..=path.len()).find(rewritten) {
+            path.truncate(length);
+        }
+
+        let in_template = |length: &usize| {
+            matches!(
+                tree.get(&path[..length - 1]).map(ExpansionTree::symbol),
+                Some(ExpansionType::Template)
+            )
+        };
+
+        let Some(segment_length) = (1..=path.len()).rev().find(in_template) else {
+            return match tree.symbol() {
+                ExpansionType::Result { rule_name } => self.generate_from(&rule_name.clone()),
+                _ => Err(not_found()),
+            };
+        };
+
+        let segment_path = &path[..segment_length];
+        let (rule_name, template_index) =
+            Self::segment_source(tree, segment_path).ok_or_else(not_found)?;
+
+        let expanded_rule = |length: usize| {
+            let node = tree.get(&path[..length])?;
+            match node.symbol() {
+                ExpansionType::Result { rule_name } => Some(rule_name.clone()),
+                _ => node.rule_name().map(String::from),
+            }
+        };
+        let expanding: Vec<String> = (0..=segment_length - 3).filter_map(expanded_rule).collect();
+
+        // a memoized expression is rerolled everywhere, and everything else memoized is kept
+        let mut memoized_expansions = tree.memoized_expansions(segment_path);
+        let mut rerolled_memos = Vec::new();
+        let mut node = tree.get(segment_path);
+        while let Some(current) = node {
+            if let ExpansionType::Memo { rule_name } = current.symbol() {
+                memoized_expansions.remove(rule_name);
+                rerolled_memos.push(rule_name.clone());
+            }
+
+            node = match current.children().as_slice() {
+                [child] => Some(child),
+                _ => None,
+            };
+        }
+
+        let context = HashMap::new();
+        let mut eval_context =
+            EvaluationContext::resume(self, &context, expanding, memoized_expansions);
+
+        let segment = Self::evaluate_segment(
+            &mut eval_context,
+            &rule_name,
+            template_index,
+            path[segment_length - 1],
+        )?;
+
+        let mut rerolled = tree.clone();
+        rerolled.replace(segment_path, segment);
+
+        for memo in rerolled_memos {
+            Self::update_memos(&mut rerolled, &memo, &mut eval_context)?;
+        }
+
+        // memoized rules containing the expression have new expansions too, innermost first
+        for length in (0..segment_length).rev() {
+            let Some(memo) = rerolled.get(&path[..length]) else {
+                continue;
+            };
+
+            if let (ExpansionType::Memo { rule_name }, [expansion]) =
+                (memo.symbol(), memo.children().as_slice())
+            {
+                let rule_name = rule_name.clone();
+                eval_context.memoize(rule_name.clone(), expansion.clone());
+                Self::update_memos(&mut rerolled, &rule_name, &mut eval_context)?;
+            }
+        }
+
+        Ok(rerolled)
+    }
+
+    /// The rule and template index of the template that a piece of a tree was evaluated from.
+    /// A template is chosen by a branch, which is the expansion of a rule.
+    fn segment_source(tree: &ExpansionTree, segment_path: &[usize]) -> Option<(String, usize)> {
+        let rule_path = &segment_path[..segment_path.len().checked_sub(3)?];
+
+        let rule = tree.get(rule_path)?;
+        let rule_name = match rule.symbol() {
+            ExpansionType::Result { rule_name } => rule_name.clone(),
+            _ => rule.rule_name()?.to_string(),
+        };
+
+        let template_index = rule.children().first()?.choice_index()?;
+        Some((rule_name, template_index))
+    }
+
+    fn evaluate_segment(
+        eval_context: &mut EvaluationContext,
+        rule_name: &String,
+        template_index: usize,
+        segment_index: usize,
+    ) -> Result<ExpansionTree, CalyxError> {
+        let rule = eval_context.expand(rule_name)?;
+        let template = rule
+            .templates()
+            .into_iter()
+            .nth(template_index)
+            .ok_or(CalyxError::ExpandedEmptyBranch)?;
+
+        template.evaluate_segment(segment_index, eval_context)
+    }
+
+    /// Evaluates every memoized expression of a rule in a tree again, so that they use the
+    /// current memoized expansion with their own filters and mappings.
+    fn update_memos(
+        tree: &mut ExpansionTree,
+        rule_name: &str,
+        eval_context: &mut EvaluationContext,
+    ) -> Result<(), CalyxError> {
+        for segment_path in tree.memo_segments(rule_name) {
+            let Some((source, template_index)) = Self::segment_source(tree, &segment_path) else {
+                continue;
+            };
+
+            let segment_index = segment_path[segment_path.len() - 1];
+            let segment =
+                Self::evaluate_segment(eval_context, &source, template_index, segment_index)?;
+            tree.replace(&segment_path, segment);
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Grammar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Options {
+    /// Creates a new options struct with a defined [Self::strict] mode and source of decisions,
+    /// which is usually a random number generator.
+    pub fn new<D: DecisionSource + 'static>(strict: bool, decision_source: D) -> Self {
+        Options {
+            strict,
+            decision_source: Box::new(decision_source),
+            max_depth: Self::DEFAULT_MAX_DEPTH,
+            max_nodes: None,
+            depth_budget: None,
+        }
+    }
+
+    /// Creates a new [Self::lenient] options struct,
+    pub fn with_rng<R: rand::Rng + 'static>(random_source: R) -> Self {
+        Self::new(false, random_source)
+    }
+
+    /// Creates a new [Self::lenient] options struct that makes decisions with a
+    /// [DecisionSource] other than a random number generator.
+    pub fn with_decision_source<D: DecisionSource + 'static>(decision_source: D) -> Self {
+        Self::new(false, decision_source)
+    }
+
+    /// The default for [Self::max_depth].
+    pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+    /// Sets the maximum depth of nested rule expansions, see [Self::max_depth].
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Sets the maximum number of rule expansions in a single generation, see [Self::max_nodes].
+    pub fn with_max_nodes(mut self, max_nodes: usize) -> Self {
+        self.max_nodes = Some(max_nodes);
+        self
+    }
+
+    /// Sets the depth after which generation winds down, see [Self::depth_budget].
+    pub fn with_depth_budget(mut self, depth_budget: usize) -> Self {
+        self.depth_budget = Some(depth_budget);
+        self
+    }
+
+    /// The maximum number of rules that may be expanded inside one another. Exceeding this fails
+    /// generation with [CalyxError::RecursionLimitExceeded] rather than overflowing the stack on
+    /// a recursive rule. Defaults to [Self::DEFAULT_MAX_DEPTH].
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// The maximum number of rules that may be expanded in a single generation, if any. Exceeding
+    /// this fails generation with [CalyxError::RecursionLimitExceeded]. This bounds the work done
+    /// by grammars that branch widely without recursing deeply.
+    pub fn max_nodes(&self) -> Option<usize> {
+        self.max_nodes
+    }
+
+    /// The depth of nested rule expansions after which generation winds down, if any.
+    ///
+    /// Once rules are nested deeper than the budget, each rule only chooses among the templates
+    /// that need the fewest further nested expansions to finish. This lets recursive grammars,
+    /// such as nested clauses or arithmetic expressions, always finish with a valid output
+    /// instead of failing with [CalyxError::RecursionLimitExceeded]. Rules that can never finish
+    /// expanding are unaffected, so [Self::max_depth] still applies to them.
+    pub fn depth_budget(&self) -> Option<usize> {
+        self.depth_budget
+    }
+
+    /// Strict mode requires all rules to be defined in a grammar to successfully evaluate it.
+    ///
+    /// Strict is the opposite of [Self::lenient].
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    /// In lenient mode, undefined rules in a grammar will evaluate to an empty string.
+    ///
+    /// Lenient is the opposite of [Self::strict]
+    pub fn lenient(&self) -> bool {
+        !self.strict()
+    }
+}
+
+#[cfg(test)]
 mod grammar_tests {
-    use crate::generation::expansion_tree::ExpansionType;
-    use crate::generation::{CalyxError, Grammar};
+    use crate::generation::choice_log::ChoiceLog;
+    use crate::generation::expansion_tree::{ExpansionTree, ExpansionType, NodeSelector};
+    use crate::generation::filter::Filter;
+    use crate::generation::test_support::define;
+    use crate::generation::{CalyxError, Grammar, Options};
     use rand::SeedableRng;
     use rand::rngs::StdRng;
     use std::collections::HashMap;
@@ -255,7 +1486,9 @@ mod grammar_tests {
         );
 
         let expansion = grammar.generate().expect("Error during grammar generation");
-        assert!(matches!(expansion.symbol(), ExpansionType::Result));
+        assert!(
+            matches!(expansion.symbol(), ExpansionType::Result { rule_name } if rule_name == "start")
+        );
         assert_eq!(expansion.flatten(), "atom");
     }
 
@@ -449,4 +1682,904 @@ mod grammar_tests {
 
         assert_eq!("jewels", text);
     }
+
+    #[test]
+    fn context_rules_are_used_for_a_single_generation() {
+        let mut grammar = Grammar::new_strict();
+
+        assert!(
+            grammar
+                .start_single(String::from("{@player} enters {city}. Hi {@player}!"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("city"), String::from("Lankhmar"))
+                .is_ok()
+        );
+
+        let context = HashMap::from([(String::from("player"), vec![String::from("Fafhrd")])]);
+        let text = grammar
+            .generate_with_context(&context)
+            .expect("Error during grammar generation")
+            .flatten();
+        assert_eq!("Fafhrd enters Lankhmar. Hi Fafhrd!", text);
+
+        let result = grammar.generate();
+        assert!(
+            matches!(result, Err(CalyxError::UndefinedRule { ref rule_name }) if rule_name == "player")
+        );
+    }
+
+    #[test]
+    fn context_rules_take_precedence_over_grammar_rules() {
+        let mut grammar = Grammar::new();
+
+        assert!(grammar.start_single(String::from("{city}")).is_ok());
+        assert!(
+            grammar
+                .single_rule(String::from("city"), String::from("Lankhmar"))
+                .is_ok()
+        );
+
+        let context = HashMap::from([(String::from("city"), vec![String::from("Quarmall")])]);
+        let text = grammar
+            .generate_with_context(&context)
+            .expect("Error during grammar generation")
+            .flatten();
+        assert_eq!("Quarmall", text);
+    }
+
+    #[test]
+    fn invalid_context_rule_reports_rule_name() {
+        let mut grammar = Grammar::new();
+        assert!(grammar.start_single(String::from("{city}")).is_ok());
+
+        let context =
+            HashMap::from([(String::from("city"), vec![String::from("{name.truncate}")])]);
+        let result = grammar.generate_with_context(&context);
+        assert!(
+            matches!(result, Err(CalyxError::InvalidRule { ref rule_name, .. }) if rule_name == "city")
+        );
+    }
+
+    #[test]
+    fn mappings_apply_to_memoized_expressions_and_filters() {
+        let mut grammar = Grammar::new();
+
+        assert!(
+            grammar
+                .start_single(String::from("{@animal} {@animal>saddle.uppercase}"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("animal"), String::from("horse"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .mapping_rule(
+                    String::from("saddle"),
+                    &[(String::from("^horse$"), String::from("saddle"))]
+                )
+                .is_ok()
+        );
+
+        let text = grammar
+            .generate()
+            .expect("Error during grammar generation")
+            .flatten();
+        assert_eq!("horse SADDLE", text);
+    }
+
+    #[test]
+    fn mapping_produces_affix_table_node() {
+        let mut grammar = Grammar::new();
+
+        assert!(grammar.start_single(String::from("{a>b}")).is_ok());
+        assert!(
+            grammar
+                .mapping_rule(String::from("b"), &[(String::from("x"), String::from("y"))])
+                .is_ok()
+        );
+
+        let tree = grammar.generate().expect("Error during grammar generation");
+        let template = &tree.children()[0].children()[0];
+        assert!(matches!(
+            template.children()[0].symbol(),
+            ExpansionType::AffixTable
+        ));
+    }
+
+    #[test]
+    fn undefined_mapping_in_strict_mode_fails() {
+        let mut grammar = Grammar::new_strict();
+
+        assert!(grammar.start_single(String::from("{a>plural}")).is_ok());
+        assert!(
+            grammar
+                .single_rule(String::from("a"), String::from("x"))
+                .is_ok()
+        );
+
+        let result = grammar.generate();
+        assert!(
+            matches!(result, Err(CalyxError::UndefinedRule { ref rule_name }) if rule_name == "plural")
+        );
+    }
+
+    #[test]
+    fn empty_mapping_fails() {
+        let mut grammar = Grammar::new();
+
+        let result = grammar.mapping_rule(String::from("plural"), &[]);
+        assert!(
+            matches!(result, Err(CalyxError::EmptyMapping { ref rule_name }) if rule_name == "plural")
+        );
+    }
+
+    #[test]
+    fn mapping_with_rule_name_fails() {
+        let mut grammar = Grammar::new();
+
+        assert!(grammar.start_single(String::from("x")).is_ok());
+        let result = grammar.mapping_rule(String::from("start"), &[]);
+        assert!(matches!(result, Err(CalyxError::DuplicateRule { .. })));
+    }
+
+    #[test]
+    fn custom_filter_captures_state() {
+        let mut grammar = Grammar::new();
+        let replacements = HashMap::from([(String::from("cat"), String::from("dog"))]);
+
+        assert!(
+            grammar
+                .register_filter(String::from("replace"), move |s| {
+                    replacements
+                        .get(s)
+                        .cloned()
+                        .unwrap_or_else(|| s.to_string())
+                })
+                .is_ok()
+        );
+        assert!(grammar.start_single(String::from("{pet.replace}")).is_ok());
+        assert!(
+            grammar
+                .single_rule(String::from("pet"), String::from("cat"))
+                .is_ok()
+        );
+
+        let text = grammar
+            .generate()
+            .expect("Error during grammar generation")
+            .flatten();
+        assert_eq!("dog", text);
+    }
+
+    #[test]
+    fn shared_filter_is_usable_by_multiple_grammars() {
+        let filter = Filter::new(|s| s.repeat(2));
+
+        for _ in 0..2 {
+            let mut grammar = Grammar::new();
+            assert!(
+                grammar
+                    .register_shared_filter(String::from("twice"), filter.clone())
+                    .is_ok()
+            );
+            assert!(grammar.start_single(String::from("{word.twice}")).is_ok());
+            assert!(
+                grammar
+                    .single_rule(String::from("word"), String::from("ha"))
+                    .is_ok()
+            );
+
+            let text = grammar
+                .generate()
+                .expect("Error during grammar generation")
+                .flatten();
+            assert_eq!("haha", text);
+        }
+    }
+
+    #[test]
+    fn custom_filter_with_arguments() {
+        let mut grammar = Grammar::new();
+        let filter = Filter::with_arguments(1..=1, |s, args| {
+            let count = args[0].as_integer().unwrap_or(1) as usize;
+            Ok(s.repeat(count))
+        });
+
+        assert!(
+            grammar
+                .register_shared_filter(String::from("repeat"), filter)
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .start_single(String::from("{word.repeat(3)}"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("word"), String::from("ha"))
+                .is_ok()
+        );
+
+        let text = grammar
+            .generate()
+            .expect("Error during grammar generation")
+            .flatten();
+        assert_eq!("hahaha", text);
+    }
+
+    #[test]
+    fn register_duplicate_filter_fails() {
+        let mut grammar = Grammar::new();
+
+        let result = grammar.register_filter(String::from("uppercase"), |s| s.to_string());
+        assert!(
+            matches!(result, Err(CalyxError::DuplicateFilter { ref filter_name }) if filter_name == "uppercase")
+        );
+    }
+
+    #[test]
+    fn invalid_template_reports_rule_and_template() {
+        let mut grammar = Grammar::new();
+
+        let result = grammar.uniform_rule(
+            String::from("greeting"),
+            &vec![String::from("Hello"), String::from("Hi {name..upper}")],
+        );
+
+        let Err(CalyxError::InvalidExpression {
+            expression,
+            diagnostic,
+        }) = result
+        else {
+            panic!("Expected InvalidExpression");
+        };
+
+        assert_eq!(expression, ".");
+        assert_eq!(diagnostic.rule_name(), Some("greeting"));
+        assert_eq!(diagnostic.template_index(), Some(1));
+        assert_eq!(
+            diagnostic.to_string(),
+            "error: empty filter name\n --> rule `greeting`, template 1, byte 8\n  |\n  | Hi {name..upper}\n  |         ^"
+        );
+    }
+
+    #[test]
+    fn validate_accepts_complete_grammar() {
+        let mut grammar = Grammar::new();
+
+        assert!(
+            grammar
+                .start_single(String::from("{@name.uppercase} {name>short}"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("name"), String::from("Rincewind"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .mapping_rule(
+                    String::from("short"),
+                    &[(String::from("^(.).*"), String::from("$1"))]
+                )
+                .is_ok()
+        );
+
+        assert!(grammar.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let mut grammar = Grammar::new_strict();
+
+        assert!(
+            grammar
+                .uniform_rule(
+                    String::from("start"),
+                    &vec![
+                        String::from("{greeting}"),
+                        String::from("{name.shout} {name>short} {name.tag(1)}"),
+                    ],
+                )
+                .is_ok()
+        );
+        assert!(grammar.uniform_rule(String::from("name"), &vec![]).is_ok());
+        assert!(
+            grammar
+                .register_filter(String::from("tag"), |input| format!("#{}", input))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("orphan"), String::from("{$missing}"))
+                .is_ok()
+        );
+
+        let errors = grammar.validate().expect_err("Expected an invalid grammar");
+        let described: Vec<(String, String)> = errors
+            .iter()
+            .map(|error| match error {
+                CalyxError::InvalidRule { rule_name, cause } => {
+                    let cause = match cause.as_ref() {
+                        CalyxError::UndefinedRule { rule_name } => rule_name.clone(),
+                        CalyxError::UndefinedFilter { filter_name } => filter_name.clone(),
+                        CalyxError::InvalidFilterArguments { filter_name, .. } => {
+                            format!("{}()", filter_name)
+                        }
+                        CalyxError::ExpandedEmptyBranch => String::from("empty"),
+                        other => panic!("Unexpected cause {:?}", other),
+                    };
+                    (rule_name.clone(), cause)
+                }
+                other => panic!("Unexpected error {:?}", other),
+            })
+            .collect();
+
+        let expected = [
+            ("name", "empty"),
+            ("orphan", "missing"),
+            ("start", "greeting"),
+            ("start", "shout"),
+            ("start", "short"),
+            ("start", "tag()"),
+        ];
+        let expected: Vec<(String, String)> = expected
+            .iter()
+            .map(|(rule, cause)| (rule.to_string(), cause.to_string()))
+            .collect();
+
+        assert_eq!(described, expected);
+    }
+
+    #[test]
+    fn recursion_is_limited_by_depth() {
+        let rng = StdRng::seed_from_u64(12345);
+        let mut grammar = Grammar::with_options(Options::with_rng(rng).with_max_depth(10));
+
+        assert!(grammar.start_single(String::from("{a}")).is_ok());
+        assert!(
+            grammar
+                .single_rule(String::from("a"), String::from("{a}"))
+                .is_ok()
+        );
+
+        let result = grammar.generate();
+        let Err(CalyxError::RecursionLimitExceeded { rule_path }) = result else {
+            panic!("Expected RecursionLimitExceeded");
+        };
+
+        assert_eq!(rule_path.len(), 11);
+        assert_eq!(rule_path[0], "start");
+        assert!(rule_path[1..].iter().all(|symbol| symbol == "a"));
+    }
+
+    #[test]
+    fn default_depth_limit_does_not_overflow_stack() {
+        let mut grammar = Grammar::new();
+
+        assert!(
+            grammar
+                .start_single(String::from("{@memo}{$unique}{start.uppercase}"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("memo"), String::from("{memo}"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("unique"), String::from("{$unique}"))
+                .is_ok()
+        );
+
+        assert!(matches!(
+            grammar.generate(),
+            Err(CalyxError::RecursionLimitExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn expansions_are_limited_by_node_count() {
+        let options = || Options::with_rng(StdRng::seed_from_u64(12345));
+        let define = |grammar: &mut Grammar| {
+            assert!(grammar.start_single(String::from("{x}{x}{x}")).is_ok());
+            assert!(
+                grammar
+                    .single_rule(String::from("x"), String::from("y"))
+                    .is_ok()
+            );
+        };
+
+        let mut grammar = Grammar::with_options(options().with_max_nodes(4));
+        define(&mut grammar);
+        assert_eq!(
+            grammar
+                .generate()
+                .expect("Error during generation")
+                .flatten(),
+            "yyy"
+        );
+
+        let mut grammar = Grammar::with_options(options().with_max_nodes(3));
+        define(&mut grammar);
+        let result = grammar.generate();
+        assert!(
+            matches!(result, Err(CalyxError::RecursionLimitExceeded { ref rule_path }) if rule_path == &["start", "x"])
+        );
+    }
+
+    #[test]
+    fn validate_reports_rules_that_never_terminate() {
+        let mut grammar = Grammar::new();
+        let rules: [(&str, &[&str]); 5] = [
+            ("start", &["{list}"]),
+            ("list", &["{item}", "{item}, {list}"]),
+            ("item", &["{a}", "x"]),
+            ("a", &["{b}"]),
+            ("b", &["{a}", "{a}{item}"]),
+        ];
+
+        define(&mut grammar, &rules);
+
+        let errors = grammar.validate().expect_err("Expected an invalid grammar");
+        let paths: Vec<(String, Vec<String>)> = errors
+            .into_iter()
+            .map(|error| match error {
+                CalyxError::InvalidRule { rule_name, cause } => match *cause {
+                    CalyxError::RecursionLimitExceeded { rule_path } => (rule_name, rule_path),
+                    other => panic!("Unexpected cause {:?}", other),
+                },
+                other => panic!("Unexpected error {:?}", other),
+            })
+            .collect();
+
+        assert_eq!(
+            paths,
+            vec![
+                (
+                    String::from("a"),
+                    vec![String::from("a"), String::from("b"), String::from("a")]
+                ),
+                (
+                    String::from("b"),
+                    vec![String::from("b"), String::from("a"), String::from("b")]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn depth_budget_winds_down_recursion() {
+        let define = |grammar: &mut Grammar| {
+            assert!(grammar.start_single(String::from("{expr}")).is_ok());
+            assert!(
+                grammar
+                    .weighted_rule(
+                        String::from("expr"),
+                        &HashMap::from([
+                            (String::from("({expr} + {expr})"), 10.0),
+                            (String::from("-{expr}"), 10.0),
+                            (String::from("{digit}"), 1.0),
+                        ]),
+                    )
+                    .is_ok()
+            );
+            assert!(
+                grammar
+                    .uniform_rule(
+                        String::from("digit"),
+                        &vec![String::from("{@one}"), String::from("{$two}")]
+                    )
+                    .is_ok()
+            );
+            assert!(
+                grammar
+                    .single_rule(String::from("one"), String::from("1"))
+                    .is_ok()
+            );
+            assert!(
+                grammar
+                    .single_rule(String::from("two"), String::from("2"))
+                    .is_ok()
+            );
+        };
+
+        let options = Options::with_rng(StdRng::seed_from_u64(12345)).with_max_depth(32);
+        let mut grammar = Grammar::with_options(options);
+        define(&mut grammar);
+        assert!(matches!(
+            grammar.generate(),
+            Err(CalyxError::RecursionLimitExceeded { .. })
+        ));
+
+        for seed in 0..50 {
+            let options = Options::with_rng(StdRng::seed_from_u64(seed))
+                .with_max_depth(32)
+                .with_depth_budget(6);
+            let mut grammar = Grammar::with_options(options);
+            define(&mut grammar);
+
+            let text = grammar
+                .generate()
+                .expect("Error during generation")
+                .flatten();
+
+            let mut nesting: i32 = 0;
+            let mut deepest = 0;
+            for ch in text.chars() {
+                match ch {
+                    '(' => nesting += 1,
+                    ')' => nesting -= 1,
+                    _ => {}
+                }
+                deepest = deepest.max(nesting);
+            }
+            assert!(deepest <= 6, "{} is nested too deeply", text);
+        }
+    }
+
+    #[test]
+    fn tree_records_rules_and_choices() {
+        let rng = StdRng::seed_from_u64(12345);
+        let mut grammar = Grammar::with_rng(rng);
+
+        assert!(grammar.start_single(String::from("{@a} {$b} {c}")).is_ok());
+        assert!(
+            grammar
+                .single_rule(String::from("a"), String::from("A"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .uniform_rule(String::from("b"), &vec![String::from("B")])
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .weighted_rule(
+                    String::from("c"),
+                    &HashMap::from([(String::from("C"), 2.0), (String::from("D"), 0.5)])
+                )
+                .is_ok()
+        );
+
+        let tree = grammar.generate().expect("Error during generation");
+        let start = &tree.children()[0];
+        assert_eq!(start.choice_index(), Some(0));
+        assert_eq!(start.weight(), None);
+
+        let expressions: Vec<_> = start.children()[0]
+            .children()
+            .iter()
+            .filter(|node| node.rule_name().is_some())
+            .collect();
+
+        assert!(
+            matches!(expressions[0].symbol(), ExpansionType::Memo { rule_name } if rule_name == "a")
+        );
+        assert!(
+            matches!(expressions[1].symbol(), ExpansionType::Unique { rule_name } if rule_name == "b")
+        );
+        assert!(
+            matches!(expressions[2].symbol(), ExpansionType::Expression { rule_name } if rule_name == "c")
+        );
+
+        let weighted = &expressions[2].children()[0];
+        let (index, weight) = match weighted.flatten().as_str() {
+            "C" => (0, 2.0),
+            _ => (1, 0.5),
+        };
+        assert_eq!(weighted.choice_index(), Some(index));
+        assert_eq!(weighted.weight(), Some(weight));
+    }
+
+    fn numbered(prefix: &str) -> Vec<String> {
+        (0..10).map(|i| format!("{}{}", prefix, i)).collect()
+    }
+
+    #[test]
+    fn reroll_changes_only_selected_expression() {
+        let mut grammar = Grammar::with_rng(StdRng::seed_from_u64(12345));
+
+        assert!(
+            grammar
+                .start_single(String::from("{a} {b.uppercase} {c}"))
+                .is_ok()
+        );
+        for name in ["a", "b", "c"] {
+            assert!(
+                grammar
+                    .uniform_rule(name.to_string(), &numbered(name))
+                    .is_ok()
+            );
+        }
+
+        let tree = grammar.generate().expect("Error during generation");
+        let text = tree.flatten();
+        let selector = NodeSelector::Rule(String::from("b"));
+
+        let mut changed = false;
+        for _ in 0..20 {
+            let rerolled = grammar
+                .reroll(&tree, &selector)
+                .expect("Error during reroll");
+            let words: Vec<String> = rerolled.flatten().split(' ').map(String::from).collect();
+
+            assert_eq!(rerolled.text_of("a"), tree.text_of("a"));
+            assert_eq!(rerolled.text_of("c"), tree.text_of("c"));
+            assert!(words[1].starts_with('B'));
+            changed |= rerolled.flatten() != text;
+        }
+
+        assert!(changed);
+    }
+
+    #[test]
+    fn reroll_keeps_memoized_expansions() {
+        let mut grammar = Grammar::with_rng(StdRng::seed_from_u64(12345));
+
+        assert!(
+            grammar
+                .start_single(String::from("{@hero} meets {villain}"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(
+                    String::from("villain"),
+                    String::from("{@hero}'s rival {name}")
+                )
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .uniform_rule(String::from("hero"), &numbered("hero"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .uniform_rule(String::from("name"), &numbered("name"))
+                .is_ok()
+        );
+
+        let tree = grammar.generate().expect("Error during generation");
+        let hero = tree.text_of("hero").expect("Expected a hero");
+        let villain = tree.find_rule("villain").expect("Expected a villain");
+        let selector = NodeSelector::Path(tree.path_to(villain).expect("Expected a path"));
+
+        for _ in 0..20 {
+            let rerolled = grammar
+                .reroll(&tree, &selector)
+                .expect("Error during reroll");
+            let heroes: Vec<String> = rerolled
+                .find_rules("hero")
+                .map(ExpansionTree::flatten)
+                .collect();
+            assert_eq!(heroes, vec![hero.clone(), hero.clone()]);
+        }
+    }
+
+    #[test]
+    fn reroll_memoized_expression_everywhere() {
+        let mut grammar = Grammar::with_rng(StdRng::seed_from_u64(12345));
+
+        assert!(
+            grammar
+                .start_single(String::from("{@hero} and {@hero.uppercase}"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .uniform_rule(String::from("hero"), &numbered("hero"))
+                .is_ok()
+        );
+
+        let tree = grammar.generate().expect("Error during generation");
+        let selector = NodeSelector::Rule(String::from("hero"));
+
+        let mut changed = false;
+        for _ in 0..20 {
+            let rerolled = grammar
+                .reroll(&tree, &selector)
+                .expect("Error during reroll");
+            let hero = rerolled.text_of("hero").expect("Expected a hero");
+
+            assert_eq!(
+                rerolled.flatten(),
+                format!("{} and {}", hero, hero.to_uppercase())
+            );
+            changed |= rerolled.flatten() != tree.flatten();
+        }
+
+        assert!(changed);
+    }
+
+    #[test]
+    fn reroll_inside_memoized_expansion_updates_every_copy() {
+        let mut grammar = Grammar::with_rng(StdRng::seed_from_u64(12345));
+
+        assert!(
+            grammar
+                .start_single(String::from("{@hero}, {@hero}"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("hero"), String::from("{name} the {title}"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .uniform_rule(String::from("name"), &numbered("name"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .uniform_rule(String::from("title"), &numbered("title"))
+                .is_ok()
+        );
+
+        let tree = grammar.generate().expect("Error during generation");
+        let selector = NodeSelector::Rule(String::from("title"));
+
+        for _ in 0..20 {
+            let rerolled = grammar
+                .reroll(&tree, &selector)
+                .expect("Error during reroll");
+            let heroes: Vec<String> = rerolled
+                .find_rules("hero")
+                .map(ExpansionTree::flatten)
+                .collect();
+
+            assert_eq!(heroes[0], heroes[1]);
+            assert_eq!(rerolled.text_of("name"), tree.text_of("name"));
+        }
+    }
+
+    #[test]
+    fn reroll_inside_filtered_expression_rerolls_whole_expression() {
+        let mut grammar = Grammar::new();
+        assert!(grammar.start_single(String::from("{b.uppercase}")).is_ok());
+        assert!(
+            grammar
+                .single_rule(String::from("b"), String::from("x{c}y"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("c"), String::from("c"))
+                .is_ok()
+        );
+
+        let tree = grammar.generate().expect("Error during generation");
+        let rerolled = grammar
+            .reroll(&tree, &NodeSelector::Path(vec![0, 0, 0, 0, 0, 0, 0]))
+            .expect("Error during reroll");
+        assert_eq!(rerolled.flatten(), "XCY");
+    }
+
+    #[test]
+    fn reroll_missing_node_fails() {
+        let mut grammar = Grammar::new();
+        assert!(grammar.start_single(String::from("text")).is_ok());
+
+        let tree = grammar.generate().expect("Error during generation");
+
+        for selector in [
+            NodeSelector::Rule(String::from("a")),
+            NodeSelector::Path(vec![3]),
+        ] {
+            let result = grammar.reroll(&tree, &selector);
+            assert!(
+                matches!(result, Err(CalyxError::NodeNotFound { selector: ref s }) if *s == selector)
+            );
+        }
+
+        let rerolled = grammar
+            .reroll(&tree, &NodeSelector::Path(vec![]))
+            .expect("Error during reroll");
+        assert_eq!(rerolled.flatten(), "text");
+    }
+
+    fn story_grammar(seed: u64) -> Grammar {
+        let mut grammar = Grammar::with_rng(StdRng::seed_from_u64(seed));
+
+        assert!(
+            grammar
+                .start_single(String::from(
+                    "{@hero} meets {$villain} in {place}. {@hero.uppercase} wins {prize}."
+                ))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .uniform_rule(String::from("hero"), &numbered("hero"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .uniform_rule(String::from("villain"), &numbered("villain"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .uniform_rule(
+                    String::from("place"),
+                    &vec![
+                        String::from("town"),
+                        String::from("the city"),
+                        String::from("{place} by the river"),
+                    ]
+                )
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .weighted_rule(
+                    String::from("prize"),
+                    &HashMap::from([(String::from("gold"), 1.0), (String::from("glory"), 3.0)])
+                )
+                .is_ok()
+        );
+
+        grammar
+    }
+
+    #[test]
+    fn replay_reproduces_generation() {
+        let mut grammar = story_grammar(12345);
+
+        for _ in 0..10 {
+            let (tree, log) = grammar
+                .generate_with_log()
+                .expect("Error during generation");
+            let log: ChoiceLog = log.to_string().parse().expect("Invalid log");
+
+            let replayed = story_grammar(54321)
+                .replay(&log)
+                .expect("Error during replay");
+            assert_eq!(replayed.flatten(), tree.flatten());
+        }
+    }
+
+    #[test]
+    fn replay_rejects_logs_that_do_not_match_grammar() {
+        let mut grammar = Grammar::new();
+        assert!(grammar.start_single(String::from("{name}")).is_ok());
+        assert!(
+            grammar
+                .uniform_rule(
+                    String::from("name"),
+                    &vec![String::from("Ada"), String::from("Grace")]
+                )
+                .is_ok()
+        );
+
+        let log: ChoiceLog = "start;start:0,name:1".parse().unwrap();
+        let replayed = grammar.replay(&log).expect("Error during replay");
+        assert_eq!(replayed.flatten(), "Grace");
+
+        for (log, expected_position, expected_rule) in [
+            ("start;start:0,name:2", 1, "name"),
+            ("start;start:0,title:0", 1, "name"),
+            ("start;start:0", 1, "name"),
+            ("start;start:0,name:0,name:1", 2, "name"),
+        ] {
+            let log: ChoiceLog = log.parse().unwrap();
+            assert!(matches!(
+                grammar.replay(&log),
+                Err(CalyxError::ChoiceLogMismatch { position, ref rule_name })
+                    if position == expected_position && rule_name == expected_rule
+            ));
+        }
+    }
 }
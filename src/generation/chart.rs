@@ -0,0 +1,126 @@
+use crate::generation::evaluation::Registry;
+use crate::generation::production::ProductionBranch;
+use std::collections::{HashMap, HashSet};
+
+/// A rule expanding to the text between two boundaries.
+pub(crate) type Span<'a> = (&'a str, usize, usize);
+
+/// Remembers what is known about rules expanding to pieces of a text, so that each rule is worked
+/// out once for each piece.
+///
+/// Pieces of the text are given by boundaries, which are the indices of its characters, along
+/// with its length as the last boundary, so that a piece never splits a character.
+pub(crate) struct Chart<'a, T> {
+    rules: HashMap<&'a str, &'a dyn ProductionBranch>,
+    strict: bool,
+    text: &'a str,
+    offsets: Vec<usize>,
+    entries: HashMap<Span<'a>, T>,
+    in_progress: HashSet<Span<'a>>,
+}
+
+impl<'a, T> Chart<'a, T> {
+    pub(crate) fn new(registry: &'a Registry, strict: bool, text: &'a str) -> Self {
+        Chart {
+            rules: registry
+                .rules()
+                .map(|(term, rule)| (term.as_str(), rule))
+                .collect(),
+            strict,
+            text,
+            offsets: text
+                .char_indices()
+                .map(|(offset, _)| offset)
+                .chain([text.len()])
+                .collect(),
+            entries: HashMap::new(),
+            in_progress: HashSet::new(),
+        }
+    }
+
+    /// The last boundary, at the end of the text.
+    pub(crate) fn end(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// The text between two boundaries.
+    pub(crate) fn piece(&self, start: usize, end: usize) -> &'a str {
+        &self.text[self.offsets[start]..self.offsets[end]]
+    }
+
+    /// The boundary just after a literal, if the text between two boundaries starts with it.
+    pub(crate) fn literal(&self, literal: &str, start: usize, end: usize) -> Option<usize> {
+        if !self.piece(start, end).starts_with(literal) {
+            return None;
+        }
+
+        self.offsets
+            .binary_search(&(self.offsets[start] + literal.len()))
+            .ok()
+    }
+
+    /// Looks up a rule by name.
+    pub(crate) fn rule(&self, symbol: &str) -> Option<&'a dyn ProductionBranch> {
+        self.rules.get(symbol).copied()
+    }
+
+    /// Whether a rule that is not defined expands to the text between two boundaries. Undefined
+    /// rules expand to the empty string when lenient, and fail when strict.
+    pub(crate) fn undefined_matches(&self, start: usize, end: usize) -> bool {
+        !self.strict && start == end
+    }
+
+    pub(crate) fn get(&self, span: &Span<'a>) -> Option<&T> {
+        self.entries.get(span)
+    }
+
+    pub(crate) fn insert(&mut self, span: Span<'a>, entry: T) {
+        self.entries.insert(span, entry);
+    }
+
+    /// Takes every entry out of the chart.
+    pub(crate) fn take(&mut self) -> HashMap<Span<'a>, T> {
+        std::mem::take(&mut self.entries)
+    }
+
+    /// Marks a span as being worked out, returning false if it already is, in which case a rule
+    /// refers back to itself without consuming any text.
+    pub(crate) fn begin(&mut self, span: Span<'a>) -> bool {
+        self.in_progress.insert(span)
+    }
+
+    /// Marks a span as no longer being worked out.
+    pub(crate) fn finish(&mut self, span: &Span<'a>) {
+        self.in_progress.remove(span);
+    }
+}
+
+#[cfg(test)]
+mod chart_tests {
+    use crate::generation::chart::Chart;
+    use crate::generation::evaluation::Registry;
+
+    #[test]
+    fn pieces_never_split_characters() {
+        let registry = Registry::new();
+        let chart: Chart<()> = Chart::new(&registry, false, "héllo");
+
+        assert_eq!(chart.end(), 5);
+        assert_eq!(chart.piece(1, 3), "él");
+        assert_eq!(chart.literal("hé", 0, 5), Some(2));
+        assert_eq!(chart.literal("llo", 2, 4), None);
+        assert_eq!(chart.literal("", 3, 3), Some(3));
+    }
+
+    #[test]
+    fn undefined_rules_depend_on_strictness() {
+        let registry = Registry::new();
+        let lenient: Chart<()> = Chart::new(&registry, false, "ab");
+        let strict: Chart<()> = Chart::new(&registry, true, "ab");
+
+        assert!(lenient.rule("missing").is_none());
+        assert!(lenient.undefined_matches(1, 1));
+        assert!(!lenient.undefined_matches(0, 1));
+        assert!(!strict.undefined_matches(1, 1));
+    }
+}
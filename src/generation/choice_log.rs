@@ -0,0 +1,194 @@
+use crate::generation::CalyxError;
+use std::fmt;
+use std::str::FromStr;
+
+/// A template chosen while expanding a rule.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Choice {
+    rule_name: String,
+    index: usize,
+}
+
+impl Choice {
+    pub(crate) fn new(rule_name: String, index: usize) -> Self {
+        Choice { rule_name, index }
+    }
+
+    /// The name of the rule that the template was chosen from.
+    pub fn rule_name(&self) -> &str {
+        &self.rule_name
+    }
+
+    /// The index of the chosen template within its rule.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+/// Every choice made during a generation, in the order they were made, which can be given to
+/// [Grammar::replay](super::Grammar::replay) to generate the same text again.
+///
+/// A log can be stored as a short string with [ToString::to_string] and read back with
+/// [str::parse]. The string is the start symbol, followed by a `;` and each choice as
+/// `rule:index`, separated by commas.
+///
+/// # Examples
+///
+/// ```
+/// use calyx_rs::generation::choice_log::ChoiceLog;
+///
+/// let log: ChoiceLog = "start;start:0,greeting:1".parse().expect("Invalid log");
+/// assert_eq!(log.start_symbol(), "start");
+/// assert_eq!(log.choices()[1].rule_name(), "greeting");
+/// assert_eq!(log.choices()[1].index(), 1);
+/// assert_eq!(log.to_string(), "start;start:0,greeting:1");
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChoiceLog {
+    start_symbol: String,
+    choices: Vec<Choice>,
+}
+
+impl ChoiceLog {
+    pub(crate) fn new(start_symbol: String, choices: Vec<Choice>) -> Self {
+        ChoiceLog {
+            start_symbol,
+            choices,
+        }
+    }
+
+    /// The rule that the generation started from.
+    pub fn start_symbol(&self) -> &str {
+        &self.start_symbol
+    }
+
+    /// The choices made during the generation, in the order they were made.
+    pub fn choices(&self) -> &[Choice] {
+        &self.choices
+    }
+
+    /// The number of choices made during the generation.
+    pub fn len(&self) -> usize {
+        self.choices.len()
+    }
+
+    /// Whether no choices were made during the generation.
+    pub fn is_empty(&self) -> bool {
+        self.choices.is_empty()
+    }
+}
+
+impl fmt::Display for ChoiceLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{};", self.start_symbol)?;
+
+        for (position, choice) in self.choices.iter().enumerate() {
+            if position > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}:{}", choice.rule_name, choice.index)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for ChoiceLog {
+    type Err = CalyxError;
+
+    /// Reads a log from the string written by [ToString::to_string].
+    ///
+    /// # Errors
+    ///
+    /// - [CalyxError::InvalidChoiceLog] if the string is not a valid log.
+    ///
+    fn from_str(log: &str) -> Result<Self, Self::Err> {
+        let (start_symbol, choices) = log
+            .split_once(';')
+            .ok_or_else(|| invalid("missing `;` after the start symbol"))?;
+
+        if start_symbol.is_empty() {
+            return Err(invalid("missing start symbol"));
+        }
+
+        let choices = if choices.is_empty() {
+            Vec::new()
+        } else {
+            choices
+                .split(',')
+                .map(parse_choice)
+                .collect::<Result<Vec<Choice>, CalyxError>>()?
+        };
+
+        Ok(ChoiceLog::new(start_symbol.to_string(), choices))
+    }
+}
+
+fn parse_choice(choice: &str) -> Result<Choice, CalyxError> {
+    let (rule_name, index) = choice
+        .rsplit_once(':')
+        .filter(|(rule_name, _)| !rule_name.is_empty())
+        .ok_or_else(|| invalid(&format!("expected `rule:index`, found {:?}", choice)))?;
+
+    let index = index
+        .parse()
+        .map_err(|_| invalid(&format!("invalid template index {:?}", index)))?;
+
+    Ok(Choice::new(rule_name.to_string(), index))
+}
+
+fn invalid(message: &str) -> CalyxError {
+    CalyxError::InvalidChoiceLog {
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod choice_log_tests {
+    use crate::generation::CalyxError;
+    use crate::generation::choice_log::{Choice, ChoiceLog};
+
+    #[test]
+    fn round_trip_through_string() {
+        let log = ChoiceLog::new(
+            String::from("story"),
+            vec![
+                Choice::new(String::from("story"), 2),
+                Choice::new(String::from("hero_name"), 10),
+            ],
+        );
+
+        assert_eq!(log.to_string(), "story;story:2,hero_name:10");
+        assert_eq!(log.to_string().parse::<ChoiceLog>().unwrap(), log);
+    }
+
+    #[test]
+    fn log_without_choices() {
+        let log = ChoiceLog::new(String::from("start"), vec![]);
+
+        assert_eq!(log.to_string(), "start;");
+        assert_eq!("start;".parse::<ChoiceLog>().unwrap(), log);
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn malformed_logs_are_rejected() {
+        for log in [
+            "start",
+            ";start:0",
+            "start;start",
+            "start;:0",
+            "start;a:x",
+            "start;a:0,",
+        ] {
+            assert!(
+                matches!(
+                    log.parse::<ChoiceLog>(),
+                    Err(CalyxError::InvalidChoiceLog { .. })
+                ),
+                "{:?} should be rejected",
+                log
+            );
+        }
+    }
+}
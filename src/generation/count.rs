@@ -0,0 +1,474 @@
+use crate::generation::evaluation::Registry;
+use crate::generation::production::ProductionBranch;
+use crate::generation::production::depth::MinimumDepths;
+use crate::generation::production::node::TemplateNode;
+use crate::generation::production::parser::Sigil;
+use std::collections::{HashMap, HashSet};
+
+/// The number of derivations of a rule, as returned by
+/// [Grammar::count_derivations](super::Grammar::count_derivations).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DerivationCount {
+    /// A finite number of derivations. Counts too large to represent are [u128::MAX].
+    Finite(u128),
+    /// At most a finite number of derivations. Memoized and unique expansions are shared by every
+    /// template expanded in a derivation, which the count only follows within one template, so
+    /// it can be more than the number of derivations once they are shared by several.
+    AtMost(u128),
+    /// Infinitely many derivations, as the rule can recurse any number of times and still finish.
+    Infinite,
+}
+
+/// Counts the derivations of a rule from the structure of its templates.
+pub(crate) fn count_derivations(
+    registry: &Registry,
+    strict: bool,
+    rule_name: &str,
+) -> DerivationCount {
+    let mut counter = Counter {
+        rules: registry
+            .rules()
+            .map(|(term, rule)| (term.as_str(), rule))
+            .collect(),
+        depths: MinimumDepths::compute(registry.rules()),
+        strict,
+        counts: HashMap::new(),
+    };
+
+    if counter.reaches_cycle(rule_name, &mut HashSet::new(), &mut HashSet::new()) {
+        DerivationCount::Infinite
+    } else if counter.shares_expansions(rule_name) {
+        DerivationCount::AtMost(counter.rule(rule_name))
+    } else {
+        DerivationCount::Finite(counter.rule(rule_name))
+    }
+}
+
+struct Counter<'a> {
+    rules: HashMap<&'a str, &'a dyn ProductionBranch>,
+    depths: MinimumDepths,
+    strict: bool,
+    counts: HashMap<&'a str, u128>,
+}
+
+impl<'a> Counter<'a> {
+    /// Whether a rule can be expanded to completion. Undefined rules expand to the empty string
+    /// when lenient, and fail when strict.
+    fn finishes(&self, symbol: &str) -> bool {
+        if self.rules.contains_key(symbol) {
+            self.depths.terminates(symbol)
+        } else {
+            !self.strict
+        }
+    }
+
+    /// Whether every rule a template refers to can be expanded to completion.
+    fn finishes_template(&self, template: &TemplateNode) -> bool {
+        template
+            .expressions()
+            .all(|expression| self.finishes(&expression.symbol))
+    }
+
+    /// Whether a rule can reach a cycle of rules through templates that can be expanded to
+    /// completion, which makes its number of derivations infinite.
+    fn reaches_cycle(
+        &self,
+        symbol: &'a str,
+        visiting: &mut HashSet<&'a str>,
+        finished: &mut HashSet<&'a str>,
+    ) -> bool {
+        if finished.contains(symbol) {
+            return false;
+        }
+
+        if !visiting.insert(symbol) {
+            return true;
+        }
+
+        let Some(rule) = self.rules.get(symbol).copied() else {
+            return false;
+        };
+
+        for template in rule.templates() {
+            if !self.finishes_template(template) {
+                continue;
+            }
+
+            for expression in template.expressions() {
+                if self.reaches_cycle(&expression.symbol, visiting, finished) {
+                    return true;
+                }
+            }
+        }
+
+        visiting.remove(symbol);
+        finished.insert(symbol);
+        false
+    }
+
+    /// Whether memoized or unique expansions could be shared by more than one template expanded
+    /// in a derivation of a rule, which must not reach a cycle.
+    fn shares_expansions(&self, symbol: &'a str) -> bool {
+        let mut order = Vec::new();
+        self.order_rules(symbol, &mut HashSet::new(), &mut order);
+
+        // the most times each rule can be expanded in a derivation, working down from the rule
+        let mut expansions: HashMap<&str, u128> = HashMap::from([(symbol, 1)]);
+        // the rules with templates that refer to each rule with each sigil
+        let mut sharing: HashMap<(Sigil, &str), Vec<&str>> = HashMap::new();
+
+        for term in order.into_iter().rev() {
+            let times = expansions.get(term).copied().unwrap_or(0);
+            let mut references: HashMap<&str, u128> = HashMap::new();
+
+            for template in self.rules[term].templates() {
+                if !self.finishes_template(template) {
+                    continue;
+                }
+
+                let mut counts: HashMap<&str, u128> = HashMap::new();
+                let mut memoized: HashSet<&str> = HashSet::new();
+                for expression in template.expressions() {
+                    let reference = expression.symbol.as_str();
+                    // later memoized expressions repeat the first
+                    if expression.sigil != Sigil::Memo || memoized.insert(reference) {
+                        *counts.entry(reference).or_default() += 1;
+                    }
+
+                    if expression.sigil != Sigil::None {
+                        let rules = sharing.entry((expression.sigil, reference)).or_default();
+                        if !rules.contains(&term) {
+                            rules.push(term);
+                        }
+                    }
+                }
+
+                for (reference, count) in counts {
+                    let most = references.entry(reference).or_default();
+                    *most = (*most).max(count);
+                }
+            }
+
+            for (reference, count) in references {
+                let total = expansions.entry(reference).or_default();
+                *total = total.saturating_add(times.saturating_mul(count));
+            }
+        }
+
+        sharing.values().any(|rules| match rules.as_slice() {
+            [term] => expansions.get(term).is_some_and(|times| *times > 1),
+            _ => true,
+        })
+    }
+
+    /// Lists the defined rules reachable through templates that can be expanded to completion,
+    /// each after every rule it refers to.
+    fn order_rules(
+        &self,
+        symbol: &'a str,
+        visited: &mut HashSet<&'a str>,
+        order: &mut Vec<&'a str>,
+    ) {
+        let Some(rule) = self.rules.get(symbol).copied() else {
+            return;
+        };
+
+        if !visited.insert(symbol) {
+            return;
+        }
+
+        for template in rule.templates() {
+            if self.finishes_template(template) {
+                for expression in template.expressions() {
+                    self.order_rules(&expression.symbol, visited, order);
+                }
+            }
+        }
+
+        order.push(symbol);
+    }
+
+    fn rule(&mut self, symbol: &'a str) -> u128 {
+        let Some(rule) = self.rules.get(symbol).copied() else {
+            return if self.strict { 0 } else { 1 };
+        };
+
+        if let Some(count) = self.counts.get(symbol) {
+            return *count;
+        }
+
+        let count = rule.templates().into_iter().fold(0u128, |count, template| {
+            count.saturating_add(self.template(template))
+        });
+
+        self.counts.insert(symbol, count);
+        count
+    }
+
+    fn template(&mut self, template: &'a TemplateNode) -> u128 {
+        // the references of templates that cannot finish may recurse forever
+        if !self.finishes_template(template) {
+            return 0;
+        }
+
+        let mut count: u128 = 1;
+        let mut memoized: HashSet<&str> = HashSet::new();
+        let mut unique: Vec<(&str, usize)> = Vec::new();
+
+        for expression in template.expressions() {
+            let symbol = expression.symbol.as_str();
+
+            match expression.sigil {
+                Sigil::None => count = count.saturating_mul(self.rule(symbol)),
+                // later memoized expressions repeat the first
+                Sigil::Memo if memoized.insert(symbol) => {
+                    count = count.saturating_mul(self.rule(symbol))
+                }
+                Sigil::Memo => {}
+                Sigil::Unique => match unique.iter_mut().find(|(seen, _)| *seen == symbol) {
+                    Some((_, occurrences)) => *occurrences += 1,
+                    None => unique.push((symbol, 1)),
+                },
+            }
+        }
+
+        for (symbol, occurrences) in unique {
+            count = count.saturating_mul(self.unique(symbol, occurrences));
+        }
+
+        count
+    }
+
+    /// Counts the derivations of a rule expanded uniquely several times, which go through every
+    /// template of the rule in some order before repeating any.
+    fn unique(&mut self, symbol: &'a str, occurrences: usize) -> u128 {
+        let Some(rule) = self.rules.get(symbol).copied() else {
+            return if self.strict { 0 } else { 1 };
+        };
+
+        let counts: Vec<u128> = rule
+            .templates()
+            .into_iter()
+            .map(|template| self.template(template))
+            .collect();
+
+        if counts.is_empty() {
+            return 0;
+        }
+
+        let cycles = occurrences / counts.len();
+        let remainder = occurrences % counts.len();
+
+        let every_template = ordered_selections(&counts, counts.len());
+        (0..cycles).fold(ordered_selections(&counts, remainder), |count, _| {
+            count.saturating_mul(every_template)
+        })
+    }
+}
+
+/// Counts the ways to choose a number of distinct templates in order, and a derivation of each.
+fn ordered_selections(counts: &[u128], chosen: usize) -> u128 {
+    // the sums of the products of every set of counts of each size
+    let mut products = vec![0u128; chosen + 1];
+    products[0] = 1;
+
+    for count in counts {
+        for size in (1..=chosen).rev() {
+            products[size] =
+                products[size].saturating_add(products[size - 1].saturating_mul(*count));
+        }
+    }
+
+    (1..=chosen as u128).fold(products[chosen], |total, order| total.saturating_mul(order))
+}
+
+#[cfg(test)]
+mod count_tests {
+    use crate::generation::count::DerivationCount;
+    use crate::generation::test_support::{define, grammar};
+    use crate::generation::{Grammar, Options};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use std::collections::HashMap;
+
+    fn enumerated(grammar: &mut Grammar) -> u128 {
+        grammar.enumerate().count() as u128
+    }
+
+    #[test]
+    fn counts_combinations_of_templates() {
+        let mut grammar = grammar(&[
+            ("start", &["{first} {last}", "{first}", "Anonymous"]),
+            ("first", &["Ada", "Grace", "{title} {initial}"]),
+            ("last", &["Lovelace", "Hopper"]),
+            ("title", &["Dr", "Prof"]),
+            ("initial", &["A", "B", "C"]),
+        ]);
+        let weights = HashMap::from([(String::from("Jr"), 1.0), (String::from("Sr"), 2.0)]);
+        assert!(
+            grammar
+                .weighted_rule(String::from("suffix"), &weights)
+                .is_ok()
+        );
+
+        assert_eq!(
+            grammar.count_derivations("first"),
+            DerivationCount::Finite(8)
+        );
+        assert_eq!(
+            grammar.count_derivations("start"),
+            DerivationCount::Finite(25)
+        );
+        assert_eq!(
+            grammar.count_derivations("suffix"),
+            DerivationCount::Finite(2)
+        );
+        assert_eq!(enumerated(&mut grammar), 25);
+    }
+
+    #[test]
+    fn memoized_and_unique_expressions_match_enumeration() {
+        let mut grammar = grammar(&[
+            (
+                "start",
+                &["{@name} and {@name} meet {$name}, {$name} and {$name}"],
+            ),
+            ("name", &["Ada", "Grace", "{title}"]),
+            ("title", &["Dr", "Prof"]),
+        ]);
+
+        // 4 memoized names, then every ordering of 3 distinct templates
+        assert_eq!(
+            grammar.count_derivations("start"),
+            DerivationCount::Finite(4 * 6 * 2)
+        );
+        assert_eq!(enumerated(&mut grammar), 4 * 6 * 2);
+    }
+
+    #[test]
+    fn shared_memoized_and_unique_expressions_are_bounds() {
+        for sigil in ["@", "$"] {
+            let start = format!("{{{sigil}name}} {{other}}");
+            let other = format!("{{{sigil}name}}");
+            let mut grammar = grammar(&[
+                ("start", &[start.as_str()]),
+                ("other", &[other.as_str()]),
+                ("name", &["A", "B"]),
+            ]);
+
+            assert_eq!(
+                grammar.count_derivations("start"),
+                DerivationCount::AtMost(4)
+            );
+            assert_eq!(
+                grammar.count_derivations("other"),
+                DerivationCount::Finite(2)
+            );
+            assert_eq!(enumerated(&mut grammar), 2);
+        }
+
+        // a rule expanded twice shares the expansions of its memoized expression
+        let mut twice = grammar(&[
+            ("start", &["{pair} {pair}"]),
+            ("pair", &["{@name}{@name}"]),
+            ("name", &["A", "B"]),
+        ]);
+        assert_eq!(twice.count_derivations("start"), DerivationCount::AtMost(4));
+        assert_eq!(enumerated(&mut twice), 2);
+
+        // but not a rule expanded once
+        let mut once = grammar(&[
+            ("start", &["{pair}", "{name}"]),
+            ("pair", &["{@name}{@name}", "{$name} {$name}"]),
+            ("name", &["A", "B"]),
+        ]);
+        assert_eq!(once.count_derivations("start"), DerivationCount::Finite(6));
+        assert_eq!(enumerated(&mut once), 6);
+    }
+
+    #[test]
+    fn undefined_and_unfinished_rules() {
+        let rules: &[(&str, &[&str])] = &[
+            ("start", &["{missing}", "{loop}", "done"]),
+            ("loop", &["{loop}"]),
+        ];
+
+        let lenient = grammar(rules);
+        assert_eq!(
+            lenient.count_derivations("start"),
+            DerivationCount::Finite(2)
+        );
+        assert_eq!(
+            lenient.count_derivations("missing"),
+            DerivationCount::Finite(1)
+        );
+
+        let mut strict = Grammar::with_options(Options::new(true, StdRng::seed_from_u64(0)));
+        define(&mut strict, rules);
+        assert_eq!(
+            strict.count_derivations("start"),
+            DerivationCount::Finite(1)
+        );
+        assert_eq!(
+            strict.count_derivations("missing"),
+            DerivationCount::Finite(0)
+        );
+    }
+
+    #[test]
+    fn recursive_rules_are_infinite() {
+        let grammar = grammar(&[
+            ("start", &["{number}", "{loop} {number}"]),
+            ("number", &["{digit}", "{digit}{number}"]),
+            ("digit", &["0", "1"]),
+            ("loop", &["{loop}"]),
+        ]);
+
+        assert_eq!(
+            grammar.count_derivations("start"),
+            DerivationCount::Infinite
+        );
+        assert_eq!(
+            grammar.count_derivations("digit"),
+            DerivationCount::Finite(2)
+        );
+        assert_eq!(
+            grammar.count_derivations("loop"),
+            DerivationCount::Finite(0)
+        );
+    }
+
+    #[test]
+    fn large_counts_saturate() {
+        let digits: Vec<String> = (0..1000).map(|digit| digit.to_string()).collect();
+        let templates: Vec<&str> = digits.iter().map(String::as_str).collect();
+        let grammar = grammar(&[
+            (
+                "start",
+                &[
+                    "{digit}{digit}{digit}{digit}{digit}{digit}{digit}{digit}{digit}{digit}{digit}{digit}{digit}{digit}",
+                ],
+            ),
+            ("digit", &templates),
+        ]);
+
+        assert_eq!(
+            grammar.count_derivations("start"),
+            DerivationCount::Finite(u128::MAX)
+        );
+    }
+
+    #[test]
+    fn estimate_distinct_outputs() {
+        // two derivations of "a"
+        let mut small = grammar(&[("start", &["a", "{letter}", "b"]), ("letter", &["a"])]);
+        assert_eq!(small.estimate_distinct_outputs("start", 100).unwrap(), 2.0);
+
+        let digits: Vec<String> = (0..10).map(|digit| digit.to_string()).collect();
+        let templates: Vec<&str> = digits.iter().map(String::as_str).collect();
+        let mut large = grammar(&[("start", &["{digit}{digit}{digit}"]), ("digit", &templates)]);
+
+        let estimate = large.estimate_distinct_outputs("start", 500).unwrap();
+        assert!((500.0..=1000.0).contains(&estimate), "{}", estimate);
+    }
+}
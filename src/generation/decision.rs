@@ -0,0 +1,312 @@
+use rand::RngExt;
+use rand::seq::SliceRandom;
+
+/// Makes the choices of a generation, such as which template of a rule to expand.
+///
+/// Every [rand::Rng] is a decision source that chooses at random. Other sources can drive
+/// generation from any other input, such as the bytes given by a fuzzer to a [ByteSource].
+///
+/// # Examples
+///
+/// ```
+/// use calyx_rs::generation::decision::DecisionSource;
+/// use calyx_rs::generation::{Grammar, Options};
+///
+/// // always chooses the last template
+/// struct Last;
+///
+/// impl DecisionSource for Last {
+///     fn choose(&mut self, count: usize) -> usize {
+///         count - 1
+///     }
+/// }
+///
+/// let mut grammar: Grammar = Grammar::with_options(Options::with_decision_source(Last));
+/// assert!(grammar.start_uniform(&vec![String::from("a"), String::from("b")]).is_ok());
+/// assert_eq!(grammar.generate().expect("Error during generation").flatten(), "b");
+/// ```
+pub trait DecisionSource {
+    /// Chooses an index in `0..count`. The count is always at least 1.
+    fn choose(&mut self, count: usize) -> usize;
+
+    /// Chooses an index of a list of weights, in proportion to the weights. The weights are
+    /// always positive and finite, and there is always at least one.
+    fn choose_weighted(&mut self, weights: &[f64]) -> usize {
+        let total: f64 = weights.iter().sum();
+        let water_mark = self.choose(WEIGHT_RESOLUTION) as f64 / WEIGHT_RESOLUTION as f64 * total;
+        weighted_index(weights, water_mark)
+    }
+
+    /// Shuffles a list of indices into a random order.
+    fn shuffle(&mut self, items: &mut [usize]) {
+        for index in (1..items.len()).rev() {
+            let other = self.choose(index + 1);
+            items.swap(index, other);
+        }
+    }
+
+    /// Whether this source has run out of decisions to make. Once it has, generation winds down
+    /// as it does past the [depth budget](super::Options::depth_budget), so that the output is
+    /// finished as soon as possible.
+    fn is_exhausted(&self) -> bool {
+        false
+    }
+
+    /// Whether generation only chooses templates that can finish within the
+    /// [max depth](super::Options::max_depth), rather than failing once it is exceeded, so that
+    /// every sequence of decisions finishes.
+    fn avoids_recursion_limit(&self) -> bool {
+        false
+    }
+}
+
+/// The number of distinct water marks used by the default [DecisionSource::choose_weighted].
+const WEIGHT_RESOLUTION: usize = 1 << 16;
+
+/// Finds the weight that a water mark between 0 and the total weight falls within.
+fn weighted_index(weights: &[f64], water_mark: f64) -> usize {
+    let mut cumulative = 0.0;
+
+    for (index, weight) in weights.iter().enumerate() {
+        cumulative += weight;
+        if water_mark < cumulative {
+            return index;
+        }
+    }
+
+    // only reachable through rounding errors, as the water mark is less than the total weight
+    weights.len() - 1
+}
+
+impl<R: rand::Rng + ?Sized> DecisionSource for R {
+    fn choose(&mut self, count: usize) -> usize {
+        self.random_range(0..count)
+    }
+
+    fn choose_weighted(&mut self, weights: &[f64]) -> usize {
+        let total: f64 = weights.iter().sum();
+        let water_mark = self.random::<f64>() * total;
+        weighted_index(weights, water_mark)
+    }
+
+    fn shuffle(&mut self, items: &mut [usize]) {
+        SliceRandom::shuffle(items, self);
+    }
+}
+
+/// Makes decisions by reading from a string of bytes, so that every byte string maps to exactly
+/// one output of a grammar. This is intended to generate inputs for fuzzers from the bytes that
+/// they provide.
+///
+/// Each choice among up to 256 templates reads one byte, larger choices read as many bytes as
+/// needed, and rules with only one template read nothing. Once every byte has been read, or once
+/// rules are nested so deeply that any deeper template would exceed the max depth, each choice is
+/// among the templates that finish expanding soonest.
+///
+/// # Examples
+///
+/// ```
+/// use calyx_rs::generation::decision::ByteSource;
+/// use calyx_rs::generation::{Grammar, Options};
+///
+/// let mut grammar: Grammar = Grammar::with_options(Options::with_decision_source(ByteSource::new([1, 0])));
+/// assert!(grammar.start_single(String::from("{a}{b}")).is_ok());
+/// assert!(grammar.uniform_rule(String::from("a"), &vec![String::from("x"), String::from("y")]).is_ok());
+/// assert!(grammar.uniform_rule(String::from("b"), &vec![String::from("x"), String::from("y")]).is_ok());
+///
+/// assert_eq!(grammar.generate().expect("Error during generation").flatten(), "yx");
+/// ```
+pub struct ByteSource {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl ByteSource {
+    /// Creates a source that reads from the start of some bytes.
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
+        ByteSource {
+            bytes: bytes.into(),
+            position: 0,
+        }
+    }
+
+    /// The number of bytes read so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Reads the next byte, or 0 if every byte has been read.
+    fn next_byte(&mut self) -> u8 {
+        match self.bytes.get(self.position) {
+            Some(byte) => {
+                self.position += 1;
+                *byte
+            }
+            None => 0,
+        }
+    }
+}
+
+impl DecisionSource for ByteSource {
+    fn choose(&mut self, count: usize) -> usize {
+        let mut value: usize = 0;
+        let mut range: usize = 1;
+
+        while range < count {
+            value = (value << 8) | self.next_byte() as usize;
+            range = range.saturating_mul(256);
+        }
+
+        value % count
+    }
+
+    fn choose_weighted(&mut self, weights: &[f64]) -> usize {
+        if weights.len() == 1 {
+            return 0;
+        }
+
+        let total: f64 = weights.iter().sum();
+        let water_mark = self.choose(WEIGHT_RESOLUTION) as f64 / WEIGHT_RESOLUTION as f64 * total;
+        weighted_index(weights, water_mark)
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn avoids_recursion_limit(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod decision_tests {
+    use crate::generation::decision::{ByteSource, DecisionSource};
+    use crate::generation::test_support::define;
+    use crate::generation::{Grammar, Options};
+    use rand::prelude::StdRng;
+    use rand::{RngExt, SeedableRng};
+
+    #[test]
+    fn bytes_choose_indices() {
+        let mut source = ByteSource::new([7, 7, 1, 2, 200]);
+
+        assert_eq!(source.choose(1), 0);
+        assert_eq!(source.position(), 0);
+        assert_eq!(source.choose(3), 1);
+        assert_eq!(source.choose(100), 7);
+        assert_eq!(source.choose(1000), 0x0102);
+        assert!(!source.is_exhausted());
+        assert_eq!(source.choose(256), 200);
+        assert!(source.is_exhausted());
+        assert_eq!(source.choose(5), 0);
+    }
+
+    #[test]
+    fn bytes_choose_weighted() {
+        let weights = [1.0, 3.0];
+
+        assert_eq!(ByteSource::new([0x3f, 0xff]).choose_weighted(&weights), 0);
+        assert_eq!(ByteSource::new([0x40, 0x00]).choose_weighted(&weights), 1);
+        assert_eq!(ByteSource::new([0xff, 0xff]).choose_weighted(&weights), 1);
+
+        let mut source = ByteSource::new([1]);
+        assert_eq!(source.choose_weighted(&[2.0]), 0);
+        assert_eq!(source.position(), 0);
+    }
+
+    #[test]
+    fn bytes_shuffle() {
+        let mut items = [0, 1, 2, 3];
+        ByteSource::new([0, 0, 0]).shuffle(&mut items);
+
+        assert_eq!(items, [1, 2, 3, 0]);
+    }
+
+    fn generate(bytes: &[u8]) -> String {
+        let options = Options::with_decision_source(ByteSource::new(bytes));
+        let mut grammar = Grammar::with_options(options);
+
+        let expr = ["{expr} + {expr}", "({expr})", "{number}"];
+        let number = ["{digit}", "{digit}{number}"];
+        let digit = ["0", "1", "2"];
+
+        define(
+            &mut grammar,
+            &[("expr", &expr), ("number", &number), ("digit", &digit)],
+        );
+
+        grammar
+            .generate_from(&String::from("expr"))
+            .expect("Error during generation")
+            .flatten()
+    }
+
+    #[test]
+    fn every_byte_string_finishes() {
+        let mut rng = StdRng::seed_from_u64(12345);
+
+        assert_eq!(generate(&[]), "0");
+        assert_eq!(generate(&[1, 2, 0, 1]), "(1)");
+
+        for _ in 0..200 {
+            let length = rng.random_range(0..64);
+            let bytes: Vec<u8> = (0..length).map(|_| rng.random()).collect();
+
+            assert_eq!(generate(&bytes), generate(&bytes));
+        }
+    }
+
+    #[test]
+    fn long_byte_strings_stay_within_max_depth() {
+        let mut grammar: Grammar =
+            Grammar::with_options(Options::with_decision_source(ByteSource::new(vec![
+                1;
+                1000
+            ])));
+        assert!(
+            grammar
+                .start_uniform(&vec![String::from("x"), String::from("({start})")])
+                .is_ok()
+        );
+
+        let output = grammar
+            .generate()
+            .expect("Error during generation")
+            .flatten();
+        let depth = output.matches('(').count();
+
+        assert!(depth > 0 && depth < grammar.options.max_depth);
+        assert_eq!(
+            output,
+            format!("{}x{}", "(".repeat(depth), ")".repeat(depth))
+        );
+    }
+
+    #[test]
+    fn byte_strings_stay_within_small_max_depths() {
+        let mut rng = StdRng::seed_from_u64(12345);
+
+        let start = ["{a}{a}", "z"];
+        let a = ["{start}", "{b}"];
+        let b = ["{b}", "q{start}"];
+
+        for max_depth in 4..=20 {
+            for attempt in 0..50 {
+                let bytes: Vec<u8> = if attempt == 0 {
+                    vec![0; 3]
+                } else {
+                    let length = rng.random_range(0..64);
+                    (0..length).map(|_| rng.random()).collect()
+                };
+
+                let options =
+                    Options::with_decision_source(ByteSource::new(bytes)).with_max_depth(max_depth);
+                let mut grammar = Grammar::with_options(options);
+                define(&mut grammar, &[("start", &start), ("a", &a), ("b", &b)]);
+
+                assert!(grammar.generate().is_ok());
+            }
+        }
+    }
+}
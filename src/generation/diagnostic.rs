@@ -0,0 +1,149 @@
+use std::fmt;
+use std::ops::Range;
+
+/// Describes a problem found while parsing a template, along with where it was found.
+///
+/// The [Display](fmt::Display) implementation renders the offending line of the template with the
+/// problem underlined:
+///
+/// ```text
+/// error: empty expression
+///  --> rule `start`, template 0, byte 6
+///   |
+///   | Hello {} world
+///   |       ^^
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    message: String,
+    template: String,
+    span: Range<usize>,
+    rule_name: Option<String>,
+    template_index: Option<usize>,
+}
+
+impl Diagnostic {
+    pub(crate) fn new(message: String, template: &str, span: Range<usize>) -> Self {
+        Diagnostic {
+            message,
+            template: template.to_string(),
+            span,
+            rule_name: None,
+            template_index: None,
+        }
+    }
+
+    /// A description of the problem.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The full template that the problem was found in.
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    /// The byte offsets of the problem in the [template](Self::template).
+    pub fn span(&self) -> Range<usize> {
+        self.span.clone()
+    }
+
+    /// The part of the template that the problem was found in.
+    pub fn fragment(&self) -> &str {
+        self.template.get(self.span.clone()).unwrap_or_default()
+    }
+
+    /// The name of the rule that the template belongs to, if known.
+    pub fn rule_name(&self) -> Option<&str> {
+        self.rule_name.as_deref()
+    }
+
+    /// The index of the template within its rule, if known.
+    ///
+    /// The templates of weighted rules are indexed in alphabetical order.
+    pub fn template_index(&self) -> Option<usize> {
+        self.template_index
+    }
+
+    pub(crate) fn set_rule_name(&mut self, rule_name: &str) {
+        self.rule_name.get_or_insert_with(|| rule_name.to_string());
+    }
+
+    pub(crate) fn set_template_index(&mut self, index: usize) {
+        self.template_index.get_or_insert(index);
+    }
+
+    fn location(&self) -> String {
+        let mut location = Vec::new();
+
+        if let Some(rule_name) = &self.rule_name {
+            location.push(format!("rule `{}`", rule_name));
+        }
+
+        if let Some(index) = self.template_index {
+            location.push(format!("template {}", index));
+        }
+
+        location.push(format!("byte {}", self.span.start));
+        location.join(", ")
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let start = self.span.start.min(self.template.len());
+        let line_start = self.template[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.template[start..]
+            .find('\n')
+            .map_or(self.template.len(), |i| start + i);
+        let line = &self.template[line_start..line_end];
+
+        let end = self.span.end.clamp(start, line_end);
+        let indent = self.template[line_start..start].chars().count();
+        let width = self.template[start..end].chars().count().max(1);
+
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(f, " --> {}", self.location())?;
+        writeln!(f, "  |")?;
+        writeln!(f, "  | {}", line)?;
+        write!(f, "  | {}{}", " ".repeat(indent), "^".repeat(width))
+    }
+}
+
+#[cfg(test)]
+mod diagnostic_tests {
+    use crate::generation::diagnostic::Diagnostic;
+
+    #[test]
+    fn display_underlines_span() {
+        let mut diagnostic =
+            Diagnostic::new(String::from("empty expression"), "Hello {} world", 6..8);
+        diagnostic.set_rule_name("start");
+        diagnostic.set_template_index(0);
+
+        assert_eq!(
+            diagnostic.to_string(),
+            "error: empty expression\n --> rule `start`, template 0, byte 6\n  |\n  | Hello {} world\n  |       ^^"
+        );
+    }
+
+    #[test]
+    fn display_only_shows_line_of_span() {
+        let diagnostic = Diagnostic::new(
+            String::from("unclosed expression"),
+            "first\nsé {a\nlast",
+            10..13,
+        );
+
+        assert_eq!(
+            diagnostic.to_string(),
+            "error: unclosed expression\n --> byte 10\n  |\n  | sé {a\n  |    ^^"
+        );
+    }
+
+    #[test]
+    fn fragment_is_spanned_text() {
+        let diagnostic = Diagnostic::new(String::from("empty filter name"), "{a..b}", 2..3);
+        assert_eq!(diagnostic.fragment(), ".");
+    }
+}
@@ -0,0 +1,616 @@
+use crate::generation::{CalyxError, Grammar};
+use serde_json::{Map, Number, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// The definition of a single rule, using the original templates it was defined with.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuleDefinition {
+    Single(String),
+    Uniform(Vec<String>),
+    Weighted(HashMap<String, f64>),
+    Mapping(Vec<(String, String)>),
+}
+
+/// A serializable set of rule definitions, which can be read from and written to external files.
+///
+/// Documents are the bridge between a [Grammar] and its external file formats: they can be
+/// parsed from a file and defined in a grammar, or exported from a grammar with
+/// [Grammar::to_document] and written back out.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GrammarDocument {
+    rules: BTreeMap<String, RuleDefinition>,
+}
+
+impl RuleDefinition {
+    pub(crate) fn define(&self, term: &str, grammar: &mut Grammar) -> Result<(), CalyxError> {
+        let result = match self {
+            RuleDefinition::Single(production) => {
+                grammar.single_rule(term.to_string(), production.clone())
+            }
+            RuleDefinition::Uniform(production) => {
+                grammar.uniform_rule(term.to_string(), production)
+            }
+            RuleDefinition::Weighted(production) => {
+                grammar.weighted_rule(term.to_string(), production)
+            }
+            RuleDefinition::Mapping(pairs) => grammar.mapping_rule(term.to_string(), pairs),
+        };
+
+        result.map_err(|error| match error {
+            CalyxError::DuplicateRule { .. } => error,
+            cause => CalyxError::InvalidRule {
+                rule_name: term.to_string(),
+                cause: Box::new(cause),
+            },
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            RuleDefinition::Single(template) => Value::String(template.clone()),
+            RuleDefinition::Uniform(templates) => Value::Array(
+                templates
+                    .iter()
+                    .map(|template| Value::String(template.clone()))
+                    .collect(),
+            ),
+            RuleDefinition::Weighted(weights) => Value::Object(
+                weights
+                    .iter()
+                    .map(|(template, weight)| {
+                        let weight = Number::from_f64(*weight).map_or(Value::Null, Value::Number);
+                        (template.clone(), weight)
+                    })
+                    .collect(),
+            ),
+            RuleDefinition::Mapping(pairs) => Value::Array(
+                pairs
+                    .iter()
+                    .map(|(pattern, replacement)| {
+                        Value::Array(vec![
+                            Value::String(pattern.clone()),
+                            Value::String(replacement.clone()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl GrammarDocument {
+    /// Creates a new document with no rules.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The rules of this document, ordered by name.
+    pub fn rules(&self) -> &BTreeMap<String, RuleDefinition> {
+        &self.rules
+    }
+
+    /// Adds a rule to this document, replacing any existing rule with the same name.
+    pub fn insert(&mut self, term: String, definition: RuleDefinition) {
+        self.rules.insert(term, definition);
+    }
+
+    /// Parses a JSON document in the format read by Ruby Calyx's `Calyx::Grammar.load`.
+    ///
+    /// The document must be an object mapping rule names to productions, where a string is a
+    /// single production, an array of strings is a uniform production, and an object of template
+    /// to weight is a weighted production. An array of `[pattern, replacement]` pairs defines a
+    /// mapping rule.
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidDocument] if the document is not valid JSON or is not an object.
+    /// - [CalyxError::InvalidRule] if a rule in the document has an invalid production.
+    ///
+    pub fn from_json_str(json: &str) -> Result<Self, CalyxError> {
+        let document: Value =
+            serde_json::from_str(json).map_err(|error| CalyxError::InvalidDocument {
+                message: error.to_string(),
+            })?;
+
+        Self::from_value(&document)
+    }
+
+    /// Parses a YAML document with the same structure as [Self::from_json_str].
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidDocument] if the document is not valid YAML or is not a mapping.
+    /// - [CalyxError::InvalidRule] if a rule in the document has an invalid production.
+    ///
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml_str(yaml: &str) -> Result<Self, CalyxError> {
+        let document: serde_yaml_ng::Value =
+            serde_yaml_ng::from_str(yaml).map_err(|error| CalyxError::InvalidDocument {
+                message: error.to_string(),
+            })?;
+
+        Self::from_value(&yaml_to_json(document)?)
+    }
+
+    /// Parses a TOML document with the same structure as [Self::from_json_str].
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidDocument] if the document is not valid TOML.
+    /// - [CalyxError::InvalidRule] if a rule in the document has an invalid production.
+    ///
+    #[cfg(feature = "toml")]
+    pub fn from_toml_str(toml: &str) -> Result<Self, CalyxError> {
+        let document: Value =
+            toml::from_str(toml).map_err(|error| CalyxError::InvalidDocument {
+                message: error.to_string(),
+            })?;
+
+        Self::from_value(&document)
+    }
+
+    /// Writes this document as pretty-printed JSON, in the format read by [Self::from_json_str].
+    pub fn to_json_string(&self) -> String {
+        let rules: Map<String, Value> = self
+            .rules
+            .iter()
+            .map(|(term, definition)| (term.clone(), definition.to_json()))
+            .collect();
+
+        // serializing a JSON value with string keys cannot fail
+        serde_json::to_string_pretty(&Value::Object(rules)).unwrap_or_default()
+    }
+
+    /// Writes this document to a JSON file, in the format read by [Self::from_json_str].
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidDocument] if the file could not be written.
+    ///
+    pub fn save_json<P: AsRef<Path>>(&self, path: P) -> Result<(), CalyxError> {
+        std::fs::write(path.as_ref(), self.to_json_string()).map_err(|error| {
+            CalyxError::InvalidDocument {
+                message: format!("unable to write {}: {}", path.as_ref().display(), error),
+            }
+        })
+    }
+
+    fn from_value(document: &Value) -> Result<Self, CalyxError> {
+        let rules = document
+            .as_object()
+            .ok_or_else(|| CalyxError::InvalidDocument {
+                message: String::from("expected the document to be an object of rules"),
+            })?;
+
+        let mut parsed = GrammarDocument::new();
+
+        for (term, production) in rules {
+            let definition = parse_rule(term, production)?;
+            parsed.insert(term.clone(), definition);
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Reads a file into a string, reporting any IO errors as [CalyxError::InvalidDocument].
+pub(crate) fn read_file<P: AsRef<Path>>(path: P) -> Result<String, CalyxError> {
+    std::fs::read_to_string(path.as_ref()).map_err(|error| CalyxError::InvalidDocument {
+        message: format!("unable to read {}: {}", path.as_ref().display(), error),
+    })
+}
+
+/// Converts a YAML value to JSON. YAML allows any value as the key of a mapping, so scalar keys
+/// such as the `1` in `1: 1.0` are written as strings.
+#[cfg(feature = "yaml")]
+fn yaml_to_json(value: serde_yaml_ng::Value) -> Result<Value, CalyxError> {
+    use serde_yaml_ng::Value as Yaml;
+
+    Ok(match value {
+        Yaml::Null => Value::Null,
+        Yaml::Bool(boolean) => Value::Bool(boolean),
+        Yaml::Number(number) => {
+            if let Some(integer) = number.as_i64() {
+                Value::from(integer)
+            } else if let Some(integer) = number.as_u64() {
+                Value::from(integer)
+            } else {
+                number
+                    .as_f64()
+                    .and_then(Number::from_f64)
+                    .map_or(Value::Null, Value::Number)
+            }
+        }
+        Yaml::String(string) => Value::String(string),
+        Yaml::Sequence(items) => Value::Array(
+            items
+                .into_iter()
+                .map(yaml_to_json)
+                .collect::<Result<_, _>>()?,
+        ),
+        Yaml::Mapping(entries) => {
+            let mut object = Map::with_capacity(entries.len());
+
+            for (key, value) in entries {
+                let key = match key {
+                    Yaml::String(string) => string,
+                    Yaml::Number(number) => number.to_string(),
+                    Yaml::Bool(boolean) => boolean.to_string(),
+                    _ => {
+                        return Err(CalyxError::InvalidDocument {
+                            message: String::from("expected every key to be a string or number"),
+                        });
+                    }
+                };
+                object.insert(key, yaml_to_json(value)?);
+            }
+
+            Value::Object(object)
+        }
+        Yaml::Tagged(tagged) => yaml_to_json(tagged.value)?,
+    })
+}
+
+fn parse_rule(term: &str, production: &Value) -> Result<RuleDefinition, CalyxError> {
+    match production {
+        Value::String(template) => Ok(RuleDefinition::Single(template.clone())),
+        Value::Array(pairs) if pairs.first().is_some_and(Value::is_array) => {
+            parse_mapping(term, pairs)
+        }
+        Value::Array(templates) => {
+            let mut choices = Vec::with_capacity(templates.len());
+
+            for template in templates {
+                let template = template
+                    .as_str()
+                    .ok_or_else(|| invalid_rule(term, "expected every choice to be a string"))?;
+                choices.push(template.to_string());
+            }
+
+            Ok(RuleDefinition::Uniform(choices))
+        }
+        Value::Object(weights) => parse_weights(term, weights),
+        _ => Err(invalid_rule(
+            term,
+            "expected a string, array of strings, or object of weights",
+        )),
+    }
+}
+
+fn parse_weights(term: &str, weights: &Map<String, Value>) -> Result<RuleDefinition, CalyxError> {
+    let mut choices = HashMap::with_capacity(weights.len());
+
+    for (template, weight) in weights {
+        let weight = weight
+            .as_f64()
+            .ok_or_else(|| invalid_rule(term, "expected every weight to be a number"))?;
+        choices.insert(template.clone(), weight);
+    }
+
+    Ok(RuleDefinition::Weighted(choices))
+}
+
+fn parse_mapping(term: &str, pairs: &[Value]) -> Result<RuleDefinition, CalyxError> {
+    let mut mapping = Vec::with_capacity(pairs.len());
+
+    for pair in pairs {
+        match pair.as_array().map(Vec::as_slice) {
+            Some([Value::String(pattern), Value::String(replacement)]) => {
+                mapping.push((pattern.clone(), replacement.clone()));
+            }
+            _ => {
+                return Err(invalid_rule(
+                    term,
+                    "expected every mapping to be a pair of pattern and replacement strings",
+                ));
+            }
+        }
+    }
+
+    Ok(RuleDefinition::Mapping(mapping))
+}
+
+fn invalid_rule(term: &str, message: &str) -> CalyxError {
+    CalyxError::InvalidRule {
+        rule_name: term.to_string(),
+        cause: Box::new(CalyxError::InvalidDocument {
+            message: message.to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod json_tests {
+    use crate::generation::{CalyxError, Grammar};
+    use rand::SeedableRng;
+    use rand::prelude::StdRng;
+
+    #[test]
+    fn load_single_rule() {
+        let mut grammar = Grammar::from_json_str(r#"{ "start": "Hello world." }"#)
+            .expect("Error loading grammar");
+
+        let text = grammar
+            .generate()
+            .expect("Error during generation")
+            .flatten();
+        assert_eq!(text, "Hello world.");
+    }
+
+    #[test]
+    fn load_uniform_and_weighted_rules() {
+        let json = r#"{
+            "start": "{greeting} {name}",
+            "greeting": ["Hello", "Hi"],
+            "name": { "world": 1, "there": 0.5 }
+        }"#;
+
+        let mut grammar = Grammar::with_rng(StdRng::seed_from_u64(12345));
+        assert!(grammar.define_json_str(json).is_ok());
+
+        let text = grammar
+            .generate()
+            .expect("Error during generation")
+            .flatten();
+        assert!(["Hello world", "Hello there", "Hi world", "Hi there"].contains(&text.as_str()));
+    }
+
+    #[test]
+    fn load_mapping_rule() {
+        let json = r#"{
+            "start": "{animal>plural}",
+            "animal": "pony",
+            "plural": [["(.+)y$", "${1}ies"], ["(.+)", "${1}s"]]
+        }"#;
+
+        let mut grammar = Grammar::from_json_str(json).expect("Error loading grammar");
+        let text = grammar
+            .generate()
+            .expect("Error during generation")
+            .flatten();
+        assert_eq!(text, "ponies");
+    }
+
+    #[test]
+    fn invalid_mapping_reports_rule_name() {
+        let result = Grammar::from_json_str(r#"{ "plural": [["(.+)"]] }"#);
+        assert!(
+            matches!(result, Err(CalyxError::InvalidRule { ref rule_name, .. }) if rule_name == "plural")
+        );
+    }
+
+    #[test]
+    fn malformed_json_fails() {
+        let result = Grammar::from_json_str(r#"{ "start": "#);
+        assert!(matches!(result, Err(CalyxError::InvalidDocument { .. })));
+    }
+
+    #[test]
+    fn non_object_document_fails() {
+        let result = Grammar::from_json_str(r#"["Hello"]"#);
+        assert!(matches!(result, Err(CalyxError::InvalidDocument { .. })));
+    }
+
+    #[test]
+    fn invalid_production_reports_rule_name() {
+        let result = Grammar::from_json_str(r#"{ "start": "{name}", "name": 42 }"#);
+        assert!(
+            matches!(result, Err(CalyxError::InvalidRule { ref rule_name, .. }) if rule_name == "name")
+        );
+    }
+
+    #[test]
+    fn invalid_weight_reports_rule_name() {
+        let result = Grammar::from_json_str(r#"{ "start": { "a": 1, "b": -1 } }"#);
+        assert!(matches!(
+            result,
+            Err(CalyxError::InvalidRule { ref rule_name, ref cause })
+                if rule_name == "start" && matches!(**cause, CalyxError::InvalidWeight { .. })
+        ));
+    }
+
+    #[test]
+    fn duplicate_rule_fails() {
+        let mut grammar = Grammar::new();
+        assert!(grammar.start_single(String::from("Hello")).is_ok());
+
+        let result = grammar.define_json_str(r#"{ "start": "Hi" }"#);
+        assert!(
+            matches!(result, Err(CalyxError::DuplicateRule { ref rule_name }) if rule_name == "start")
+        );
+    }
+}
+
+#[cfg(all(test, feature = "yaml"))]
+mod yaml_tests {
+    use crate::generation::document::{GrammarDocument, RuleDefinition};
+    use crate::generation::{CalyxError, Grammar};
+    use std::collections::HashMap;
+
+    #[test]
+    fn load_yaml_rules() {
+        let yaml = "
+start: '{greeting} {name}'
+greeting:
+  - Hello
+name:
+  world: 1
+";
+
+        let mut grammar = Grammar::from_yaml_str(yaml).expect("Error loading grammar");
+        let text = grammar
+            .generate()
+            .expect("Error during generation")
+            .flatten();
+        assert_eq!(text, "Hello world");
+    }
+
+    #[test]
+    fn load_yaml_multi_line_string() {
+        let yaml = "
+start: |-
+  Once upon a time,
+  there was {name}.
+name: a dragon
+";
+
+        let mut grammar = Grammar::from_yaml_str(yaml).expect("Error loading grammar");
+        let text = grammar
+            .generate()
+            .expect("Error during generation")
+            .flatten();
+        assert_eq!(text, "Once upon a time,\nthere was a dragon.");
+    }
+
+    #[test]
+    fn load_yaml_number_keys() {
+        let yaml = "
+start:
+  1: 1.0
+  2.5: 3
+";
+
+        let document = GrammarDocument::from_yaml_str(yaml).expect("Error loading document");
+        let weights = HashMap::from([(String::from("1"), 1.0), (String::from("2.5"), 3.0)]);
+        assert_eq!(
+            document.rules().get("start"),
+            Some(&RuleDefinition::Weighted(weights))
+        );
+    }
+
+    #[test]
+    fn invalid_yaml_production_reports_rule_name() {
+        let result = Grammar::from_yaml_str("start:\n  - [nested]\n");
+        assert!(
+            matches!(result, Err(CalyxError::InvalidRule { ref rule_name, .. }) if rule_name == "start")
+        );
+    }
+}
+
+#[cfg(all(test, feature = "toml"))]
+mod toml_tests {
+    use crate::generation::{CalyxError, Grammar};
+
+    #[test]
+    fn load_toml_rules() {
+        let toml = r#"
+start = "{greeting} {name}"
+greeting = ["Hello"]
+
+[name]
+world = 1
+"#;
+
+        let mut grammar = Grammar::from_toml_str(toml).expect("Error loading grammar");
+        let text = grammar
+            .generate()
+            .expect("Error during generation")
+            .flatten();
+        assert_eq!(text, "Hello world");
+    }
+
+    #[test]
+    fn load_toml_multi_line_string() {
+        let toml = r#"
+start = """
+Once upon a time,
+there was {name}."""
+name = "a dragon"
+"#;
+
+        let mut grammar = Grammar::from_toml_str(toml).expect("Error loading grammar");
+        let text = grammar
+            .generate()
+            .expect("Error during generation")
+            .flatten();
+        assert_eq!(text, "Once upon a time,\nthere was a dragon.");
+    }
+
+    #[test]
+    fn malformed_toml_fails() {
+        let result = Grammar::from_toml_str("start = ");
+        assert!(matches!(result, Err(CalyxError::InvalidDocument { .. })));
+    }
+}
+
+#[cfg(test)]
+mod export_tests {
+    use crate::generation::Grammar;
+    use crate::generation::document::{GrammarDocument, RuleDefinition};
+    use std::collections::HashMap;
+
+    #[test]
+    fn export_reproduces_original_templates() {
+        let mut grammar = Grammar::new();
+        assert!(
+            grammar
+                .start_single(String::from("{greeting} {@name.uppercase}"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .uniform_rule(
+                    String::from("greeting"),
+                    &vec![String::from("Hello"), String::from("Hi")]
+                )
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .weighted_rule(
+                    String::from("name"),
+                    &HashMap::from([(String::from("world"), 2.0), (String::from("there"), 0.5)])
+                )
+                .is_ok()
+        );
+
+        let document = grammar.to_document();
+
+        let mut expected = GrammarDocument::new();
+        expected.insert(
+            String::from("start"),
+            RuleDefinition::Single(String::from("{greeting} {@name.uppercase}")),
+        );
+        expected.insert(
+            String::from("greeting"),
+            RuleDefinition::Uniform(vec![String::from("Hello"), String::from("Hi")]),
+        );
+        expected.insert(
+            String::from("name"),
+            RuleDefinition::Weighted(HashMap::from([
+                (String::from("world"), 2.0),
+                (String::from("there"), 0.5),
+            ])),
+        );
+
+        assert_eq!(document, expected);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let json = r#"{
+            "start": "{greeting} {name>shout}",
+            "shout": [["(.+)", "${1}!"]],
+            "greeting": ["Hello", "Hi"],
+            "name": { "world": 1.5, "there": 0.5 }
+        }"#;
+
+        let grammar = Grammar::from_json_str(json).expect("Error loading grammar");
+        let exported = grammar.to_document().to_json_string();
+
+        let reloaded = Grammar::from_json_str(&exported).expect("Error reloading grammar");
+        assert_eq!(grammar.to_document(), reloaded.to_document());
+    }
+    #[test]
+    fn empty_rules_round_trip_as_uniform_rules() {
+        let mut grammar = Grammar::new();
+        assert!(grammar.uniform_rule(String::from("blank"), &vec![]).is_ok());
+        assert!(grammar.mapping_rule(String::from("plural"), &[]).is_err());
+
+        let exported = grammar.to_document().to_json_string();
+        let reloaded = Grammar::from_json_str(&exported).expect("Error reloading grammar");
+
+        assert_eq!(
+            reloaded.to_document().rules().get("blank"),
+            Some(&RuleDefinition::Uniform(vec![]))
+        );
+        assert_eq!(grammar.to_document(), reloaded.to_document());
+    }
+}
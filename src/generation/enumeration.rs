@@ -0,0 +1,186 @@
+use crate::generation::evaluation::{EvaluationContext, Odds};
+use crate::generation::expansion_tree::{ExpansionTree, ExpansionType};
+use crate::generation::{CalyxError, Grammar};
+use std::collections::HashMap;
+
+/// Iterates over every derivation of a grammar, as returned by
+/// [Grammar::enumerate_from](super::Grammar::enumerate_from).
+///
+/// Derivations are listed in order of the templates chosen, earliest choice first. Each is
+/// generated only when it is needed, so large grammars can be enumerated a piece at a time.
+pub struct Enumeration<'a> {
+    grammar: &'a mut Grammar,
+    start_symbol: String,
+    max_depth: Option<usize>,
+    prefix: Option<Vec<usize>>,
+}
+
+impl<'a> Enumeration<'a> {
+    pub(crate) fn new(grammar: &'a mut Grammar, start_symbol: String) -> Self {
+        Enumeration {
+            grammar,
+            start_symbol,
+            max_depth: None,
+            prefix: Some(Vec::new()),
+        }
+    }
+
+    /// Only lists the derivations that nest rules at most this deep, skipping the rest. This
+    /// makes the enumeration of a recursive grammar finite.
+    ///
+    /// Without a maximum depth, a derivation that nests rules deeper than the
+    /// [maximum depth](super::Options::max_depth) of the grammar fails the enumeration instead.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+}
+
+impl Iterator for Enumeration<'_> {
+    type Item = Result<ExpansionTree, CalyxError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let prefix = self.prefix.take()?;
+
+            let context = HashMap::new();
+            let mut eval_context =
+                EvaluationContext::enumerate(self.grammar, &context, &prefix, self.max_depth);
+            let result = eval_context.expand_and_evaluate(&self.start_symbol);
+
+            self.prefix = next_prefix(eval_context.enumerated());
+
+            match result {
+                Ok(tree) => {
+                    let rule_name = self.start_symbol.clone();
+                    return Some(Ok(ExpansionTree::chain(
+                        ExpansionType::Result { rule_name },
+                        tree,
+                    )));
+                }
+                Err(CalyxError::RecursionLimitExceeded { .. }) if self.max_depth.is_some() => {}
+                Err(error) => {
+                    self.prefix = None;
+                    return Some(Err(error));
+                }
+            }
+        }
+    }
+}
+
+/// Advances the last choice that has another option, forgetting every choice after it, as
+/// those may not be made at all with a different option.
+pub(crate) fn next_prefix(taken: &[(usize, Odds)]) -> Option<Vec<usize>> {
+    let last = taken
+        .iter()
+        .rposition(|(position, odds)| position + 1 < odds.len())?;
+
+    let mut prefix: Vec<usize> = taken[..last]
+        .iter()
+        .map(|(position, _)| *position)
+        .collect();
+    prefix.push(taken[last].0 + 1);
+    Some(prefix)
+}
+
+#[cfg(test)]
+mod enumeration_tests {
+    use crate::generation::test_support::grammar;
+    use crate::generation::{CalyxError, Grammar};
+    use std::collections::HashMap;
+
+    fn outputs(grammar: &mut Grammar) -> Vec<String> {
+        grammar
+            .enumerate()
+            .map(|tree| tree.expect("Error during enumeration").flatten())
+            .collect()
+    }
+
+    #[test]
+    fn lists_every_combination_in_order() {
+        let mut grammar = grammar(&[
+            ("start", &["{size} {animal}", "nothing"]),
+            ("size", &["big", "small"]),
+            ("animal", &["cat", "dog"]),
+        ]);
+
+        assert_eq!(
+            outputs(&mut grammar),
+            vec!["big cat", "big dog", "small cat", "small dog", "nothing"]
+        );
+    }
+
+    #[test]
+    fn weighted_rules_list_every_template() {
+        let mut grammar = Grammar::new();
+        let weights = HashMap::from([(String::from("a"), 1.0), (String::from("b"), 9.0)]);
+        assert!(grammar.start_weighted(&weights).is_ok());
+
+        assert_eq!(outputs(&mut grammar), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn memoized_rules_are_consistent() {
+        let mut grammar = grammar(&[
+            ("start", &["{@name} and {@name}"]),
+            ("name", &["Ada", "Grace"]),
+        ]);
+
+        assert_eq!(
+            outputs(&mut grammar),
+            vec!["Ada and Ada", "Grace and Grace"]
+        );
+    }
+
+    #[test]
+    fn unique_rules_do_not_repeat() {
+        let mut grammar = grammar(&[
+            ("start", &["{$name}, {$name}, {$name}"]),
+            ("name", &["Ada", "Grace"]),
+        ]);
+
+        assert_eq!(
+            outputs(&mut grammar),
+            vec![
+                "Ada, Grace, Ada",
+                "Ada, Grace, Grace",
+                "Grace, Ada, Ada",
+                "Grace, Ada, Grace",
+            ]
+        );
+    }
+
+    #[test]
+    fn recursion_is_bounded_by_depth() {
+        let mut grammar = grammar(&[("start", &["({start})", "x"])]);
+
+        let outputs: Vec<String> = grammar
+            .enumerate()
+            .with_max_depth(3)
+            .map(|tree| tree.expect("Error during enumeration").flatten())
+            .collect();
+        assert_eq!(outputs, vec!["((x))", "(x)", "x"]);
+
+        let mut enumeration = grammar.enumerate();
+        assert!(matches!(
+            enumeration.next(),
+            Some(Err(CalyxError::RecursionLimitExceeded { .. }))
+        ));
+        assert!(enumeration.next().is_none());
+    }
+
+    #[test]
+    fn enumeration_is_lazy() {
+        let mut grammar = grammar(&[
+            ("start", &["{digit}{digit}{digit}{digit}{digit}{digit}"]),
+            ("digit", &["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"]),
+        ]);
+
+        let third = grammar
+            .enumerate()
+            .nth(2)
+            .expect("Expected a derivation")
+            .expect("Error during enumeration");
+        assert_eq!(third.flatten(), "000002");
+    }
+}
@@ -1,14 +1,19 @@
+use crate::generation::choice_log::Choice;
 use crate::generation::expansion_tree::ExpansionTree;
 use crate::generation::filter::Filter;
 use crate::generation::production::ProductionBranch;
+use crate::generation::production::affix::AffixTable;
 use crate::generation::production::branch::UniformBranch;
 use crate::generation::production::branch::{EmptyBranch, WeightedBranch};
+use crate::generation::production::depth::MinimumDepths;
+use crate::generation::production::parser::Expression;
 use crate::generation::{CalyxError, Grammar, Options, filter};
-use rand::seq::SliceRandom;
+use std::cell::OnceCell;
 use std::collections::HashMap;
 
 pub(crate) struct Registry {
     rules: HashMap<String, Box<dyn ProductionBranch>>,
+    mappings: HashMap<String, AffixTable>,
     filters: HashMap<String, Filter>,
     // this will always be an empty branch but is stored in the struct so that the lifetime matches
     empty_rule: EmptyBranch,
@@ -18,6 +23,7 @@ impl Registry {
     pub(crate) fn new() -> Self {
         Self {
             rules: HashMap::new(),
+            mappings: HashMap::new(),
             filters: filter::create_builtin_filters(),
             empty_rule: EmptyBranch {},
         }
@@ -28,11 +34,12 @@ impl Registry {
         symbol: String,
         production: &Vec<String>,
     ) -> Result<(), CalyxError> {
-        if self.rules.contains_key(&symbol) {
+        if self.is_defined(&symbol) {
             return Err(CalyxError::DuplicateRule { rule_name: symbol });
         }
 
-        let branch = UniformBranch::parse(production)?;
+        let branch = UniformBranch::parse(production.as_slice(), &self.filters)
+            .map_err(|error| error.in_rule(&symbol))?;
         self.rules.insert(symbol, Box::new(branch));
 
         Ok(())
@@ -43,16 +50,42 @@ impl Registry {
         symbol: String,
         production: &HashMap<String, f64>,
     ) -> Result<(), CalyxError> {
-        if self.rules.contains_key(&symbol) {
+        if self.is_defined(&symbol) {
             return Err(CalyxError::DuplicateRule { rule_name: symbol });
         }
 
-        let branch = WeightedBranch::parse(production)?;
+        let branch = WeightedBranch::parse(production, &self.filters)
+            .map_err(|error| error.in_rule(&symbol))?;
         self.rules.insert(symbol, Box::new(branch));
 
         Ok(())
     }
 
+    pub(crate) fn define_mapping(
+        &mut self,
+        symbol: String,
+        pairs: &[(String, String)],
+    ) -> Result<(), CalyxError> {
+        if self.is_defined(&symbol) {
+            return Err(CalyxError::DuplicateRule { rule_name: symbol });
+        }
+
+        // an empty mapping would be exported the same way as an empty uniform rule
+        if pairs.is_empty() {
+            return Err(CalyxError::EmptyMapping { rule_name: symbol });
+        }
+
+        let table = AffixTable::parse(pairs)?;
+        self.mappings.insert(symbol, table);
+
+        Ok(())
+    }
+
+    /// Rules and mappings share a namespace, so that they can be exported to the same document.
+    fn is_defined(&self, symbol: &String) -> bool {
+        self.rules.contains_key(symbol) || self.mappings.contains_key(symbol)
+    }
+
     pub(crate) fn expand(
         &self,
         symbol: &String,
@@ -74,50 +107,327 @@ impl Registry {
         }
     }
 
+    /// Parses the rules of a runtime context passed to generation.
+    pub(crate) fn parse_context(
+        &self,
+        context: &HashMap<String, Vec<String>>,
+    ) -> Result<HashMap<String, UniformBranch>, CalyxError> {
+        let mut rules = HashMap::with_capacity(context.len());
+
+        for (symbol, production) in context {
+            let branch = UniformBranch::parse(production, &self.filters).map_err(|cause| {
+                CalyxError::InvalidRule {
+                    rule_name: symbol.clone(),
+                    cause: Box::new(cause.in_rule(symbol)),
+                }
+            })?;
+
+            rules.insert(symbol.clone(), branch);
+        }
+
+        Ok(rules)
+    }
+
+    pub(crate) fn rules(&self) -> impl Iterator<Item = (&String, &dyn ProductionBranch)> {
+        self.rules.iter().map(|(term, rule)| (term, rule.as_ref()))
+    }
+
+    pub(crate) fn mappings(&self) -> impl Iterator<Item = (&String, &AffixTable)> {
+        self.mappings.iter()
+    }
+
+    /// Looks up a mapping by name. In lenient mode, an undefined mapping is returned as [None] and
+    /// leaves text unchanged.
+    pub(crate) fn get_mapping(
+        &self,
+        symbol: &String,
+        options: &Options,
+    ) -> Result<Option<&AffixTable>, CalyxError> {
+        match self.mappings.get(symbol) {
+            Some(table) => Ok(Some(table)),
+            None if options.strict => Err(CalyxError::UndefinedRule {
+                rule_name: symbol.clone(),
+            }),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn define_filter(
+        &mut self,
+        filter_name: String,
+        filter: Filter,
+    ) -> Result<(), CalyxError> {
+        if self.filters.contains_key(&filter_name) {
+            return Err(CalyxError::DuplicateFilter { filter_name });
+        }
+
+        self.filters.insert(filter_name, filter);
+
+        Ok(())
+    }
+
     pub(crate) fn get_filter(&self, filter_name: &String) -> Option<&Filter> {
         self.filters.get(filter_name)
     }
+
+    /// Checks every rule for empty branches, and for references to rules, mappings and filters
+    /// that are not defined. Each problem is wrapped in [CalyxError::InvalidRule], ordered by rule
+    /// name and then by position within the rule.
+    pub(crate) fn validate(&self) -> Vec<CalyxError> {
+        let mut rules: Vec<(&String, &dyn ProductionBranch)> = self.rules().collect();
+        rules.sort_by_key(|(term, _)| *term);
+
+        let depths = MinimumDepths::compute(self.rules());
+        let mut errors = Vec::new();
+
+        for (term, rule) in rules {
+            let invalid = |cause: CalyxError| CalyxError::InvalidRule {
+                rule_name: term.clone(),
+                cause: Box::new(cause),
+            };
+
+            if rule.len() == 0 {
+                errors.push(invalid(CalyxError::ExpandedEmptyBranch));
+            }
+
+            if !depths.terminates(term) {
+                errors.push(invalid(CalyxError::RecursionLimitExceeded {
+                    rule_path: self.recursion_path(term, &depths),
+                }));
+            }
+
+            for template in rule.templates() {
+                for expression in template.expressions() {
+                    errors.extend(self.check_expression(expression).into_iter().map(invalid));
+                }
+            }
+        }
+
+        errors
+    }
+
+    /// Follows the first non-terminating reference of each rule from a non-terminating rule, until
+    /// a rule repeats.
+    fn recursion_path(&self, symbol: &String, depths: &MinimumDepths) -> Vec<String> {
+        let mut path = vec![symbol.clone()];
+        let mut current = symbol;
+
+        loop {
+            let next = self.rules.get(current).and_then(|rule| {
+                rule.templates()
+                    .into_iter()
+                    .flat_map(|template| template.expressions())
+                    .map(|expression| &expression.symbol)
+                    .find(|next| !depths.terminates(next))
+            });
+
+            let Some(next) = next else {
+                return path;
+            };
+
+            let repeated = path.contains(next);
+            path.push(next.clone());
+
+            if repeated {
+                return path;
+            }
+
+            current = next;
+        }
+    }
+
+    fn check_expression(&self, expression: &Expression) -> Vec<CalyxError> {
+        let mut errors = Vec::new();
+
+        let undefined_symbols = std::iter::once(&expression.symbol)
+            .filter(|symbol| !self.rules.contains_key(*symbol))
+            .chain(
+                expression
+                    .mappings
+                    .iter()
+                    .filter(|mapping| !self.mappings.contains_key(*mapping)),
+            );
+
+        for symbol in undefined_symbols {
+            errors.push(CalyxError::UndefinedRule {
+                rule_name: symbol.clone(),
+            });
+        }
+
+        for call in &expression.filters {
+            match self.filters.get(&call.name) {
+                None => errors.push(CalyxError::UndefinedFilter {
+                    filter_name: call.name.clone(),
+                }),
+                Some(filter) if !filter.accepts(call.arguments.len()) => {
+                    let message = filter.arity_message(call.arguments.len());
+                    errors.push(filter::invalid_arguments(&call.name, &message));
+                }
+                Some(_) => {}
+            }
+        }
+
+        errors
+    }
+}
+
+/// How the choices of a generation are made.
+enum Decisions<'a> {
+    /// Each choice is made by the decision source of the grammar.
+    Choose,
+    /// Each choice is the next choice of a previous generation.
+    Replay(&'a [Choice]),
+    /// Each choice is the next position of a prefix, or the first option once the prefix runs
+    /// out, recording the position and odds of every choice made.
+    Enumerate {
+        prefix: &'a [usize],
+        taken: Vec<(usize, Odds)>,
+    },
+}
+
+/// How likely each option of a choice is to be chosen at random.
+#[derive(Clone, Debug)]
+pub(crate) enum Odds {
+    /// Every option is equally likely.
+    Uniform(usize),
+    /// Each option is chosen in proportion to its weight.
+    Weighted(Vec<f64>),
+}
+
+impl Odds {
+    /// The number of options.
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Odds::Uniform(count) => *count,
+            Odds::Weighted(weights) => weights.len(),
+        }
+    }
+
+    /// The probability of choosing an option, which is 0 for options that do not exist.
+    pub(crate) fn probability(&self, index: usize) -> f64 {
+        match self {
+            Odds::Uniform(count) if index < *count => 1.0 / *count as f64,
+            Odds::Weighted(weights) => weights
+                .get(index)
+                .map_or(0.0, |weight| weight / weights.iter().sum::<f64>()),
+            _ => 0.0,
+        }
+    }
 }
 
 pub(crate) struct EvaluationContext<'a> {
     registry: &'a Registry,
+    context: &'a HashMap<String, UniformBranch>,
     options: &'a mut Options,
     memoized_expansions: HashMap<String, ExpansionTree>,
     cycles: HashMap<String, UniqueCycle>,
+    rule_path: Vec<String>,
+    node_count: usize,
+    depths: OnceCell<MinimumDepths>,
+    choices: Vec<Choice>,
+    decisions: Decisions<'a>,
+    unused_templates: HashMap<String, Vec<usize>>,
+    max_depth: usize,
+    probability: f64,
 }
 
 impl<'a> EvaluationContext<'a> {
-    pub(crate) fn new(grammar: &'a mut Grammar) -> EvaluationContext<'a> {
+    pub(crate) fn new(
+        grammar: &'a mut Grammar,
+        context: &'a HashMap<String, UniformBranch>,
+    ) -> EvaluationContext<'a> {
+        let max_depth = grammar.options.max_depth;
+
         EvaluationContext {
             registry: &grammar.registry,
+            context,
             options: &mut grammar.options,
             memoized_expansions: HashMap::new(),
             cycles: HashMap::new(),
+            rule_path: Vec::new(),
+            node_count: 0,
+            depths: OnceCell::new(),
+            choices: Vec::new(),
+            decisions: Decisions::Choose,
+            unused_templates: HashMap::new(),
+            max_depth,
+            probability: 1.0,
         }
     }
 
-    pub(crate) fn unique_expansion(
-        &mut self,
-        symbol: &String,
-    ) -> Result<ExpansionTree, CalyxError> {
-        let rule = self.registry.expand(symbol, self.options)?;
+    /// Creates a context that makes the same choices as a previous generation, instead of
+    /// choosing at random.
+    pub(crate) fn replay(
+        grammar: &'a mut Grammar,
+        context: &'a HashMap<String, UniformBranch>,
+        choices: &'a [Choice],
+    ) -> EvaluationContext<'a> {
+        EvaluationContext {
+            decisions: Decisions::Replay(choices),
+            ..Self::new(grammar, context)
+        }
+    }
 
-        if !self.cycles.contains_key(symbol) {
-            let cycle = UniqueCycle::new(rule.len());
+    /// Creates a context that makes the choices at the positions of a prefix, then always makes
+    /// the first choice, so that every generation can be listed by advancing the prefix.
+    pub(crate) fn enumerate(
+        grammar: &'a mut Grammar,
+        context: &'a HashMap<String, UniformBranch>,
+        prefix: &'a [usize],
+        max_depth: Option<usize>,
+    ) -> EvaluationContext<'a> {
+        let eval_context = Self::new(grammar, context);
 
-            self.cycles.insert(symbol.clone(), cycle);
+        EvaluationContext {
+            decisions: Decisions::Enumerate {
+                prefix,
+                taken: Vec::new(),
+            },
+            max_depth: max_depth.unwrap_or(eval_context.max_depth),
+            ..eval_context
         }
+    }
 
-        let cycle = self
-            .cycles
-            .get_mut(symbol)
-            .ok_or_else(|| CalyxError::UndefinedRule {
-                rule_name: symbol.clone(),
-            })?;
+    /// Creates a context that continues a previous generation from inside it, given the rules
+    /// that were being expanded and the memoized expansions to keep.
+    pub(crate) fn resume(
+        grammar: &'a mut Grammar,
+        context: &'a HashMap<String, UniformBranch>,
+        rule_path: Vec<String>,
+        memoized_expansions: HashMap<String, ExpansionTree>,
+    ) -> EvaluationContext<'a> {
+        EvaluationContext {
+            rule_path,
+            memoized_expansions,
+            ..Self::new(grammar, context)
+        }
+    }
 
-        let index = cycle.poll(self.options);
+    /// Looks up a rule, preferring the rules of the runtime context over the registry.
+    pub(crate) fn expand(&self, symbol: &String) -> Result<&'a dyn ProductionBranch, CalyxError> {
+        match self.context.get(symbol) {
+            Some(rule) => Ok(rule),
+            None => self.registry.expand(symbol, self.options),
+        }
+    }
 
-        rule.evaluate_at(index, self)
+    pub(crate) fn unique_expansion(
+        &mut self,
+        symbol: &String,
+    ) -> Result<ExpansionTree, CalyxError> {
+        let rule = self.expand(symbol)?;
+
+        self.enter(symbol)?;
+        // winding down takes priority over cycling through every template
+        let result = if self.shallowest_templates(rule).is_some() {
+            rule.evaluate(self)
+        } else {
+            self.choose_unique(symbol, rule.len())
+                .and_then(|index| rule.evaluate_at(index, self))
+        };
+        self.rule_path.pop();
+
+        result
     }
 
     pub(crate) fn memoize_expansion(
@@ -145,8 +455,144 @@ impl<'a> EvaluationContext<'a> {
         &mut self,
         symbol: &String,
     ) -> Result<ExpansionTree, CalyxError> {
-        let rule = self.registry.expand(symbol, self.options)?;
-        rule.evaluate(self)
+        let rule = self.expand(symbol)?;
+
+        self.enter(symbol)?;
+        let result = rule.evaluate(self);
+        self.rule_path.pop();
+
+        result
+    }
+
+    /// Chooses one of the templates of the rule being expanded, and records the choice. When
+    /// replaying or enumerating generations, the choice is made by them instead of `pick`.
+    pub(crate) fn choose(
+        &mut self,
+        odds: Odds,
+        pick: impl FnOnce(&mut Self) -> usize,
+    ) -> Result<usize, CalyxError> {
+        let index = self.decide(&odds, pick)?;
+        self.probability *= odds.probability(index);
+        self.record(index);
+        Ok(index)
+    }
+
+    /// Chooses the template of a unique expansion, going through every template of the rule
+    /// before any is repeated.
+    fn choose_unique(&mut self, symbol: &String, count: usize) -> Result<usize, CalyxError> {
+        if count == 0 {
+            return Ok(0);
+        }
+
+        // the unused templates are tracked in every mode, as each is equally likely to be next
+        let unused = self.unused_templates.entry(symbol.clone()).or_default();
+        if unused.is_empty() {
+            unused.extend(0..count);
+        }
+        let unused_count = unused.len();
+
+        let index = if let Decisions::Enumerate { .. } = self.decisions {
+            // enumeration chooses among the unused templates, so that each order is only listed once
+            let position = self.decide(&Odds::Uniform(unused_count), |_| 0)?;
+            self.unused_templates[symbol][position]
+        } else {
+            self.decide(&Odds::Uniform(count), |eval_context| {
+                eval_context
+                    .cycles
+                    .entry(symbol.clone())
+                    .or_insert_with(|| UniqueCycle::new(count))
+                    .poll(eval_context.options)
+            })?
+        };
+
+        let unused = self.unused_templates.entry(symbol.clone()).or_default();
+        match unused
+            .iter()
+            .position(|unused_index| *unused_index == index)
+        {
+            Some(position) => {
+                unused.remove(position);
+                self.probability /= unused_count as f64;
+            }
+            // a replayed choice that was already used could not have been made at random
+            None => self.probability = 0.0,
+        }
+
+        self.record(index);
+        Ok(index)
+    }
+
+    fn decide(
+        &mut self,
+        odds: &Odds,
+        pick: impl FnOnce(&mut Self) -> usize,
+    ) -> Result<usize, CalyxError> {
+        let position = self.choices.len();
+
+        match &mut self.decisions {
+            Decisions::Choose => Ok(pick(self)),
+            Decisions::Replay(choices) => match choices.get(position) {
+                Some(choice)
+                    if Some(choice.rule_name()) == self.rule_path.last().map(String::as_str)
+                        && choice.index() < odds.len() =>
+                {
+                    Ok(choice.index())
+                }
+                _ => Err(CalyxError::ChoiceLogMismatch {
+                    position,
+                    rule_name: self.rule_path.last().cloned().unwrap_or_default(),
+                }),
+            },
+            Decisions::Enumerate { prefix, taken } => {
+                let chosen = prefix.get(taken.len()).copied().unwrap_or(0);
+                taken.push((chosen, odds.clone()));
+                Ok(chosen)
+            }
+        }
+    }
+
+    fn record(&mut self, index: usize) {
+        let rule_name = self.rule_path.last().cloned().unwrap_or_default();
+        self.choices.push(Choice::new(rule_name, index));
+    }
+
+    /// The probability of making every choice made so far at random, ignoring the
+    /// [depth budget](Options::depth_budget).
+    pub(crate) fn probability(&self) -> f64 {
+        self.probability
+    }
+
+    /// The choices made so far, in the order they were made.
+    pub(crate) fn choices(&self) -> &[Choice] {
+        &self.choices
+    }
+
+    /// When enumerating, the position and odds of every choice made so far.
+    pub(crate) fn enumerated(&self) -> &[(usize, Odds)] {
+        match &self.decisions {
+            Decisions::Enumerate { taken, .. } => taken,
+            _ => &[],
+        }
+    }
+
+    /// Records the expansion of a rule, failing if it exceeds the depth or node limits.
+    fn enter(&mut self, symbol: &str) -> Result<(), CalyxError> {
+        self.rule_path.push(symbol.to_string());
+        self.node_count += 1;
+
+        let too_deep = self.rule_path.len() > self.max_depth;
+        let too_many = self
+            .options
+            .max_nodes
+            .is_some_and(|max_nodes| self.node_count > max_nodes);
+
+        if too_deep || too_many {
+            return Err(CalyxError::RecursionLimitExceeded {
+                rule_path: self.rule_path.clone(),
+            });
+        }
+
+        Ok(())
     }
 }
 
@@ -155,9 +601,98 @@ impl<'a> EvaluationContext<'a> {
         self.registry
     }
 
+    /// Sets the expansion used for every memoized expression of a rule from now on.
+    pub(crate) fn memoize(&mut self, symbol: String, tree: ExpansionTree) {
+        self.memoized_expansions.insert(symbol, tree);
+    }
+
+    pub(crate) fn apply_mapping(
+        &self,
+        symbol: &String,
+        input: String,
+    ) -> Result<String, CalyxError> {
+        let mapping = self.registry.get_mapping(symbol, self.options)?;
+        Ok(mapping.map_or(input.clone(), |table| table.apply(&input)))
+    }
+
     pub(crate) fn options(&mut self) -> &mut Options {
         self.options
     }
+
+    /// Once rules are nested deeper than the [depth budget](Options::depth_budget), finds the
+    /// indices of the templates of a branch that need the fewest further expansions to finish.
+    /// For a [decision source](crate::generation::decision::DecisionSource::avoids_recursion_limit)
+    /// that avoids the recursion limit, finds the templates that can finish within the max depth
+    /// instead, or the shallowest if none can. Returns [None] if generation need not wind down, or
+    /// if none of the templates can finish.
+    pub(crate) fn shallowest_templates(&self, branch: &dyn ProductionBranch) -> Option<Vec<usize>> {
+        // replayed and enumerated choices are not made at random, so never need to wind down
+        if !matches!(self.decisions, Decisions::Choose) {
+            return None;
+        }
+
+        let exhausted = self.options.decision_source.is_exhausted();
+        let over_budget = self
+            .options
+            .depth_budget
+            .is_some_and(|budget| self.rule_path.len() > budget);
+        let bounded = self.options.decision_source.avoids_recursion_limit();
+
+        if !exhausted && !over_budget && !bounded {
+            return None;
+        }
+
+        // the depths are only needed to wind down generation, so are computed the first time
+        let depths = self.depths.get_or_init(|| {
+            let registry_rules = self
+                .registry
+                .rules()
+                .filter(|(term, _)| !self.context.contains_key(*term));
+            let context_rules = self
+                .context
+                .iter()
+                .map(|(term, rule)| (term, rule as &dyn ProductionBranch));
+
+            MinimumDepths::compute(registry_rules.chain(context_rules))
+        });
+
+        let template_depths: Vec<Option<usize>> = branch
+            .templates()
+            .into_iter()
+            .map(|template| depths.template(template))
+            .collect();
+
+        let shallowest = template_depths.iter().flatten().min()?;
+        let shallowest_templates = || {
+            template_depths
+                .iter()
+                .enumerate()
+                .filter(|(_, depth)| depth.as_ref() == Some(shallowest))
+                .map(|(index, _)| index)
+                .collect()
+        };
+
+        if exhausted || over_budget {
+            return Some(shallowest_templates());
+        }
+
+        // any template that needs more room than is left could not finish within the limit
+        let room = self.max_depth.saturating_sub(self.rule_path.len());
+        let fitting: Vec<usize> = template_depths
+            .iter()
+            .enumerate()
+            .filter(|(_, depth)| depth.is_some_and(|depth| depth <= room))
+            .map(|(index, _)| index)
+            .collect();
+
+        if fitting.len() == template_depths.len() {
+            None
+        } else if fitting.is_empty() {
+            Some(shallowest_templates())
+        } else {
+            Some(fitting)
+        }
+    }
 }
 
 struct UniqueCycle {
@@ -188,8 +723,7 @@ impl UniqueCycle {
 
     fn shuffle(&mut self, options: &mut Options) {
         self.populate_sequence();
-        let rng = &mut *options.random_source;
-        self.sequence.as_mut_slice().shuffle(rng);
+        options.decision_source.shuffle(&mut self.sequence);
     }
 
     fn poll(&mut self, options: &mut Options) -> usize {
@@ -1,16 +1,96 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+mod visit;
+
+pub use visit::{BreadthFirst, DepthFirst, Visitor};
+
 #[derive(Clone, Debug)]
 pub enum ExpansionType {
     Atom(String),
-    Result,
-    UniformBranch,
-    WeightedBranch,
+    /// The root of a generated tree, naming the rule that generation started from.
+    Result {
+        rule_name: String,
+    },
+    /// A template chosen from a uniform rule, by its index within the rule.
+    UniformBranch {
+        index: usize,
+    },
+    /// A template chosen from a weighted rule, by its index within the rule (in alphabetical
+    /// order) and its weight.
+    WeightedBranch {
+        index: usize,
+        weight: f64,
+    },
     EmptyBranch,
     AffixTable,
     Template,
-    Expression,
+    /// An expansion of the named rule.
+    Expression {
+        rule_name: String,
+    },
     ExpressionChain,
-    Memo,
-    Unique,
+    /// A memoized expansion of the named rule.
+    Memo {
+        rule_name: String,
+    },
+    /// A unique expansion of the named rule.
+    Unique {
+        rule_name: String,
+    },
+}
+
+/// A range of the text of an [ExpansionTree], along with the rules that produced it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextSpan {
+    range: Range<usize>,
+    rule_path: Vec<String>,
+    template_index: Option<usize>,
+}
+
+impl TextSpan {
+    /// The byte offsets of this span in the flattened text.
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    /// The names of the rules that were being expanded when this span was produced, from the
+    /// outermost to the innermost. The text of the rule that generation started from has an
+    /// empty path.
+    pub fn rule_path(&self) -> &[String] {
+        &self.rule_path
+    }
+
+    /// The name of the innermost rule that produced this span, if any.
+    pub fn rule_name(&self) -> Option<&str> {
+        self.rule_path.last().map(String::as_str)
+    }
+
+    /// The index of the template of the innermost rule that produced this span, if known.
+    pub fn template_index(&self) -> Option<usize> {
+        self.template_index
+    }
+}
+
+/// Selects a node of an [ExpansionTree], such as the part of a generation to
+/// [reroll](crate::generation::Grammar::reroll).
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeSelector {
+    /// The first expansion of the named rule, in depth first order.
+    Rule(String),
+    /// The node reached by following a path of child indices from the root, as returned by
+    /// [ExpansionTree::path_to].
+    Path(Vec<usize>),
+}
+
+impl NodeSelector {
+    /// The path to the selected node in a tree, if it exists.
+    pub fn resolve(&self, tree: &ExpansionTree) -> Option<Vec<usize>> {
+        match self {
+            NodeSelector::Rule(rule_name) => tree.path_to(tree.find_rule(rule_name)?),
+            NodeSelector::Path(path) => tree.get(path).map(|_| path.clone()),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -28,12 +108,154 @@ impl ExpansionTree {
         &self.symbol
     }
 
+    /// The name of the rule expanded by this node, if it is an [Expression](ExpansionType::Expression),
+    /// [Memo](ExpansionType::Memo) or [Unique](ExpansionType::Unique) node.
+    pub fn rule_name(&self) -> Option<&str> {
+        match &self.symbol {
+            ExpansionType::Expression { rule_name }
+            | ExpansionType::Memo { rule_name }
+            | ExpansionType::Unique { rule_name } => Some(rule_name),
+            _ => None,
+        }
+    }
+
+    /// The index of the template chosen by this node, if it is a branch node.
+    pub fn choice_index(&self) -> Option<usize> {
+        match self.symbol {
+            ExpansionType::UniformBranch { index }
+            | ExpansionType::WeightedBranch { index, .. } => Some(index),
+            _ => None,
+        }
+    }
+
+    /// The weight of the template chosen by this node, if it is a weighted branch node.
+    pub fn weight(&self) -> Option<f64> {
+        match self.symbol {
+            ExpansionType::WeightedBranch { weight, .. } => Some(weight),
+            _ => None,
+        }
+    }
+
     pub fn flatten(&self) -> String {
         let mut term = String::new();
         self.collect_atoms(&mut term);
         term
     }
 
+    /// Flattens this tree to text like [Self::flatten], along with a [TextSpan] for each run of
+    /// text produced by the same template of the same rule.
+    ///
+    /// The text of an expression with filters or mappings is attributed to the rule of the
+    /// expression as a whole.
+    pub fn flatten_with_spans(&self) -> (String, Vec<TextSpan>) {
+        let mut text = String::new();
+        let mut spans = Vec::new();
+        let mut rule_path = Vec::new();
+
+        self.collect_spans(&mut text, &mut spans, &mut rule_path, None);
+
+        (text, spans)
+    }
+
+    /// Replaces the text below this node, keeping the chain of nodes down to the first template
+    /// so that the new text is still attributed to the rule and template that it came from.
+    pub(crate) fn with_text(&self, text: String) -> Self {
+        if let ExpansionType::Atom(_) = self.symbol {
+            return ExpansionTree::new_atom(text);
+        }
+
+        let tail = match (&self.symbol, self.children.as_slice()) {
+            (ExpansionType::Template, _) => ExpansionTree::new_atom(text),
+            (_, [child]) => child.with_text(text),
+            _ => ExpansionTree::new_atom(text),
+        };
+
+        ExpansionTree::chain(self.symbol.clone(), tail)
+    }
+
+    /// Replaces the node at a path with another tree. The path must exist.
+    pub(crate) fn replace(&mut self, path: &[usize], subtree: ExpansionTree) {
+        match path.split_first() {
+            None => *self = subtree,
+            Some((index, rest)) => self.children[*index].replace(rest, subtree),
+        }
+    }
+
+    /// The memoized expansions of this tree by rule, other than those below a path.
+    pub(crate) fn memoized_expansions(
+        &self,
+        excluding: &[usize],
+    ) -> HashMap<String, ExpansionTree> {
+        let mut memos = HashMap::new();
+        self.collect_memos(&mut Vec::new(), excluding, &mut memos);
+        memos
+    }
+
+    fn collect_memos(
+        &self,
+        path: &mut Vec<usize>,
+        excluding: &[usize],
+        memos: &mut HashMap<String, ExpansionTree>,
+    ) {
+        if path.as_slice() == excluding {
+            return;
+        }
+
+        match (&self.symbol, self.children.as_slice()) {
+            // the text below filters and mappings is rewritten, so is not the memoized expansion
+            (ExpansionType::ExpressionChain | ExpansionType::AffixTable, _) => return,
+            (ExpansionType::Memo { rule_name }, [expansion]) => {
+                memos
+                    .entry(rule_name.clone())
+                    .or_insert_with(|| expansion.clone());
+            }
+            _ => {}
+        }
+
+        for (index, child) in self.children.iter().enumerate() {
+            path.push(index);
+            child.collect_memos(path, excluding, memos);
+            path.pop();
+        }
+    }
+
+    /// The paths to the pieces of templates that memoize a rule, whether or not their text has
+    /// been rewritten by filters or mappings.
+    pub(crate) fn memo_segments(&self, rule_name: &str) -> Vec<Vec<usize>> {
+        let mut paths = Vec::new();
+        self.collect_memo_segments(rule_name, &mut Vec::new(), &mut paths);
+        paths
+    }
+
+    fn collect_memo_segments(
+        &self,
+        rule_name: &str,
+        path: &mut Vec<usize>,
+        paths: &mut Vec<Vec<usize>>,
+    ) {
+        for (index, child) in self.children.iter().enumerate() {
+            path.push(index);
+
+            if matches!(self.symbol, ExpansionType::Template) && child.memoizes(rule_name) {
+                paths.push(path.clone());
+            } else {
+                child.collect_memo_segments(rule_name, path, paths);
+            }
+
+            path.pop();
+        }
+    }
+
+    fn memoizes(&self, rule_name: &str) -> bool {
+        match (&self.symbol, self.children.as_slice()) {
+            (ExpansionType::Memo { rule_name: memo }, _) => memo == rule_name,
+            (ExpansionType::ExpressionChain | ExpansionType::AffixTable, [child]) => {
+                child.memoizes(rule_name)
+            }
+            _ => false,
+        }
+    }
+
     pub(crate) fn new(symbol: ExpansionType, tail: Vec<ExpansionTree>) -> Self {
         ExpansionTree {
             children: tail,
@@ -55,6 +277,58 @@ impl ExpansionTree {
         }
     }
 
+    fn collect_spans(
+        &self,
+        text: &mut String,
+        spans: &mut Vec<TextSpan>,
+        rule_path: &mut Vec<String>,
+        template_index: Option<usize>,
+    ) {
+        match &self.symbol {
+            ExpansionType::Atom(term) => {
+                let start = text.len();
+                text.push_str(term);
+
+                if term.is_empty() {
+                    return;
+                }
+
+                if let Some(last) = spans.last_mut()
+                    && last.range.end == start
+                    && last.rule_path == *rule_path
+                    && last.template_index == template_index
+                {
+                    last.range.end = text.len();
+                } else {
+                    spans.push(TextSpan {
+                        range: start..text.len(),
+                        rule_path: rule_path.clone(),
+                        template_index,
+                    });
+                }
+            }
+            _ => {
+                let rule_name = self.rule_name();
+                let template_index = match rule_name {
+                    Some(_) => None,
+                    None => self.choice_index().or(template_index),
+                };
+
+                if let Some(rule_name) = rule_name {
+                    rule_path.push(rule_name.to_string());
+                }
+
+                for child in &self.children {
+                    child.collect_spans(text, spans, rule_path, template_index);
+                }
+
+                if rule_name.is_some() {
+                    rule_path.pop();
+                }
+            }
+        }
+    }
+
     fn collect_atoms(&self, concat: &mut String) {
         if let ExpansionType::Atom(term) = &self.symbol {
             concat.push_str(term.as_str());
@@ -68,6 +342,7 @@ impl ExpansionTree {
 
 #[cfg(test)]
 mod expansion_tree_tests {
+    use crate::generation::Grammar;
     use crate::generation::expansion_tree::{ExpansionTree, ExpansionType};
 
     #[test]
@@ -83,4 +358,57 @@ mod expansion_tree_tests {
         let text = exp.flatten();
         assert_eq!(text, "-ONE--TWO--THREE-");
     }
+
+    #[test]
+    fn spans_attribute_text_to_rules() {
+        let mut grammar = Grammar::new();
+
+        assert!(
+            grammar
+                .start_single(String::from("Hello {name}, {name.uppercase}{missing}!"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .uniform_rule(String::from("name"), &vec![String::from("{first} {last}")])
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("first"), String::from("Ada"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("last"), String::from("L"))
+                .is_ok()
+        );
+
+        let tree = grammar.generate().expect("Error during generation");
+        let (text, spans) = tree.flatten_with_spans();
+        assert_eq!(text, tree.flatten());
+        assert_eq!(text, "Hello Ada L, ADA L!");
+
+        let described: Vec<(&str, Vec<&str>, Option<usize>)> = spans
+            .iter()
+            .map(|span| {
+                let path = span.rule_path().iter().map(String::as_str).collect();
+                (&text[span.range()], path, span.template_index())
+            })
+            .collect();
+
+        assert_eq!(
+            described,
+            vec![
+                ("Hello ", vec![], Some(0)),
+                ("Ada", vec!["name", "first"], Some(0)),
+                (" ", vec!["name"], Some(0)),
+                ("L", vec!["name", "last"], Some(0)),
+                (", ", vec![], Some(0)),
+                ("ADA L", vec!["name"], Some(0)),
+                ("!", vec![], Some(0)),
+            ]
+        );
+        assert_eq!(spans[1].rule_name(), Some("first"));
+    }
 }
@@ -0,0 +1,258 @@
+use crate::generation::expansion_tree::ExpansionTree;
+use std::collections::VecDeque;
+
+/// Walks an [ExpansionTree] with [ExpansionTree::accept].
+///
+/// # Examples
+///
+/// ```
+/// use calyx_rs::generation::Grammar;
+/// use calyx_rs::generation::expansion_tree::{ExpansionTree, Visitor};
+///
+/// struct RuleCounter {
+///     count: usize,
+/// }
+///
+/// impl Visitor for RuleCounter {
+///     fn enter(&mut self, node: &ExpansionTree) -> bool {
+///         if node.rule_name().is_some() {
+///             self.count += 1;
+///         }
+///         true
+///     }
+/// }
+///
+/// let mut grammar: Grammar = Grammar::new();
+/// assert!(grammar.start_single(String::from("{a} {a}")).is_ok());
+/// assert!(grammar.single_rule(String::from("a"), String::from("A")).is_ok());
+///
+/// let mut counter = RuleCounter { count: 0 };
+/// grammar.generate().expect("Error during generation").accept(&mut counter);
+/// assert_eq!(counter.count, 2);
+/// ```
+pub trait Visitor {
+    /// Called when a node is reached, before its children. Returning `false` skips the children.
+    fn enter(&mut self, _node: &ExpansionTree) -> bool {
+        true
+    }
+
+    /// Called after a node and its children have been visited.
+    fn exit(&mut self, _node: &ExpansionTree) {}
+}
+
+/// Iterates over the nodes of a tree depth first, visiting each node before its children.
+pub struct DepthFirst<'a> {
+    stack: Vec<&'a ExpansionTree>,
+}
+
+impl<'a> Iterator for DepthFirst<'a> {
+    type Item = &'a ExpansionTree;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        self.stack.extend(node.children().iter().rev());
+        Some(node)
+    }
+}
+
+/// Iterates over the nodes of a tree breadth first, visiting each level before the next.
+pub struct BreadthFirst<'a> {
+    queue: VecDeque<&'a ExpansionTree>,
+}
+
+impl<'a> Iterator for BreadthFirst<'a> {
+    type Item = &'a ExpansionTree;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.queue.pop_front()?;
+        self.queue.extend(node.children());
+        Some(node)
+    }
+}
+
+impl ExpansionTree {
+    /// Walks this tree depth first, calling the visitor for each node.
+    pub fn accept<V: Visitor + ?Sized>(&self, visitor: &mut V) {
+        if visitor.enter(self) {
+            for child in self.children() {
+                child.accept(visitor);
+            }
+        }
+
+        visitor.exit(self);
+    }
+
+    /// Iterates over this node and all of its descendants depth first.
+    pub fn iter_depth_first(&self) -> DepthFirst<'_> {
+        DepthFirst { stack: vec![self] }
+    }
+
+    /// Iterates over this node and all of its descendants breadth first.
+    pub fn iter_breadth_first(&self) -> BreadthFirst<'_> {
+        BreadthFirst {
+            queue: VecDeque::from([self]),
+        }
+    }
+
+    /// Finds the first expansion of a rule, in depth first order.
+    pub fn find_rule(&self, rule_name: &str) -> Option<&ExpansionTree> {
+        self.iter_depth_first()
+            .find(|node| node.rule_name() == Some(rule_name))
+    }
+
+    /// Finds every expansion of a rule, in depth first order.
+    pub fn find_rules<'a>(
+        &'a self,
+        rule_name: &'a str,
+    ) -> impl Iterator<Item = &'a ExpansionTree> + 'a {
+        self.iter_depth_first()
+            .filter(move |node| node.rule_name() == Some(rule_name))
+    }
+
+    /// The text of the first expansion of a rule, in depth first order.
+    pub fn text_of(&self, rule_name: &str) -> Option<String> {
+        self.find_rule(rule_name).map(ExpansionTree::flatten)
+    }
+
+    /// The indices of the children to follow from this node to reach a node of this tree, or
+    /// [None] if the node is not part of this tree. Nodes are compared by identity, so the node
+    /// must be borrowed from this tree rather than a copy of it.
+    pub fn path_to(&self, node: &ExpansionTree) -> Option<Vec<usize>> {
+        if std::ptr::eq(self, node) {
+            return Some(Vec::new());
+        }
+
+        self.children()
+            .iter()
+            .enumerate()
+            .find_map(|(index, child)| {
+                let mut path = child.path_to(node)?;
+                path.insert(0, index);
+                Some(path)
+            })
+    }
+
+    /// The node reached by following a path of child indices from this node.
+    pub fn get(&self, path: &[usize]) -> Option<&ExpansionTree> {
+        path.iter()
+            .try_fold(self, |node, index| node.children().get(*index))
+    }
+}
+
+#[cfg(test)]
+mod visit_tests {
+    use crate::generation::Grammar;
+    use crate::generation::expansion_tree::{ExpansionTree, ExpansionType, Visitor};
+
+    fn generate() -> ExpansionTree {
+        let mut grammar = Grammar::new();
+
+        assert!(
+            grammar
+                .start_single(String::from("{hero} met {villain}"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("hero"), String::from("{name} the brave"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("villain"), String::from("{name}"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .uniform_rule(String::from("name"), &vec![String::from("Ada")])
+                .is_ok()
+        );
+
+        grammar.generate().expect("Error during generation")
+    }
+
+    fn rule_names<'a>(nodes: impl Iterator<Item = &'a ExpansionTree>) -> Vec<&'a str> {
+        nodes.filter_map(ExpansionTree::rule_name).collect()
+    }
+
+    #[test]
+    fn depth_first_visits_children_in_order() {
+        let tree = generate();
+        assert_eq!(
+            rule_names(tree.iter_depth_first()),
+            vec!["hero", "name", "villain", "name"]
+        );
+    }
+
+    #[test]
+    fn breadth_first_visits_each_level_in_turn() {
+        let tree = generate();
+        assert_eq!(
+            rule_names(tree.iter_breadth_first()),
+            vec!["hero", "villain", "name", "name"]
+        );
+        assert_eq!(
+            tree.iter_breadth_first().count(),
+            tree.iter_depth_first().count()
+        );
+    }
+
+    #[test]
+    fn visitor_can_skip_children() {
+        struct Atoms {
+            atoms: Vec<String>,
+            depth: usize,
+        }
+
+        impl Visitor for Atoms {
+            fn enter(&mut self, node: &ExpansionTree) -> bool {
+                self.depth += 1;
+
+                if let ExpansionType::Atom(atom) = node.symbol() {
+                    self.atoms.push(atom.clone());
+                }
+
+                node.rule_name() != Some("hero")
+            }
+
+            fn exit(&mut self, _node: &ExpansionTree) {
+                self.depth -= 1;
+            }
+        }
+
+        let tree = generate();
+        let mut visitor = Atoms {
+            atoms: Vec::new(),
+            depth: 0,
+        };
+        tree.accept(&mut visitor);
+
+        assert_eq!(visitor.atoms, vec![" met ", "Ada"]);
+        assert_eq!(visitor.depth, 0);
+    }
+
+    #[test]
+    fn find_rules_and_text() {
+        let tree = generate();
+
+        let hero = tree.find_rule("hero").expect("Expected a hero");
+        assert_eq!(hero.flatten(), "Ada the brave");
+        assert_eq!(tree.find_rules("name").count(), 2);
+        assert_eq!(tree.text_of("villain"), Some(String::from("Ada")));
+        assert_eq!(tree.text_of("sidekick"), None);
+    }
+
+    #[test]
+    fn path_to_node_and_back() {
+        let tree = generate();
+        let villain = tree.find_rule("villain").expect("Expected a villain");
+
+        let path = tree.path_to(villain).expect("Expected a path");
+        assert!(std::ptr::eq(tree.get(&path).unwrap(), villain));
+        assert_eq!(tree.path_to(&tree), Some(vec![]));
+
+        let copy = villain.clone();
+        assert_eq!(tree.path_to(&copy), None);
+        assert!(tree.get(&[0, 5]).is_none());
+    }
+}
@@ -1,13 +1,498 @@
+mod inflection;
+
+use crate::generation::CalyxError;
 use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use unicode_segmentation::UnicodeSegmentation;
+
+type SimpleFilterFunction = fn(&str) -> String;
+type FilterFunction = dyn Fn(&str, &[FilterArgument]) -> Result<String, CalyxError> + Send + Sync;
+
+/// A function applied to the text of an expression with dot-notation, such as `{name.uppercase}`
+/// or `{title.truncate(20)}`.
+///
+/// Filters are reference counted so that a single filter, along with any state it captures, can be
+/// shared between grammars.
+#[derive(Clone)]
+pub struct Filter {
+    function: Arc<FilterFunction>,
+    arity: RangeInclusive<usize>,
+}
+
+/// A literal argument passed to a filter in an expression chain.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterArgument {
+    String(String),
+    Integer(i64),
+}
+
+impl Filter {
+    /// Creates a filter that does not accept any arguments.
+    pub fn new<F>(function: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        Filter {
+            function: Arc::new(move |input, _| Ok(function(input))),
+            arity: 0..=0,
+        }
+    }
+
+    /// Creates a filter that accepts a number of arguments within the given range.
+    ///
+    /// The number of arguments is checked before the function is called, but the function is
+    /// responsible for checking the type of each argument.
+    pub fn with_arguments<F>(arity: RangeInclusive<usize>, function: F) -> Self
+    where
+        F: Fn(&str, &[FilterArgument]) -> Result<String, CalyxError> + Send + Sync + 'static,
+    {
+        Filter {
+            function: Arc::new(function),
+            arity,
+        }
+    }
+
+    /// Whether this filter can be called with the given number of arguments.
+    pub fn accepts(&self, argument_count: usize) -> bool {
+        self.arity.contains(&argument_count)
+    }
+
+    /// Applies this filter to some input text.
+    ///
+    /// # Errors
+    /// - [CalyxError::InvalidFilterArguments] if the arguments are not accepted by this filter.
+    ///
+    pub fn apply(
+        &self,
+        filter_name: &str,
+        input: &str,
+        arguments: &[FilterArgument],
+    ) -> Result<String, CalyxError> {
+        if !self.accepts(arguments.len()) {
+            return Err(invalid_arguments(
+                filter_name,
+                &self.arity_message(arguments.len()),
+            ));
+        }
 
-pub type Filter = fn(String) -> String;
+        (self.function)(input, arguments)
+    }
+
+    pub(crate) fn arity_message(&self, argument_count: usize) -> String {
+        let (min, max) = (self.arity.start(), self.arity.end());
+
+        if min == max {
+            format!("expected {} arguments, found {}", min, argument_count)
+        } else {
+            format!(
+                "expected {} to {} arguments, found {}",
+                min, max, argument_count
+            )
+        }
+    }
+}
+
+impl FilterArgument {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            FilterArgument::String(value) => Some(value.as_str()),
+            FilterArgument::Integer(_) => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            FilterArgument::String(_) => None,
+            FilterArgument::Integer(value) => Some(*value),
+        }
+    }
+}
+
+/// Creates a [CalyxError::InvalidFilterArguments] for the named filter.
+pub fn invalid_arguments(filter_name: &str, message: &str) -> CalyxError {
+    CalyxError::InvalidFilterArguments {
+        filter_name: filter_name.to_string(),
+        message: message.to_string(),
+    }
+}
 
 pub(crate) fn create_builtin_filters() -> HashMap<String, Filter> {
     let mut filters: HashMap<String, Filter> = HashMap::new();
 
-    filters.insert("lowercase".to_string(), |s| s.to_lowercase());
-    filters.insert("uppercase".to_string(), |s| s.to_uppercase());
-    filters.insert("length".to_string(), |s| format!("{}", s.len()));
+    let simple_filters: [(&str, SimpleFilterFunction); 28] = [
+        ("lowercase", str::to_lowercase),
+        ("uppercase", str::to_uppercase),
+        ("capitalize", capitalize),
+        ("upper_first", upper_first),
+        ("lower_first", lower_first),
+        ("titlecase", titlecase),
+        ("sentence_case", sentence_case),
+        ("swapcase", swapcase),
+        ("snake_case", |s| words(s).join("_")),
+        ("kebab_case", |s| words(s).join("-")),
+        ("reverse", |s| s.graphemes(true).rev().collect()),
+        ("trim", |s| s.trim().to_string()),
+        ("trim_start", |s| s.trim_start().to_string()),
+        ("trim_end", |s| s.trim_end().to_string()),
+        ("squish", |s| {
+            s.split_whitespace().collect::<Vec<&str>>().join(" ")
+        }),
+        ("emphasis", |s| format!("*{}*", s)),
+        ("strong", |s| format!("**{}**", s)),
+        ("quote", |s| format!("\"{}\"", s)),
+        ("length", |s| s.len().to_string()),
+        ("char_count", |s| s.chars().count().to_string()),
+        ("byte_count", |s| s.len().to_string()),
+        ("word_count", |s| s.unicode_words().count().to_string()),
+        ("initials", initials),
+        ("pluralize", inflection::pluralize),
+        ("singularize", inflection::singularize),
+        ("article", inflection::article),
+        ("with_article", inflection::with_article),
+        ("ordinal", inflection::ordinal),
+    ];
+
+    for (name, function) in simple_filters {
+        filters.insert(name.to_string(), Filter::new(function));
+    }
+
+    filters.insert(
+        "truncate".to_string(),
+        Filter::with_arguments(1..=1, |s, args| {
+            let length = length_argument("truncate", args, 0)?;
+            Ok(s.chars().take(length).collect())
+        }),
+    );
+    filters.insert(
+        "pad_left".to_string(),
+        Filter::with_arguments(1..=2, |s, args| {
+            let padding = padding("pad_left", s, args)?;
+            Ok(format!("{}{}", padding, s))
+        }),
+    );
+    filters.insert(
+        "pad_right".to_string(),
+        Filter::with_arguments(1..=2, |s, args| {
+            let padding = padding("pad_right", s, args)?;
+            Ok(format!("{}{}", s, padding))
+        }),
+    );
+    filters.insert(
+        "join".to_string(),
+        Filter::with_arguments(1..=1, |s, args| {
+            let separator = string_argument("join", args, 0)?;
+            Ok(s.split_whitespace().collect::<Vec<&str>>().join(separator))
+        }),
+    );
 
     filters
 }
+
+/// Uppercases the first character, and lowercases the rest, like Ruby's `String#capitalize`.
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+
+    match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.flat_map(char::to_lowercase))
+            .collect(),
+        None => String::new(),
+    }
+}
+
+fn upper_first(s: &str) -> String {
+    let mut chars = s.chars();
+
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn lower_first(s: &str) -> String {
+    let mut chars = s.chars();
+
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Capitalizes each word, preserving the original whitespace and punctuation.
+fn titlecase(s: &str) -> String {
+    s.split_word_bounds()
+        .map(|segment| {
+            if segment.chars().any(char::is_alphanumeric) {
+                capitalize(segment)
+            } else {
+                segment.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Lowercases the text, then uppercases the first letter of each sentence.
+fn sentence_case(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut sentence_start = true;
+
+    for ch in s.chars() {
+        if sentence_start && ch.is_alphabetic() {
+            result.extend(ch.to_uppercase());
+            sentence_start = false;
+        } else {
+            result.extend(ch.to_lowercase());
+        }
+
+        if matches!(ch, '.' | '!' | '?') {
+            sentence_start = true;
+        }
+    }
+
+    result
+}
+
+fn swapcase(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+
+    for ch in s.chars() {
+        if ch.is_uppercase() {
+            result.extend(ch.to_lowercase());
+        } else if ch.is_lowercase() {
+            result.extend(ch.to_uppercase());
+        } else {
+            result.push(ch);
+        }
+    }
+
+    result
+}
+
+/// Splits text into lowercase words, for joining into identifiers such as `snake_case`.
+fn words(s: &str) -> Vec<String> {
+    s.split(|ch: char| ch.is_whitespace() || ch == '-' || ch == '_')
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn initials(s: &str) -> String {
+    s.unicode_words()
+        .filter_map(|word| word.chars().next())
+        .flat_map(char::to_uppercase)
+        .collect()
+}
+
+fn string_argument<'a>(
+    filter_name: &str,
+    args: &'a [FilterArgument],
+    index: usize,
+) -> Result<&'a str, CalyxError> {
+    args.get(index)
+        .and_then(FilterArgument::as_str)
+        .ok_or_else(|| {
+            invalid_arguments(
+                filter_name,
+                &format!("expected argument {} to be a string", index + 1),
+            )
+        })
+}
+
+fn length_argument(
+    filter_name: &str,
+    args: &[FilterArgument],
+    index: usize,
+) -> Result<usize, CalyxError> {
+    args.get(index)
+        .and_then(FilterArgument::as_integer)
+        .and_then(|value| usize::try_from(value).ok())
+        .ok_or_else(|| {
+            invalid_arguments(
+                filter_name,
+                &format!(
+                    "expected argument {} to be a non-negative integer",
+                    index + 1
+                ),
+            )
+        })
+}
+
+fn padding(filter_name: &str, s: &str, args: &[FilterArgument]) -> Result<String, CalyxError> {
+    let width = length_argument(filter_name, args, 0)?;
+
+    let fill = match args.get(1) {
+        Some(_) => {
+            let fill = string_argument(filter_name, args, 1)?;
+            let mut chars = fill.chars();
+
+            match (chars.next(), chars.next()) {
+                (Some(ch), None) => ch,
+                _ => {
+                    return Err(invalid_arguments(
+                        filter_name,
+                        "expected argument 2 to be a single character",
+                    ));
+                }
+            }
+        }
+        None => ' ',
+    };
+
+    let count = width.saturating_sub(s.chars().count());
+    Ok(std::iter::repeat_n(fill, count).collect())
+}
+
+#[cfg(test)]
+mod filter_tests {
+    use crate::generation::CalyxError;
+    use crate::generation::filter::{FilterArgument, create_builtin_filters};
+
+    fn apply(name: &str, input: &str, args: &[FilterArgument]) -> Result<String, CalyxError> {
+        let filters = create_builtin_filters();
+        filters[name].apply(name, input, args)
+    }
+
+    fn apply_simple(name: &str, input: &str) -> String {
+        apply(name, input, &[]).expect("Error applying filter")
+    }
+
+    #[test]
+    fn truncate_counts_characters() {
+        let result = apply("truncate", "héllo wörld", &[FilterArgument::Integer(4)]);
+        assert_eq!(result.expect("Error applying filter"), "héll");
+    }
+
+    #[test]
+    fn pad_left_defaults_to_spaces() {
+        let result = apply("pad_left", "ab", &[FilterArgument::Integer(4)]);
+        assert_eq!(result.expect("Error applying filter"), "  ab");
+    }
+
+    #[test]
+    fn pad_right_with_fill_character() {
+        let args = [
+            FilterArgument::Integer(4),
+            FilterArgument::String(String::from("-")),
+        ];
+        let result = apply("pad_right", "ab", &args);
+        assert_eq!(result.expect("Error applying filter"), "ab--");
+    }
+
+    #[test]
+    fn join_words_with_separator() {
+        let args = [FilterArgument::String(String::from(", "))];
+        let result = apply("join", "red green  blue", &args);
+        assert_eq!(result.expect("Error applying filter"), "red, green, blue");
+    }
+
+    #[test]
+    fn lowercase_and_uppercase() {
+        assert_eq!(apply_simple("lowercase", "ÀBC Straße"), "àbc straße");
+        assert_eq!(apply_simple("uppercase", "àbc straße"), "ÀBC STRASSE");
+    }
+
+    #[test]
+    fn capitalize_lowercases_remaining_characters() {
+        assert_eq!(apply_simple("capitalize", "éCOLE nORMALE"), "École normale");
+        assert_eq!(apply_simple("capitalize", ""), "");
+    }
+
+    #[test]
+    fn upper_and_lower_first_preserve_remaining_characters() {
+        assert_eq!(apply_simple("upper_first", "ébène NOIRE"), "Ébène NOIRE");
+        assert_eq!(apply_simple("lower_first", "ÉBÈNE"), "éBÈNE");
+    }
+
+    #[test]
+    fn titlecase_capitalizes_each_word() {
+        assert_eq!(
+            apply_simple("titlecase", "the  LORD of the rings"),
+            "The  Lord Of The Rings"
+        );
+        assert_eq!(apply_simple("titlecase", "ödön's éclair"), "Ödön's Éclair");
+    }
+
+    #[test]
+    fn sentence_case_capitalizes_each_sentence() {
+        assert_eq!(
+            apply_simple("sentence_case", "HELLO THERE. how are you? ÉTONNANT!"),
+            "Hello there. How are you? Étonnant!"
+        );
+    }
+
+    #[test]
+    fn swapcase_inverts_case() {
+        assert_eq!(apply_simple("swapcase", "Hello Ωmega"), "hELLO ωMEGA");
+    }
+
+    #[test]
+    fn snake_and_kebab_case() {
+        assert_eq!(apply_simple("snake_case", "Big  Red-Dog"), "big_red_dog");
+        assert_eq!(apply_simple("kebab_case", "Big  Red_Dog"), "big-red-dog");
+    }
+
+    #[test]
+    fn reverse_preserves_grapheme_clusters() {
+        assert_eq!(apply_simple("reverse", "abc"), "cba");
+        assert_eq!(apply_simple("reverse", "cafe\u{301} ok"), "ko e\u{301}fac");
+    }
+
+    #[test]
+    fn trim_filters() {
+        assert_eq!(apply_simple("trim", "\u{3000} hi \n"), "hi");
+        assert_eq!(apply_simple("trim_start", "  hi  "), "hi  ");
+        assert_eq!(apply_simple("trim_end", "  hi  "), "  hi");
+    }
+
+    #[test]
+    fn squish_collapses_whitespace() {
+        assert_eq!(apply_simple("squish", "  a \t b\n\nc  "), "a b c");
+    }
+
+    #[test]
+    fn emphasis_strong_and_quote() {
+        assert_eq!(apply_simple("emphasis", "hi"), "*hi*");
+        assert_eq!(apply_simple("strong", "hi"), "**hi**");
+        assert_eq!(apply_simple("quote", "hi"), "\"hi\"");
+    }
+
+    #[test]
+    fn counting_characters_and_bytes() {
+        assert_eq!(apply_simple("length", "héllo"), "6");
+        assert_eq!(apply_simple("char_count", "héllo"), "5");
+        assert_eq!(apply_simple("byte_count", "héllo"), "6");
+    }
+
+    #[test]
+    fn word_count_ignores_punctuation() {
+        assert_eq!(apply_simple("word_count", "Hello, wide world!"), "3");
+        assert_eq!(apply_simple("word_count", ""), "0");
+    }
+
+    #[test]
+    fn initials_of_each_word() {
+        assert_eq!(apply_simple("initials", "ada lovelace"), "AL");
+        assert_eq!(apply_simple("initials", "Émile  zola"), "ÉZ");
+    }
+
+    #[test]
+    fn wrong_argument_type_fails() {
+        let args = [FilterArgument::String(String::from("20"))];
+        let result = apply("truncate", "abc", &args);
+        assert!(matches!(
+            result,
+            Err(CalyxError::InvalidFilterArguments { ref filter_name, .. }) if filter_name == "truncate"
+        ));
+    }
+
+    #[test]
+    fn wrong_argument_count_fails() {
+        let result = apply("uppercase", "abc", &[FilterArgument::Integer(1)]);
+        assert!(matches!(
+            result,
+            Err(CalyxError::InvalidFilterArguments { ref filter_name, .. }) if filter_name == "uppercase"
+        ));
+    }
+}
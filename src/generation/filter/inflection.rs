@@ -0,0 +1,454 @@
+//! English inflection rules used by the `pluralize`, `singularize`, `article`, `with_article`, and
+//! `ordinal` filters.
+
+/// Nouns with the same singular and plural form.
+const UNCOUNTABLE: &[&str] = &[
+    "aircraft",
+    "bison",
+    "cod",
+    "deer",
+    "equipment",
+    "fish",
+    "information",
+    "moose",
+    "money",
+    "news",
+    "offspring",
+    "rice",
+    "salmon",
+    "series",
+    "sheep",
+    "species",
+    "swine",
+    "trout",
+];
+
+/// Pairs of singular and plural nouns that do not follow the regular rules.
+const IRREGULAR: &[(&str, &str)] = &[
+    ("alumnus", "alumni"),
+    ("analysis", "analyses"),
+    ("axis", "axes"),
+    ("bus", "buses"),
+    ("cactus", "cacti"),
+    ("calf", "calves"),
+    ("child", "children"),
+    ("cookie", "cookies"),
+    ("crisis", "crises"),
+    ("criterion", "criteria"),
+    ("die", "dice"),
+    ("echo", "echoes"),
+    ("elf", "elves"),
+    ("foot", "feet"),
+    ("fungus", "fungi"),
+    ("goose", "geese"),
+    ("half", "halves"),
+    ("hero", "heroes"),
+    ("index", "indices"),
+    ("knife", "knives"),
+    ("leaf", "leaves"),
+    ("life", "lives"),
+    ("loaf", "loaves"),
+    ("louse", "lice"),
+    ("man", "men"),
+    ("matrix", "matrices"),
+    ("mouse", "mice"),
+    ("movie", "movies"),
+    ("nucleus", "nuclei"),
+    ("ox", "oxen"),
+    ("person", "people"),
+    ("phenomenon", "phenomena"),
+    ("potato", "potatoes"),
+    ("quiz", "quizzes"),
+    ("radius", "radii"),
+    ("rookie", "rookies"),
+    ("self", "selves"),
+    ("shelf", "shelves"),
+    ("staff", "staves"),
+    ("thesis", "theses"),
+    ("thief", "thieves"),
+    ("tomato", "tomatoes"),
+    ("tooth", "teeth"),
+    ("torpedo", "torpedoes"),
+    ("vertex", "vertices"),
+    ("veto", "vetoes"),
+    ("wife", "wives"),
+    ("wolf", "wolves"),
+    ("woman", "women"),
+    ("zombie", "zombies"),
+];
+
+/// Words that begin with a vowel letter but a consonant sound.
+const CONSONANT_SOUND_PREFIXES: &[&str] = &[
+    "eu", "ewe", "ufo", "ura", "ure", "uri", "use", "usu", "uti", "uto",
+];
+
+/// Words that begin with a silent `h`, and so a vowel sound.
+const SILENT_H_PREFIXES: &[&str] = &["heir", "honest", "honor", "honour", "hour"];
+
+/// Letters whose names begin with a vowel sound, for reading acronyms such as `FBI`.
+const VOWEL_SOUND_LETTERS: &str = "AEFHILMNORSX";
+
+/// The longest uppercase word read letter by letter. Longer words are read as ordinary words.
+const MAX_ACRONYM_LENGTH: usize = 4;
+
+/// Converts the last word of a phrase to its plural form.
+pub(crate) fn pluralize(phrase: &str) -> String {
+    inflect_last_word(phrase, plural_of)
+}
+
+/// Converts the last word of a phrase to its singular form.
+pub(crate) fn singularize(phrase: &str) -> String {
+    inflect_last_word(phrase, singular_of)
+}
+
+/// The indefinite article, `a` or `an`, that should precede a phrase.
+pub(crate) fn article(phrase: &str) -> String {
+    if starts_with_vowel_sound(phrase.trim_start()) {
+        String::from("an")
+    } else {
+        String::from("a")
+    }
+}
+
+/// Prefixes a phrase with its indefinite article, such as `an ancient sword`.
+pub(crate) fn with_article(phrase: &str) -> String {
+    format!("{} {}", article(phrase), phrase)
+}
+
+/// Appends the English ordinal suffix to an integer, such as `1st` or `22nd`.
+///
+/// Text that is not an integer is returned unchanged.
+pub(crate) fn ordinal(number: &str) -> String {
+    let digits = number.strip_prefix('-').unwrap_or(number);
+
+    if digits.is_empty() || !digits.chars().all(|ch| ch.is_ascii_digit()) {
+        return number.to_string();
+    }
+
+    let tens = digits.len().checked_sub(2).and_then(|i| digits.get(i..));
+    let units = &digits[digits.len() - 1..];
+
+    let suffix = match (tens, units) {
+        (Some("11" | "12" | "13"), _) => "th",
+        (_, "1") => "st",
+        (_, "2") => "nd",
+        (_, "3") => "rd",
+        _ => "th",
+    };
+
+    format!("{}{}", number, suffix)
+}
+
+fn inflect_last_word(phrase: &str, inflect: fn(&str) -> String) -> String {
+    let last = phrase.rsplit(char::is_whitespace).next().unwrap_or(phrase);
+
+    // punctuation around the word, such as in `(sword).`, is kept as it is
+    let trailing = last.trim_end_matches(|ch: char| !ch.is_alphanumeric());
+    let word = trailing.trim_start_matches(|ch: char| !ch.is_alphanumeric());
+
+    if word.is_empty() {
+        return phrase.to_string();
+    }
+
+    let prefix = &phrase[..phrase.len() - last.len() + trailing.len() - word.len()];
+    let suffix = &last[trailing.len()..];
+
+    let inflected = inflect(&word.to_lowercase());
+    format!("{}{}{}", prefix, match_case(word, &inflected), suffix)
+}
+
+fn plural_of(word: &str) -> String {
+    if UNCOUNTABLE.contains(&word) {
+        return word.to_string();
+    }
+
+    if let Some((_, plural)) = IRREGULAR.iter().find(|(singular, _)| *singular == word) {
+        return plural.to_string();
+    }
+
+    if let Some(stem) = word.strip_suffix('y')
+        && stem.chars().last().is_some_and(|ch| !is_vowel(ch))
+    {
+        return format!("{}ies", stem);
+    }
+
+    if ["s", "x", "z", "ch", "sh"]
+        .iter()
+        .any(|suffix| word.ends_with(suffix))
+    {
+        return format!("{}es", word);
+    }
+
+    format!("{}s", word)
+}
+
+fn singular_of(word: &str) -> String {
+    if UNCOUNTABLE.contains(&word) {
+        return word.to_string();
+    }
+
+    if let Some((singular, _)) = IRREGULAR.iter().find(|(_, plural)| *plural == word) {
+        return singular.to_string();
+    }
+
+    if let Some(stem) = word.strip_suffix("ies")
+        && stem.chars().count() > 1
+    {
+        return format!("{}y", stem);
+    }
+
+    if ["sses", "xes", "zes", "ches", "shes"]
+        .iter()
+        .any(|suffix| word.ends_with(suffix))
+    {
+        return word[..word.len() - 2].to_string();
+    }
+
+    match word.strip_suffix('s') {
+        Some(stem) if !stem.ends_with('s') && !stem.ends_with('u') && !stem.ends_with('i') => {
+            stem.to_string()
+        }
+        _ => word.to_string(),
+    }
+}
+
+fn starts_with_vowel_sound(phrase: &str) -> bool {
+    let word: String = phrase
+        .chars()
+        .take_while(|ch| ch.is_alphanumeric())
+        .collect();
+
+    // an uppercase phrase, such as the output of the `uppercase` filter, is made of ordinary
+    // words, so acronyms are only told apart by the lowercase text around them
+    let has_lowercase = phrase.chars().any(char::is_lowercase);
+
+    let Some(first) = word.chars().next() else {
+        return false;
+    };
+
+    if first.is_ascii_digit() {
+        return number_starts_with_vowel_sound(&word);
+    }
+
+    let length = word.chars().count();
+    if has_lowercase
+        && (2..=MAX_ACRONYM_LENGTH).contains(&length)
+        && word.chars().all(char::is_uppercase)
+    {
+        return VOWEL_SOUND_LETTERS.contains(first);
+    }
+
+    let lower = word.to_lowercase();
+
+    if SILENT_H_PREFIXES
+        .iter()
+        .any(|prefix| lower.starts_with(prefix))
+    {
+        return true;
+    }
+
+    if lower == "one"
+        || lower == "once"
+        || CONSONANT_SOUND_PREFIXES
+            .iter()
+            .any(|prefix| lower.starts_with(prefix))
+    {
+        return false;
+    }
+
+    // "uni" is read as "you-nee", except in negated words such as "unimportant" or "unidentified"
+    if let Some(rest) = lower.strip_prefix("uni")
+        && !rest.starts_with(['n', 'm', 'd'])
+    {
+        return false;
+    }
+
+    lower.chars().next().is_some_and(is_vowel)
+}
+
+/// Numbers are read aloud, so `8`, `11` and `18` (and `11,000` or `18,000,000`) take `an`.
+fn number_starts_with_vowel_sound(digits: &str) -> bool {
+    let digits: String = digits.chars().take_while(char::is_ascii_digit).collect();
+
+    digits.starts_with('8')
+        || (digits.len() % 3 == 2 && (digits.starts_with("11") || digits.starts_with("18")))
+}
+
+fn is_vowel(ch: char) -> bool {
+    "aeiouàáâäèéêëìíîïòóôöùúûü".contains(ch)
+}
+
+/// Applies the capitalization of an original word to its inflected form.
+fn match_case(original: &str, inflected: &str) -> String {
+    let mut letters = original.chars().filter(|ch| ch.is_alphabetic());
+
+    match letters.next() {
+        Some(first) if first.is_uppercase() => {
+            if original.chars().count() > 1 && letters.all(char::is_uppercase) {
+                inflected.to_uppercase()
+            } else {
+                let mut chars = inflected.chars();
+                chars
+                    .next()
+                    .map(|ch| ch.to_uppercase().chain(chars).collect())
+                    .unwrap_or_default()
+            }
+        }
+        _ => inflected.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod inflection_tests {
+    use crate::generation::Grammar;
+    use crate::generation::filter::inflection::{
+        article, ordinal, pluralize, singularize, with_article,
+    };
+
+    #[test]
+    fn inflection_filters_in_templates() {
+        let mut grammar = Grammar::new();
+
+        assert!(
+            grammar
+                .start_single(String::from(
+                    "{item.with_article}, {item.pluralize} and the {count.ordinal} {@quest}"
+                ))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("item"), String::from("ancient knife"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("count"), String::from("3"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("quest"), String::from("quest"))
+                .is_ok()
+        );
+
+        let text = grammar
+            .generate()
+            .expect("Error during generation")
+            .flatten();
+        assert_eq!(text, "an ancient knife, ancient knives and the 3rd quest");
+    }
+
+    #[test]
+    fn inflection_after_other_filters() {
+        let mut grammar = Grammar::new();
+
+        assert!(
+            grammar
+                .start_single(String::from("{item.uppercase.with_article}"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("item"), String::from("sword"))
+                .is_ok()
+        );
+
+        let text = grammar
+            .generate()
+            .expect("Error during generation")
+            .flatten();
+        assert_eq!(text, "a SWORD");
+    }
+
+    #[test]
+    fn pluralize_regular_nouns() {
+        assert_eq!(pluralize("sword"), "swords");
+        assert_eq!(pluralize("box"), "boxes");
+        assert_eq!(pluralize("church"), "churches");
+        assert_eq!(pluralize("city"), "cities");
+        assert_eq!(pluralize("day"), "days");
+    }
+
+    #[test]
+    fn pluralize_irregular_and_uncountable_nouns() {
+        assert_eq!(pluralize("child"), "children");
+        assert_eq!(pluralize("wolf"), "wolves");
+        assert_eq!(pluralize("cactus"), "cacti");
+        assert_eq!(pluralize("sheep"), "sheep");
+    }
+
+    #[test]
+    fn pluralize_last_word_and_preserve_case() {
+        assert_eq!(pluralize("ancient Sword"), "ancient Swords");
+        assert_eq!(pluralize("Mouse"), "Mice");
+        assert_eq!(pluralize("ELF"), "ELVES");
+        assert_eq!(pluralize(""), "");
+    }
+
+    #[test]
+    fn pluralize_around_punctuation() {
+        assert_eq!(pluralize("sword."), "swords.");
+        assert_eq!(pluralize("(old mouse)!"), "(old mice)!");
+        assert_eq!(singularize("boxes,"), "box,");
+        assert_eq!(pluralize("..."), "...");
+    }
+
+    #[test]
+    fn singularize_nouns() {
+        assert_eq!(singularize("swords"), "sword");
+        assert_eq!(singularize("boxes"), "box");
+        assert_eq!(singularize("cities"), "city");
+        assert_eq!(singularize("glasses"), "glass");
+        assert_eq!(singularize("people"), "person");
+        assert_eq!(singularize("movies"), "movie");
+        assert_eq!(singularize("fish"), "fish");
+        assert_eq!(singularize("glass"), "glass");
+        assert_eq!(singularize("old Knives"), "old Knife");
+    }
+
+    #[test]
+    fn articles_follow_vowel_sounds() {
+        assert_eq!(with_article("ancient sword"), "an ancient sword");
+        assert_eq!(with_article("sword"), "a sword");
+        assert_eq!(article("hour"), "an");
+        assert_eq!(article("honest man"), "an");
+        assert_eq!(article("horse"), "a");
+        assert_eq!(article("unicorn"), "a");
+        assert_eq!(article("unimportant detail"), "an");
+        assert_eq!(article("umbrella"), "an");
+        assert_eq!(article("European"), "a");
+        assert_eq!(article("one-eyed giant"), "a");
+        assert_eq!(article("Élan"), "an");
+    }
+
+    #[test]
+    fn articles_for_acronyms_and_numbers() {
+        assert_eq!(article("FBI agent"), "an");
+        assert_eq!(article("MRI scan"), "an");
+        assert_eq!(article("UFO"), "a");
+        assert_eq!(article("SWORD"), "a");
+        assert_eq!(article("HAT"), "a");
+        assert_eq!(article("Ancient SWORDS"), "an");
+        assert_eq!(article("8 foot wall"), "an");
+        assert_eq!(article("11 year old"), "an");
+        assert_eq!(article("110 year old"), "a");
+        assert_eq!(article("18000 ton ship"), "an");
+        assert_eq!(article("7"), "a");
+    }
+
+    #[test]
+    fn ordinal_suffixes() {
+        assert_eq!(ordinal("1"), "1st");
+        assert_eq!(ordinal("2"), "2nd");
+        assert_eq!(ordinal("3"), "3rd");
+        assert_eq!(ordinal("4"), "4th");
+        assert_eq!(ordinal("11"), "11th");
+        assert_eq!(ordinal("12"), "12th");
+        assert_eq!(ordinal("113"), "113th");
+        assert_eq!(ordinal("22"), "22nd");
+        assert_eq!(ordinal("101"), "101st");
+        assert_eq!(ordinal("-1"), "-1st");
+        assert_eq!(ordinal("first"), "first");
+    }
+}
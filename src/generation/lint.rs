@@ -0,0 +1,453 @@
+use crate::generation::document::RuleDefinition;
+use crate::generation::evaluation::Registry;
+use crate::generation::production::ProductionBranch;
+use crate::generation::production::parser::{Segment, Sigil};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// A suspicious but valid part of a grammar, as reported by [Grammar::lint](super::Grammar::lint).
+#[derive(Clone, Debug, PartialEq)]
+pub enum LintWarning {
+    /// A rule or mapping that cannot be reached from any of the roots.
+    UnreachableRule { rule_name: String },
+    /// A rule whose every expansion is the empty string.
+    EmptyOutput { rule_name: String },
+    /// A template that appears more than once in the same uniform rule, which makes it more likely
+    /// to be chosen than the other templates.
+    DuplicateAlternative {
+        rule_name: String,
+        template: String,
+        count: usize,
+    },
+    /// A memoized `@` reference to a rule with only one template, which always expands the same way
+    /// without memoization.
+    RedundantMemo {
+        rule_name: String,
+        reference: String,
+    },
+    /// A unique `$` reference to a rule with only one template, which has nothing to cycle through.
+    RedundantUnique {
+        rule_name: String,
+        reference: String,
+    },
+    /// A reference to a rule whose name belongs to a mapping. Rules and mappings share a namespace,
+    /// so no rule of that name can be defined, and the reference is undefined.
+    ShadowedRule {
+        rule_name: String,
+        reference: String,
+    },
+}
+
+impl LintWarning {
+    /// The name of the rule that the warning was found in.
+    pub fn rule_name(&self) -> &str {
+        match self {
+            LintWarning::UnreachableRule { rule_name }
+            | LintWarning::EmptyOutput { rule_name }
+            | LintWarning::DuplicateAlternative { rule_name, .. }
+            | LintWarning::RedundantMemo { rule_name, .. }
+            | LintWarning::RedundantUnique { rule_name, .. }
+            | LintWarning::ShadowedRule { rule_name, .. } => rule_name,
+        }
+    }
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LintWarning::UnreachableRule { rule_name } => {
+                write!(f, "rule `{}` is never used", rule_name)
+            }
+            LintWarning::EmptyOutput { rule_name } => {
+                write!(f, "rule `{}` only produces the empty string", rule_name)
+            }
+            LintWarning::DuplicateAlternative {
+                rule_name,
+                template,
+                count,
+            } => write!(
+                f,
+                "rule `{}` contains the template {:?} {} times",
+                rule_name, template, count
+            ),
+            LintWarning::RedundantMemo {
+                rule_name,
+                reference,
+            } => write!(
+                f,
+                "rule `{}` memoizes `{}`, which only has one template",
+                rule_name, reference
+            ),
+            LintWarning::RedundantUnique {
+                rule_name,
+                reference,
+            } => write!(
+                f,
+                "rule `{}` uniquely expands `{}`, which only has one template",
+                rule_name, reference
+            ),
+            LintWarning::ShadowedRule {
+                rule_name,
+                reference,
+            } => write!(
+                f,
+                "rule `{}` expands `{}`, which is the name of a mapping",
+                rule_name, reference
+            ),
+        }
+    }
+}
+
+/// Lints every rule of a registry, ordered by rule name.
+pub(crate) fn lint(registry: &Registry, roots: &[String]) -> Vec<LintWarning> {
+    let rules: BTreeMap<&String, &dyn ProductionBranch> = registry.rules().collect();
+
+    let reachable = reachable_symbols(&rules, roots);
+    let non_empty = non_empty_rules(&rules);
+
+    let mut symbols: Vec<&String> = rules
+        .keys()
+        .copied()
+        .chain(registry.mappings().map(|(term, _)| term))
+        .collect();
+    symbols.sort();
+
+    let mut warnings = Vec::new();
+
+    for term in symbols {
+        if !reachable.contains(term.as_str()) {
+            warnings.push(LintWarning::UnreachableRule {
+                rule_name: term.clone(),
+            });
+        }
+
+        let Some(rule) = rules.get(term) else {
+            continue;
+        };
+
+        if rule.len() > 0 && !non_empty.contains(term.as_str()) {
+            warnings.push(LintWarning::EmptyOutput {
+                rule_name: term.clone(),
+            });
+        }
+
+        warnings.extend(duplicate_alternatives(term, *rule));
+        warnings.extend(redundant_references(term, *rule, &rules));
+        warnings.extend(shadowed_references(term, *rule, registry));
+    }
+
+    warnings
+}
+
+/// Finds every rule and mapping referenced, directly or indirectly, by the roots.
+fn reachable_symbols<'a>(
+    rules: &BTreeMap<&'a String, &'a dyn ProductionBranch>,
+    roots: &'a [String],
+) -> HashSet<&'a str> {
+    let mut reachable: HashSet<&str> = HashSet::new();
+    let mut pending: Vec<&str> = roots.iter().map(String::as_str).collect();
+
+    while let Some(symbol) = pending.pop() {
+        if !reachable.insert(symbol) {
+            continue;
+        }
+
+        let Some(rule) = rules.get(&symbol.to_string()) else {
+            continue;
+        };
+
+        for template in rule.templates() {
+            for expression in template.expressions() {
+                pending.push(&expression.symbol);
+                reachable.extend(expression.mappings.iter().map(String::as_str));
+            }
+        }
+    }
+
+    reachable
+}
+
+/// Finds every rule that can produce some text. Filters and mappings may turn the empty string
+/// into text, so any expression that uses them is assumed to.
+fn non_empty_rules<'a>(rules: &BTreeMap<&'a String, &'a dyn ProductionBranch>) -> HashSet<&'a str> {
+    let mut non_empty: HashSet<&str> = HashSet::new();
+
+    loop {
+        let mut changed = false;
+
+        for (term, rule) in rules {
+            if non_empty.contains(term.as_str()) {
+                continue;
+            }
+
+            let produces_text = rule.templates().iter().any(|template| {
+                template.segments().iter().any(|segment| match segment {
+                    Segment::Text(_) => true,
+                    Segment::Expression(expression) => {
+                        !expression.filters.is_empty()
+                            || !expression.mappings.is_empty()
+                            || !rules.contains_key(&expression.symbol)
+                            || non_empty.contains(expression.symbol.as_str())
+                    }
+                })
+            });
+
+            if produces_text {
+                non_empty.insert(term);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            return non_empty;
+        }
+    }
+}
+
+fn duplicate_alternatives(term: &str, rule: &dyn ProductionBranch) -> Vec<LintWarning> {
+    let RuleDefinition::Uniform(templates) = rule.to_definition() else {
+        return Vec::new();
+    };
+
+    let mut counts: Vec<(String, usize)> = Vec::new();
+
+    for template in templates {
+        match counts.iter_mut().find(|(seen, _)| *seen == template) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((template, 1)),
+        }
+    }
+
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(template, count)| LintWarning::DuplicateAlternative {
+            rule_name: term.to_string(),
+            template,
+            count,
+        })
+        .collect()
+}
+
+fn redundant_references(
+    term: &str,
+    rule: &dyn ProductionBranch,
+    rules: &BTreeMap<&String, &dyn ProductionBranch>,
+) -> Vec<LintWarning> {
+    let mut warnings = Vec::new();
+
+    for template in rule.templates() {
+        for expression in template.expressions() {
+            let single_choice = rules
+                .get(&expression.symbol)
+                .is_some_and(|referenced| referenced.len() == 1);
+
+            if !single_choice {
+                continue;
+            }
+
+            let rule_name = term.to_string();
+            let reference = expression.symbol.clone();
+
+            match expression.sigil {
+                Sigil::Memo => warnings.push(LintWarning::RedundantMemo {
+                    rule_name,
+                    reference,
+                }),
+                Sigil::Unique => warnings.push(LintWarning::RedundantUnique {
+                    rule_name,
+                    reference,
+                }),
+                Sigil::None => {}
+            }
+        }
+    }
+
+    warnings
+}
+
+fn shadowed_references(
+    term: &str,
+    rule: &dyn ProductionBranch,
+    registry: &Registry,
+) -> Vec<LintWarning> {
+    let mut warnings: Vec<LintWarning> = Vec::new();
+
+    for template in rule.templates() {
+        for expression in template.expressions() {
+            let is_mapping = registry
+                .mappings()
+                .any(|(mapping, _)| *mapping == expression.symbol);
+            let warning = LintWarning::ShadowedRule {
+                rule_name: term.to_string(),
+                reference: expression.symbol.clone(),
+            };
+
+            if is_mapping && !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod lint_tests {
+    use crate::generation::lint::LintWarning;
+    use crate::generation::test_support::grammar;
+
+    #[test]
+    fn clean_grammar_has_no_warnings() {
+        let grammar = grammar(&[
+            ("start", &["{@hero} meets {$villain}"]),
+            ("hero", &["Ada", "Grace"]),
+            ("villain", &["Moriarty", "Blofeld"]),
+        ]);
+
+        assert_eq!(grammar.lint(), vec![]);
+    }
+
+    #[test]
+    fn unreachable_rules_and_mappings() {
+        let mut grammar = grammar(&[
+            ("start", &["{a>plural}"]),
+            ("a", &["b"]),
+            ("orphan", &["{also_orphan}"]),
+            ("also_orphan", &["x"]),
+        ]);
+        for name in ["plural", "unused"] {
+            assert!(
+                grammar
+                    .mapping_rule(name.to_string(), &[(String::from("$"), String::from("s"))])
+                    .is_ok()
+            );
+        }
+
+        let unreachable: Vec<String> = grammar
+            .lint()
+            .into_iter()
+            .filter(|warning| matches!(warning, LintWarning::UnreachableRule { .. }))
+            .map(|warning| warning.rule_name().to_string())
+            .collect();
+
+        assert_eq!(unreachable, vec!["also_orphan", "orphan", "unused"]);
+    }
+
+    #[test]
+    fn roots_are_configurable() {
+        let grammar = grammar(&[("title", &["{name}"]), ("name", &["Ada"])]);
+
+        assert_eq!(grammar.lint_from(&[String::from("title")]), vec![]);
+        assert_eq!(
+            grammar.lint(),
+            vec![
+                LintWarning::UnreachableRule {
+                    rule_name: String::from("name")
+                },
+                LintWarning::UnreachableRule {
+                    rule_name: String::from("title")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rules_that_only_produce_empty_string() {
+        let grammar = grammar(&[
+            ("start", &["{blank}{loop}{shouted}{text}"]),
+            ("blank", &["{nothing}", "{loop}"]),
+            ("nothing", &["{loop}"]),
+            ("loop", &["{loop}"]),
+            ("shouted", &["{nothing.uppercase}"]),
+            ("text", &["{nothing}", "x"]),
+        ]);
+
+        let empty: Vec<String> = grammar
+            .lint()
+            .into_iter()
+            .filter(|warning| matches!(warning, LintWarning::EmptyOutput { .. }))
+            .map(|warning| warning.rule_name().to_string())
+            .collect();
+
+        assert_eq!(empty, vec!["blank", "loop", "nothing"]);
+    }
+
+    #[test]
+    fn duplicate_alternatives() {
+        let grammar = grammar(&[("start", &["a", "b", "a", "c", "a", "b"])]);
+
+        assert_eq!(
+            grammar.lint(),
+            vec![
+                LintWarning::DuplicateAlternative {
+                    rule_name: String::from("start"),
+                    template: String::from("a"),
+                    count: 3,
+                },
+                LintWarning::DuplicateAlternative {
+                    rule_name: String::from("start"),
+                    template: String::from("b"),
+                    count: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn sigils_on_single_choice_rules() {
+        let grammar = grammar(&[("start", &["{@name} {$name} {name}"]), ("name", &["Ada"])]);
+
+        assert_eq!(
+            grammar.lint(),
+            vec![
+                LintWarning::RedundantMemo {
+                    rule_name: String::from("start"),
+                    reference: String::from("name"),
+                },
+                LintWarning::RedundantUnique {
+                    rule_name: String::from("start"),
+                    reference: String::from("name"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rule_references_to_mappings() {
+        let mut grammar = grammar(&[
+            ("start", &["{plural} {noun>plural}", "{plural}"]),
+            ("noun", &["cat"]),
+        ]);
+        assert!(
+            grammar
+                .mapping_rule(
+                    String::from("plural"),
+                    &[(String::from("$"), String::from("s"))]
+                )
+                .is_ok()
+        );
+
+        assert_eq!(
+            grammar.lint(),
+            vec![LintWarning::ShadowedRule {
+                rule_name: String::from("start"),
+                reference: String::from("plural"),
+            }]
+        );
+    }
+
+    #[test]
+    fn display() {
+        let warning = LintWarning::DuplicateAlternative {
+            rule_name: String::from("start"),
+            template: String::from("a"),
+            count: 2,
+        };
+
+        assert_eq!(
+            warning.to_string(),
+            "rule `start` contains the template \"a\" 2 times"
+        );
+    }
+}
@@ -0,0 +1,624 @@
+use crate::generation::chart::{Chart, Span};
+use crate::generation::choice_log::Choice;
+use crate::generation::evaluation::{EvaluationContext, Registry};
+use crate::generation::expansion_tree::{ExpansionTree, ExpansionType};
+use crate::generation::production::parser::{Segment, Sigil};
+use crate::generation::{CalyxError, Grammar};
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Once the derivations that have not been listed are less likely than this altogether, they
+/// are ignored.
+const TOLERANCE: f64 = 1e-12;
+
+/// The most derivations listed to find the probabilities of outputs that cannot be computed
+/// from the structure of the grammar alone.
+const SEARCH_LIMIT: usize = 1 << 16;
+
+/// The most times the probabilities of recursive rules are refined before they are returned.
+const MAX_ROUNDS: usize = 1000;
+
+/// Finds the rule that a tree is an expansion of, and the choices made to generate it, in the
+/// order they were made.
+///
+/// Choices inside memoized expansions are taken from the first copy that was not rewritten by
+/// filters or mappings, if any, as the text below those no longer records them.
+pub(crate) fn derivation_choices(tree: &ExpansionTree) -> Option<(String, Vec<Choice>)> {
+    let start_symbol = match tree.symbol() {
+        ExpansionType::Result { rule_name } => rule_name.as_str(),
+        _ => tree.rule_name()?,
+    };
+
+    let memos = tree.memoized_expansions(&[]);
+    let mut seen_memos = HashSet::new();
+    let mut choices = Vec::new();
+    collect_choices(tree, start_symbol, &memos, &mut seen_memos, &mut choices);
+
+    Some((start_symbol.to_string(), choices))
+}
+
+fn collect_choices<'t>(
+    tree: &'t ExpansionTree,
+    rule_name: &'t str,
+    memos: &'t HashMap<String, ExpansionTree>,
+    seen_memos: &mut HashSet<&'t str>,
+    choices: &mut Vec<Choice>,
+) {
+    let rule_name = match tree.symbol() {
+        ExpansionType::Memo { rule_name } => {
+            // later copies of a memoized expansion repeat the first, so make no choices
+            if seen_memos.insert(rule_name) {
+                let expansion = memos.get(rule_name).or(tree.children().first());
+                if let Some(expansion) = expansion {
+                    collect_choices(expansion, rule_name, memos, seen_memos, choices);
+                }
+            }
+            return;
+        }
+        ExpansionType::Result { rule_name }
+        | ExpansionType::Expression { rule_name }
+        | ExpansionType::Unique { rule_name } => rule_name.as_str(),
+        _ => rule_name,
+    };
+
+    if let Some(index) = tree.choice_index() {
+        choices.push(Choice::new(rule_name.to_string(), index));
+    }
+
+    for child in tree.children() {
+        collect_choices(child, rule_name, memos, seen_memos, choices);
+    }
+}
+
+/// The probability of a rule expanding to a text, summed over every derivation that produces it.
+pub(crate) fn output_probability(
+    grammar: &mut Grammar,
+    rule_name: &str,
+    text: &str,
+) -> Result<f64, CalyxError> {
+    let strict = grammar.options.strict;
+    if let Some(probability) = Inside::new(&grammar.registry, strict, text).rule_text(rule_name) {
+        return Ok(probability);
+    }
+
+    let mut search = Search::new(rule_name);
+    let mut probability = 0.0;
+
+    for _ in 0..SEARCH_LIMIT {
+        if search.remaining() <= TOLERANCE {
+            break;
+        }
+
+        let Some(derivation) = search.next_derivation(grammar) else {
+            break;
+        };
+
+        let (tree, derivation_probability) = derivation?;
+        if tree.flatten() == text {
+            probability += derivation_probability;
+        }
+    }
+
+    Ok(probability)
+}
+
+/// The most likely outputs of a rule, most likely first, along with their probabilities.
+pub(crate) fn top_outputs(
+    grammar: &mut Grammar,
+    rule_name: &str,
+    count: usize,
+) -> Result<Vec<(String, f64)>, CalyxError> {
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let strict = grammar.options.strict;
+    let context_free = Inside::new(&grammar.registry, strict, "").is_context_free(rule_name);
+
+    let mut search = Search::new(rule_name);
+    let mut outputs: HashMap<String, f64> = HashMap::new();
+
+    for listed in 1..=SEARCH_LIMIT {
+        let Some(derivation) = search.next_derivation(grammar) else {
+            break;
+        };
+        let (tree, probability) = derivation?;
+        let text = tree.flatten();
+
+        match outputs.entry(text) {
+            Entry::Occupied(mut entry) if !context_free => *entry.get_mut() += probability,
+            Entry::Occupied(_) => {}
+            Entry::Vacant(entry) => {
+                // the exact probability of each output is known as soon as it is found
+                let exact = if context_free {
+                    Inside::new(&grammar.registry, strict, entry.key()).rule_text(rule_name)
+                } else {
+                    None
+                };
+                entry.insert(exact.unwrap_or(probability));
+            }
+        }
+
+        if listed % 64 != 0 && search.remaining() > TOLERANCE {
+            continue;
+        }
+
+        // stop once the outputs found can no longer be overtaken by those left. Memoized, unique,
+        // filtered and mapped expressions can spread one output over many unlikely derivations,
+        // so only plain rules also stop once no derivation left is as likely as the outputs found.
+        let ranked = ranked(&outputs);
+        let remaining = search.remaining();
+        let settled = ranked.get(count - 1).is_some_and(|(_, last)| {
+            let next = ranked.get(count).map_or(0.0, |(_, next)| *next);
+
+            if context_free {
+                *last >= next.max(remaining) || *last >= search.most_likely_left()
+            } else {
+                *last >= next + remaining
+            }
+        });
+
+        if settled || remaining <= TOLERANCE {
+            break;
+        }
+    }
+
+    let mut top = ranked(&outputs);
+    top.truncate(count);
+
+    if context_free {
+        return Ok(top);
+    }
+
+    // the search may have stopped before finding every derivation of the outputs kept
+    let mut kept: HashMap<String, f64> = HashMap::with_capacity(top.len());
+    for (text, _) in top {
+        let probability = output_probability(grammar, rule_name, &text)?;
+        kept.insert(text, probability);
+    }
+
+    Ok(ranked(&kept))
+}
+
+/// Sorts outputs by their probability, most likely first, then by their text.
+fn ranked(outputs: &HashMap<String, f64>) -> Vec<(String, f64)> {
+    let mut ranked: Vec<(String, f64)> = outputs
+        .iter()
+        .map(|(text, probability)| (text.clone(), *probability))
+        .collect();
+    ranked.sort_by(|(text, probability), (other_text, other)| {
+        other
+            .total_cmp(probability)
+            .then_with(|| text.cmp(other_text))
+    });
+    ranked
+}
+
+/// A prefix of choices, along with the probability of making them at random.
+struct Prefix {
+    probability: f64,
+    positions: Vec<usize>,
+}
+
+impl PartialEq for Prefix {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Prefix {}
+
+impl PartialOrd for Prefix {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Prefix {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.probability.total_cmp(&other.probability)
+    }
+}
+
+/// Lists the derivations of a rule along with their probabilities, starting with the prefixes
+/// of choices that are most likely.
+///
+/// Each prefix stands for every derivation that starts with its choices, so the prefixes that
+/// have not been listed yet split the probability of the derivations that are left between them.
+struct Search {
+    start_symbol: String,
+    frontier: BinaryHeap<Prefix>,
+    remaining: f64,
+}
+
+impl Search {
+    fn new(start_symbol: &str) -> Self {
+        Search {
+            start_symbol: start_symbol.to_string(),
+            frontier: BinaryHeap::from([Prefix {
+                probability: 1.0,
+                positions: Vec::new(),
+            }]),
+            remaining: 1.0,
+        }
+    }
+
+    /// The total probability of the derivations that have not been listed yet.
+    fn remaining(&self) -> f64 {
+        self.remaining.max(0.0)
+    }
+
+    /// The probability of the most likely prefix that has not been listed yet, which no
+    /// derivation that is left is more likely than.
+    fn most_likely_left(&self) -> f64 {
+        self.frontier
+            .peek()
+            .map_or(0.0, |prefix| prefix.probability)
+    }
+
+    fn next_derivation(
+        &mut self,
+        grammar: &mut Grammar,
+    ) -> Option<Result<(ExpansionTree, f64), CalyxError>> {
+        loop {
+            let prefix = self.frontier.pop()?;
+            self.remaining -= prefix.probability;
+
+            let context = HashMap::new();
+            let mut eval_context =
+                EvaluationContext::enumerate(grammar, &context, &prefix.positions, None);
+            let result = eval_context.expand_and_evaluate(&self.start_symbol);
+
+            // every other option of a choice made after the prefix starts another prefix
+            let mut probability = 1.0;
+            for (depth, (position, odds)) in eval_context.enumerated().iter().enumerate() {
+                if depth >= prefix.positions.len() {
+                    for other in (0..odds.len()).filter(|other| other != position) {
+                        let other_probability = probability * odds.probability(other);
+                        if other_probability <= 0.0 {
+                            continue;
+                        }
+
+                        let mut positions: Vec<usize> = eval_context.enumerated()[..depth]
+                            .iter()
+                            .map(|(position, _)| *position)
+                            .collect();
+                        positions.push(other);
+
+                        self.remaining += other_probability;
+                        self.frontier.push(Prefix {
+                            probability: other_probability,
+                            positions,
+                        });
+                    }
+                }
+
+                probability *= odds.probability(*position);
+            }
+
+            match result {
+                Ok(tree) => {
+                    let rule_name = self.start_symbol.clone();
+                    let tree = ExpansionTree::chain(ExpansionType::Result { rule_name }, tree);
+                    return Some(Ok((tree, eval_context.probability())));
+                }
+                Err(error) if has_no_output(&error) => {}
+                Err(error) => {
+                    self.frontier.clear();
+                    return Some(Err(error));
+                }
+            }
+        }
+    }
+}
+
+/// Whether an error only means that a derivation never finishes, so has no output, rather than
+/// that the grammar cannot be expanded.
+pub(crate) fn has_no_output(error: &CalyxError) -> bool {
+    matches!(
+        error,
+        CalyxError::RecursionLimitExceeded { .. } | CalyxError::UndefinedRule { .. }
+    )
+}
+
+/// Computes the probability of rules expanding to pieces of a text, by summing over the ways
+/// that the templates of each rule can be split across the piece.
+///
+/// This only works for rules whose expressions are all plain, since memoized and unique
+/// expressions depend on the rest of the derivation, and filters and mappings rewrite text.
+struct Inside<'a> {
+    chart: Chart<'a, f64>,
+    previous: HashMap<Span<'a>, f64>,
+}
+
+impl<'a> Inside<'a> {
+    fn new(registry: &'a Registry, strict: bool, text: &'a str) -> Self {
+        Inside {
+            chart: Chart::new(registry, strict, text),
+            previous: HashMap::new(),
+        }
+    }
+
+    /// Whether every expression of the rules reachable from a rule is plain.
+    fn is_context_free(&self, rule_name: &str) -> bool {
+        let mut visited: HashSet<&str> = HashSet::new();
+        let mut pending = vec![rule_name];
+
+        while let Some(symbol) = pending.pop() {
+            let Some(rule) = self.chart.rule(symbol) else {
+                continue;
+            };
+
+            for template in rule.templates() {
+                for expression in template.expressions() {
+                    if expression.sigil != Sigil::None
+                        || !expression.mappings.is_empty()
+                        || !expression.filters.is_empty()
+                    {
+                        return false;
+                    }
+
+                    if visited.insert(&expression.symbol) {
+                        pending.push(&expression.symbol);
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    /// The probability of a rule expanding to the whole text, or [None] if the rule is not
+    /// context free.
+    ///
+    /// Rules that refer back to themselves without consuming any text are refined until their
+    /// probabilities stop changing.
+    fn rule_text(&mut self, rule_name: &'a str) -> Option<f64> {
+        if !self.is_context_free(rule_name) {
+            return None;
+        }
+
+        let end = self.chart.end();
+        let mut probability = 0.0;
+
+        for _ in 0..MAX_ROUNDS {
+            probability = self.rule(rule_name, 0, end);
+
+            let current = self.chart.take();
+            let converged = current.iter().all(|(span, value)| {
+                (value - self.previous.get(span).copied().unwrap_or(0.0)).abs() <= TOLERANCE
+            });
+            self.previous = current;
+
+            if converged {
+                break;
+            }
+        }
+
+        Some(probability)
+    }
+
+    /// The probability of a rule expanding to the text between two boundaries.
+    fn rule(&mut self, symbol: &'a str, start: usize, end: usize) -> f64 {
+        let span = (symbol, start, end);
+        if let Some(probability) = self.chart.get(&span) {
+            return *probability;
+        }
+
+        let Some(rule) = self.chart.rule(symbol) else {
+            return if self.chart.undefined_matches(start, end) {
+                1.0
+            } else {
+                0.0
+            };
+        };
+
+        // a rule that refers back to itself uses its probability from the previous round
+        if !self.chart.begin(span) {
+            return self.previous.get(&span).copied().unwrap_or(0.0);
+        }
+
+        let odds = rule.odds();
+        let mut probability = 0.0;
+        for (index, template) in rule.templates().into_iter().enumerate() {
+            probability += odds.probability(index) * self.segments(template.segments(), start, end);
+        }
+
+        self.chart.finish(&span);
+        self.chart.insert(span, probability);
+        probability
+    }
+
+    /// The probability of the segments of a template expanding to the text between two
+    /// boundaries.
+    fn segments(&mut self, segments: &'a [Segment], start: usize, end: usize) -> f64 {
+        // the probability of the segments from each one onwards expanding to the text from each
+        // boundary onwards
+        let mut rest = vec![0.0; end + 1];
+        rest[end] = 1.0;
+
+        for segment in segments.iter().rev() {
+            let mut from = vec![0.0; start];
+
+            for boundary in start..=end {
+                from.push(match segment {
+                    Segment::Text(literal) => self
+                        .chart
+                        .literal(literal, boundary, end)
+                        .map_or(0.0, |next| rest[next]),
+                    Segment::Expression(expression) => (boundary..=end)
+                        .filter(|split| rest[*split] > 0.0)
+                        .map(|split| self.rule(&expression.symbol, boundary, split) * rest[split])
+                        .sum(),
+                });
+            }
+
+            rest = from;
+        }
+
+        rest[start]
+    }
+}
+
+#[cfg(test)]
+mod probability_tests {
+    use crate::generation::CalyxError;
+    use crate::generation::test_support::grammar;
+    use std::collections::HashMap;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {}, found {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn sums_every_derivation_of_an_output() {
+        let mut grammar = grammar(&[
+            ("start", &["{a}{b}", "ab", "c"]),
+            ("a", &["a", ""]),
+            ("b", &["b", "ab"]),
+        ]);
+
+        // "{a}{b}" gives "ab" as "a"+"b" or ""+"ab", besides the literal template
+        assert_close(
+            grammar.output_probability("start", "ab").unwrap(),
+            1.0 / 3.0 * 0.5 + 1.0 / 3.0,
+        );
+        assert_close(grammar.output_probability("start", "c").unwrap(), 1.0 / 3.0);
+        assert_close(grammar.output_probability("start", "abc").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn weights_and_recursion() {
+        let mut grammar = grammar(&[("start", &["{digit}", "{digit}{start}"])]);
+        let weights = HashMap::from([(String::from("0"), 1.0), (String::from("1"), 3.0)]);
+        assert!(
+            grammar
+                .weighted_rule(String::from("digit"), &weights)
+                .is_ok()
+        );
+
+        assert_close(
+            grammar.output_probability("start", "1").unwrap(),
+            0.5 * 0.75,
+        );
+        assert_close(
+            grammar.output_probability("start", "10").unwrap(),
+            0.5 * 0.75 * 0.5 * 0.25,
+        );
+
+        let top = grammar.top_outputs("start", 3).unwrap();
+        let texts: Vec<&str> = top.iter().map(|(text, _)| text.as_str()).collect();
+        assert_eq!(texts, vec!["1", "11", "0"]);
+        assert_close(top[0].1, 0.375);
+        assert_close(top[1].1, 0.375 * 0.375);
+        assert_close(top[2].1, 0.125);
+    }
+
+    #[test]
+    fn rules_that_consume_nothing_converge() {
+        let mut grammar = grammar(&[("start", &["{start}", "x"])]);
+
+        assert_close(grammar.output_probability("start", "x").unwrap(), 1.0);
+    }
+
+    #[test]
+    fn memoized_unique_and_filtered_expressions() {
+        let mut grammar = grammar(&[
+            (
+                "start",
+                &["{@name} and {@name.uppercase}", "{$name} {$name}"],
+            ),
+            ("name", &["ada", "grace", "ada"]),
+        ]);
+
+        assert_close(
+            grammar.output_probability("start", "ada and ADA").unwrap(),
+            0.5 * 2.0 / 3.0,
+        );
+        // unique expressions never choose the same template twice in a row
+        assert_close(
+            grammar.output_probability("start", "ada ada").unwrap(),
+            0.5 * 2.0 / 3.0 * 0.5,
+        );
+
+        let top = grammar.top_outputs("start", 2).unwrap();
+        assert_eq!(top[0].0, "ada and ADA");
+        assert_close(top[0].1, 1.0 / 3.0);
+        // outputs that are as likely as each other are ordered by their text
+        assert_eq!(top[1].0, "ada ada");
+        assert_close(top[1].1, 1.0 / 6.0);
+    }
+
+    #[test]
+    fn outputs_spread_over_many_derivations() {
+        let copies = vec!["x"; 1000];
+        let mut grammar = grammar(&[("start", &["Y", "{@n}", "{@n}"]), ("n", &copies)]);
+
+        assert_close(grammar.output_probability("start", "x").unwrap(), 2.0 / 3.0);
+
+        let top = grammar.top_outputs("start", 1).unwrap();
+        assert_eq!(top[0].0, "x");
+        assert_close(top[0].1, 2.0 / 3.0);
+    }
+
+    #[test]
+    fn fewer_outputs_than_asked_for() {
+        let mut grammar = grammar(&[("start", &["a", "b", "a"])]);
+
+        let top = grammar.top_outputs("start", 5).unwrap();
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].0, "a");
+        assert_close(top[0].1, 2.0 / 3.0);
+        assert!(grammar.top_outputs("start", 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn derivation_probability_of_generated_trees() {
+        let mut grammar = grammar(&[
+            (
+                "start",
+                &[
+                    "{@name} met {@name.uppercase} and {$title} {$title}",
+                    "nobody",
+                ],
+            ),
+            ("name", &["ada", "{title} grace"]),
+            ("title", &["dr", "prof", "sir"]),
+        ]);
+
+        for _ in 0..20 {
+            let (tree, log) = grammar.generate_with_log().unwrap();
+            let probability = grammar.derivation_probability(&tree).unwrap();
+
+            let expected = if tree.flatten() == "nobody" {
+                0.5
+            } else {
+                // each memoized name and title choice, then two distinct titles
+                let name = if log.choices()[1].index() == 0 {
+                    0.5
+                } else {
+                    0.5 / 3.0
+                };
+                0.5 * name * (1.0 / 3.0) * 0.5
+            };
+            assert_close(probability, expected);
+        }
+    }
+
+    #[test]
+    fn derivation_probability_needs_a_rule() {
+        let mut grammar = grammar(&[("start", &["a"])]);
+        let tree = grammar.generate().unwrap();
+
+        assert!(matches!(
+            grammar.derivation_probability(&tree.children()[0]),
+            Err(CalyxError::NodeNotFound { .. })
+        ));
+    }
+}
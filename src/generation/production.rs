@@ -1,9 +1,14 @@
+pub(super) mod affix;
 pub(super) mod branch;
-mod node;
+pub(super) mod depth;
+pub(super) mod node;
+pub(super) mod parser;
 
-use crate::generation::evaluation::EvaluationContext;
-use crate::generation::expansion_tree::ExpansionTree;
 use crate::generation::CalyxError;
+use crate::generation::document::RuleDefinition;
+use crate::generation::evaluation::{EvaluationContext, Odds};
+use crate::generation::expansion_tree::ExpansionTree;
+use crate::generation::production::node::TemplateNode;
 
 pub trait Production {
     fn evaluate(&self, eval_context: &mut EvaluationContext) -> Result<ExpansionTree, CalyxError>;
@@ -17,4 +22,12 @@ pub trait ProductionBranch: Production {
     ) -> Result<ExpansionTree, CalyxError>;
 
     fn len(&self) -> usize;
+
+    fn to_definition(&self) -> RuleDefinition;
+
+    /// The templates of this branch, in the order they are indexed.
+    fn templates(&self) -> Vec<&TemplateNode>;
+
+    /// How likely each template of this branch is to be chosen at random.
+    fn odds(&self) -> Odds;
 }
@@ -0,0 +1,82 @@
+use crate::generation::CalyxError;
+use regex::Regex;
+
+/// A mapping rule, which rewrites text with the first of its patterns that matches.
+///
+/// Mappings are applied to expressions with the `>` operator, such as `{noun>plural}`.
+pub(crate) struct AffixTable {
+    pairs: Vec<(Regex, String)>,
+}
+
+impl AffixTable {
+    pub(crate) fn parse(raw: &[(String, String)]) -> Result<Self, CalyxError> {
+        let mut pairs = Vec::with_capacity(raw.len());
+
+        for (pattern, replacement) in raw {
+            let regex = Regex::new(pattern).map_err(|error| CalyxError::InvalidRegex {
+                pattern: pattern.clone(),
+                message: error.to_string(),
+            })?;
+
+            pairs.push((regex, replacement.clone()));
+        }
+
+        Ok(Self { pairs })
+    }
+
+    /// Replaces every match of the first matching pattern in the input. If no pattern matches,
+    /// the input is returned unchanged.
+    pub(crate) fn apply(&self, input: &str) -> String {
+        self.pairs
+            .iter()
+            .find(|(regex, _)| regex.is_match(input))
+            .map(|(regex, replacement)| regex.replace_all(input, replacement.as_str()).into_owned())
+            .unwrap_or_else(|| input.to_string())
+    }
+
+    /// The original patterns and replacements of this table.
+    pub(crate) fn to_pairs(&self) -> Vec<(String, String)> {
+        self.pairs
+            .iter()
+            .map(|(regex, replacement)| (regex.as_str().to_string(), replacement.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod affix_table_tests {
+    use crate::generation::CalyxError;
+    use crate::generation::production::affix::AffixTable;
+
+    fn table(pairs: &[(&str, &str)]) -> AffixTable {
+        let pairs: Vec<(String, String)> = pairs
+            .iter()
+            .map(|(pattern, replacement)| (pattern.to_string(), replacement.to_string()))
+            .collect();
+
+        AffixTable::parse(&pairs).expect("Error parsing affix table")
+    }
+
+    #[test]
+    fn first_matching_pattern_is_applied() {
+        let plural = table(&[("(.+)y$", "${1}ies"), ("(.+)", "${1}s")]);
+
+        assert_eq!(plural.apply("pony"), "ponies");
+        assert_eq!(plural.apply("horse"), "horses");
+    }
+
+    #[test]
+    fn unmatched_input_is_unchanged() {
+        let saddle = table(&[("^horse$", "saddle")]);
+        assert_eq!(saddle.apply("camel"), "camel");
+    }
+
+    #[test]
+    fn invalid_pattern_fails() {
+        let pairs = vec![(String::from("(unclosed"), String::from("x"))];
+        let result = AffixTable::parse(&pairs);
+        assert!(
+            matches!(result, Err(CalyxError::InvalidRegex { ref pattern, .. }) if pattern == "(unclosed")
+        );
+    }
+}
@@ -1,10 +1,11 @@
 use crate::generation::CalyxError;
-use crate::generation::evaluation::EvaluationContext;
+use crate::generation::document::RuleDefinition;
+use crate::generation::evaluation::{EvaluationContext, Odds};
 use crate::generation::expansion_tree::{ExpansionTree, ExpansionType};
+use crate::generation::filter::Filter;
 use crate::generation::production::node::TemplateNode;
 use crate::generation::production::{Production, ProductionBranch};
 use std::collections::HashMap;
-use rand::RngExt;
 
 pub(crate) struct EmptyBranch {}
 
@@ -16,7 +17,7 @@ impl Production for EmptyBranch {
 
 impl ProductionBranch for EmptyBranch {
     fn evaluate_at(
-        self: &Self,
+        &self,
         _index: usize,
         _eval_context: &mut EvaluationContext,
     ) -> Result<ExpansionTree, CalyxError> {
@@ -27,6 +28,18 @@ impl ProductionBranch for EmptyBranch {
     fn len(&self) -> usize {
         1
     }
+
+    fn to_definition(&self) -> RuleDefinition {
+        RuleDefinition::Uniform(vec![])
+    }
+
+    fn templates(&self) -> Vec<&TemplateNode> {
+        Vec::new()
+    }
+
+    fn odds(&self) -> Odds {
+        Odds::Uniform(1)
+    }
 }
 
 pub(crate) struct UniformBranch {
@@ -34,11 +47,15 @@ pub(crate) struct UniformBranch {
 }
 
 impl UniformBranch {
-    pub(crate) fn parse(raw: &Vec<String>) -> Result<Self, CalyxError> {
+    pub(crate) fn parse(
+        raw: &[String],
+        filters: &HashMap<String, Filter>,
+    ) -> Result<Self, CalyxError> {
         let mut choices: Vec<TemplateNode> = Vec::new();
 
-        for term in raw {
-            let template_node = TemplateNode::parse(term)?;
+        for (index, term) in raw.iter().enumerate() {
+            let template_node =
+                TemplateNode::parse(term, filters).map_err(|error| error.in_template(index))?;
             choices.push(template_node)
         }
 
@@ -48,14 +65,24 @@ impl UniformBranch {
 
 impl Production for UniformBranch {
     fn evaluate(&self, eval_context: &mut EvaluationContext) -> Result<ExpansionTree, CalyxError> {
-
-        let index = if !self.choices.is_empty() {
-            eval_context
-                .options()
-                .random_source
-                .random_range(0..self.choices.len())
-        } else {
+        let index = if self.choices.is_empty() {
             0
+        } else {
+            eval_context.choose(self.odds(), |eval_context| {
+                match eval_context.shallowest_templates(self) {
+                    Some(shallowest) => {
+                        let choice = eval_context
+                            .options()
+                            .decision_source
+                            .choose(shallowest.len());
+                        shallowest[choice]
+                    }
+                    None => eval_context
+                        .options()
+                        .decision_source
+                        .choose(self.choices.len()),
+                }
+            })?
         };
 
         self.evaluate_at(index, eval_context)
@@ -74,35 +101,56 @@ impl ProductionBranch for UniformBranch {
             .ok_or(CalyxError::ExpandedEmptyBranch)?;
 
         let tail = item.evaluate(eval_context)?;
-        Ok(ExpansionTree::chain(ExpansionType::UniformBranch, tail))
+        Ok(ExpansionTree::chain(
+            ExpansionType::UniformBranch { index },
+            tail,
+        ))
     }
 
     fn len(&self) -> usize {
         self.choices.len()
     }
+
+    fn to_definition(&self) -> RuleDefinition {
+        let mut templates: Vec<String> = self
+            .choices
+            .iter()
+            .map(|choice| choice.source().to_string())
+            .collect();
+
+        if templates.len() == 1 {
+            RuleDefinition::Single(templates.remove(0))
+        } else {
+            RuleDefinition::Uniform(templates)
+        }
+    }
+
+    fn templates(&self) -> Vec<&TemplateNode> {
+        self.choices.iter().collect()
+    }
+
+    fn odds(&self) -> Odds {
+        Odds::Uniform(self.choices.len())
+    }
 }
 
 struct WeightedProduction {
-    production: Box<dyn Production>,
+    production: TemplateNode,
     weight: f64,
 }
 
 impl WeightedProduction {
-    fn new<P: Production + 'static>(weight: f64, production: P) -> Result<Self, CalyxError> {
+    fn new(weight: f64, production: TemplateNode) -> Result<Self, CalyxError> {
         if WeightedBranch::is_invalid_weight(weight) {
             return Err(CalyxError::InvalidWeight { weight });
         }
 
-        Ok(Self {
-            production: Box::new(production),
-            weight,
-        })
+        Ok(Self { production, weight })
     }
 }
 
 pub(crate) struct WeightedBranch {
     productions: Vec<WeightedProduction>,
-    sum_of_weights: f64,
 }
 
 impl WeightedBranch {
@@ -119,38 +167,39 @@ impl WeightedBranch {
             });
         }
 
-        Ok(Self {
-            productions,
-            sum_of_weights,
-        })
+        Ok(Self { productions })
     }
 
-    fn get_random_production(&self, eval_context: &mut EvaluationContext) -> &WeightedProduction {
-        let rng = eval_context.options().random_source.as_mut();
-        let water_mark: f64 = rng.random::<f64>() * self.sum_of_weights;
-
-        let mut cumulative = 0.0;
-
-        for wp in &self.productions {
-            cumulative += wp.weight;
-            if water_mark < cumulative {
-                return wp;
-            }
-        }
-
-        // this should never happen, as the total weight should be greater than 0
-        panic!("Unable to evaluate weighted production")
+    fn get_random_index(&self, eval_context: &mut EvaluationContext) -> usize {
+        let candidates: Vec<usize> = eval_context
+            .shallowest_templates(self)
+            .unwrap_or_else(|| (0..self.productions.len()).collect());
+
+        let weights: Vec<f64> = candidates
+            .iter()
+            .map(|index| self.productions[*index].weight)
+            .collect();
+
+        let choice = eval_context
+            .options()
+            .decision_source
+            .choose_weighted(&weights);
+        candidates[choice]
     }
 
-    pub(crate) fn parse(raw: &HashMap<String, f64>) -> Result<Self, CalyxError> {
+    pub(crate) fn parse(
+        raw: &HashMap<String, f64>,
+        filters: &HashMap<String, Filter>,
+    ) -> Result<Self, CalyxError> {
         let mut productions: Vec<WeightedProduction> = Vec::new();
 
         // remove the random ordering of the hashmap.
         let mut entries: Vec<(&String, &f64)> = raw.iter().collect();
-        entries.sort_by(|(name_a, _), (name_b, _)| name_a.cmp(name_b));
+        entries.sort_by_key(|(name, _)| *name);
 
-        for (name, weight) in entries {
-            let node = TemplateNode::parse(&name)?;
+        for (index, (name, weight)) in entries.into_iter().enumerate() {
+            let node =
+                TemplateNode::parse(name, filters).map_err(|error| error.in_template(index))?;
             productions.push(WeightedProduction::new(*weight, node)?)
         }
 
@@ -160,9 +209,10 @@ impl WeightedBranch {
 
 impl Production for WeightedBranch {
     fn evaluate(&self, eval_context: &mut EvaluationContext) -> Result<ExpansionTree, CalyxError> {
-        let prod = self.get_random_production(eval_context);
-        let choice = prod.production.evaluate(eval_context)?;
-        Ok(ExpansionTree::chain(ExpansionType::WeightedBranch, choice))
+        let index = eval_context.choose(self.odds(), |eval_context| {
+            self.get_random_index(eval_context)
+        })?;
+        self.evaluate_at(index, eval_context)
     }
 }
 
@@ -172,14 +222,39 @@ impl ProductionBranch for WeightedBranch {
         index: usize,
         eval_context: &mut EvaluationContext,
     ) -> Result<ExpansionTree, CalyxError> {
-        self.productions
+        let item = self
+            .productions
             .get(index)
-            .ok_or(CalyxError::ExpandedEmptyBranch)?
-            .production
-            .evaluate(eval_context)
+            .ok_or(CalyxError::ExpandedEmptyBranch)?;
+
+        let tail = item.production.evaluate(eval_context)?;
+        Ok(ExpansionTree::chain(
+            ExpansionType::WeightedBranch {
+                index,
+                weight: item.weight,
+            },
+            tail,
+        ))
     }
 
     fn len(&self) -> usize {
         self.productions.len()
     }
+
+    fn to_definition(&self) -> RuleDefinition {
+        RuleDefinition::Weighted(
+            self.productions
+                .iter()
+                .map(|wp| (wp.production.source().to_string(), wp.weight))
+                .collect(),
+        )
+    }
+
+    fn templates(&self) -> Vec<&TemplateNode> {
+        self.productions.iter().map(|wp| &wp.production).collect()
+    }
+
+    fn odds(&self) -> Odds {
+        Odds::Weighted(self.productions.iter().map(|wp| wp.weight).collect())
+    }
 }
@@ -0,0 +1,133 @@
+use crate::generation::production::ProductionBranch;
+use crate::generation::production::node::TemplateNode;
+use std::collections::HashMap;
+
+/// The minimum depth of nested rule expansions needed to fully expand each rule of a grammar.
+///
+/// A rule made only of literal text has a depth of 1, and a rule that refers to other rules is one
+/// deeper than the shallowest of its templates. Undefined rules expand to nothing, so references
+/// to them add no depth. Rules that can never finish expanding have no depth.
+pub(crate) struct MinimumDepths {
+    depths: HashMap<String, Option<usize>>,
+}
+
+impl MinimumDepths {
+    pub(crate) fn compute<'a>(
+        rules: impl Iterator<Item = (&'a String, &'a dyn ProductionBranch)>,
+    ) -> Self {
+        let rules: Vec<(&String, &dyn ProductionBranch)> = rules.collect();
+
+        let mut minimum_depths = MinimumDepths {
+            depths: rules
+                .iter()
+                .map(|(term, _)| (term.to_string(), None))
+                .collect(),
+        };
+
+        // depths only ever decrease as shallower templates are found, so this settles
+        loop {
+            let mut changed = false;
+
+            for (term, rule) in &rules {
+                // a rule without templates fails to expand rather than recursing
+                let depth = if rule.len() == 0 {
+                    Some(1)
+                } else {
+                    rule.templates()
+                        .into_iter()
+                        .filter_map(|template| minimum_depths.template(template))
+                        .min()
+                        .map(|depth| depth + 1)
+                };
+
+                if depth.is_some() && depth != minimum_depths.rule(term) {
+                    minimum_depths.depths.insert(term.to_string(), depth);
+                    changed = true;
+                }
+            }
+
+            if !changed {
+                return minimum_depths;
+            }
+        }
+    }
+
+    /// The minimum depth of a rule, or [None] if it can never finish expanding.
+    pub(crate) fn rule(&self, symbol: &str) -> Option<usize> {
+        self.depths.get(symbol).copied().unwrap_or(Some(0))
+    }
+
+    /// The minimum depth of the rules a template refers to, or [None] if any of them can never
+    /// finish expanding.
+    pub(crate) fn template(&self, template: &TemplateNode) -> Option<usize> {
+        template
+            .expressions()
+            .map(|expression| self.rule(&expression.symbol))
+            .try_fold(0, |deepest, depth| Some(deepest.max(depth?)))
+    }
+
+    /// Whether a rule can finish expanding.
+    pub(crate) fn terminates(&self, symbol: &str) -> bool {
+        self.rule(symbol).is_some()
+    }
+}
+
+#[cfg(test)]
+mod depth_tests {
+    use crate::generation::production::ProductionBranch;
+    use crate::generation::production::branch::UniformBranch;
+    use crate::generation::production::depth::MinimumDepths;
+    use std::collections::HashMap;
+
+    fn depths(rules: &[(&str, &[&str])]) -> MinimumDepths {
+        let branches: Vec<(String, UniformBranch)> = rules
+            .iter()
+            .map(|(term, templates)| {
+                let templates: Vec<String> = templates.iter().map(|t| t.to_string()).collect();
+                let branch =
+                    UniformBranch::parse(&templates, &HashMap::new()).expect("Invalid template");
+                (term.to_string(), branch)
+            })
+            .collect();
+
+        MinimumDepths::compute(
+            branches
+                .iter()
+                .map(|(term, branch)| (term, branch as &dyn ProductionBranch)),
+        )
+    }
+
+    #[test]
+    fn depth_is_shallowest_template() {
+        let depths = depths(&[
+            ("expr", &["{expr} + {expr}", "({expr})", "{number}"]),
+            ("number", &["{digit}", "{digit}{number}"]),
+            ("digit", &["0", "1"]),
+        ]);
+
+        assert_eq!(depths.rule("digit"), Some(1));
+        assert_eq!(depths.rule("number"), Some(2));
+        assert_eq!(depths.rule("expr"), Some(3));
+    }
+
+    #[test]
+    fn undefined_rules_add_no_depth() {
+        let depths = depths(&[("start", &["{missing}"])]);
+
+        assert_eq!(depths.rule("missing"), Some(0));
+        assert_eq!(depths.rule("start"), Some(1));
+    }
+
+    #[test]
+    fn rules_that_never_terminate_have_no_depth() {
+        let depths = depths(&[
+            ("start", &["{a}", "{b}"]),
+            ("a", &["{b}"]),
+            ("b", &["{a}", "x{b}"]),
+        ]);
+
+        assert!(!depths.terminates("a"));
+        assert!(!depths.terminates("b"));
+        assert!(!depths.terminates("start"));
+    }
+}
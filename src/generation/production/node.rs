@@ -1,7 +1,10 @@
 use crate::generation::CalyxError;
 use crate::generation::evaluation::EvaluationContext;
 use crate::generation::expansion_tree::{ExpansionTree, ExpansionType};
+use crate::generation::filter::{Filter, invalid_arguments};
 use crate::generation::production::Production;
+use crate::generation::production::parser::{self, Expression, FilterCall, Segment, Sigil};
+use std::collections::HashMap;
 
 struct AtomNode {
     atom: String,
@@ -20,33 +23,60 @@ struct ExpressionNode {
 impl Production for ExpressionNode {
     fn evaluate(&self, eval_context: &mut EvaluationContext) -> Result<ExpansionTree, CalyxError> {
         let result = eval_context.expand_and_evaluate(&self.reference)?;
-        Ok(ExpansionTree::chain(ExpansionType::Expression, result))
+        Ok(ExpansionTree::chain(
+            ExpansionType::Expression {
+                rule_name: self.reference.clone(),
+            },
+            result,
+        ))
     }
 }
 
 struct ExpressionChain {
     expression_rule: Box<dyn Production>,
-    filter_names: Vec<String>,
+    filters: Vec<FilterCall>,
 }
 
 impl Production for ExpressionChain {
     fn evaluate(&self, eval_context: &mut EvaluationContext) -> Result<ExpansionTree, CalyxError> {
-        let mut initial_string: String = self.expression_rule.evaluate(eval_context)?.flatten();
+        let expansion = self.expression_rule.evaluate(eval_context)?;
+        let mut initial_string: String = expansion.flatten();
 
-        for filter_name in &self.filter_names {
+        for call in &self.filters {
             let filter = eval_context
                 .registry()
-                .get_filter(filter_name)
+                .get_filter(&call.name)
                 .ok_or_else(|| CalyxError::UndefinedFilter {
-                    filter_name: filter_name.clone(),
+                    filter_name: call.name.clone(),
                 })?;
 
-            initial_string = filter(initial_string);
+            initial_string = filter.apply(&call.name, &initial_string, &call.arguments)?;
         }
 
         Ok(ExpansionTree::chain(
             ExpansionType::ExpressionChain,
-            ExpansionTree::new_atom(initial_string),
+            expansion.with_text(initial_string),
+        ))
+    }
+}
+
+struct AffixNode {
+    expression: Box<dyn Production>,
+    mappings: Vec<String>,
+}
+
+impl Production for AffixNode {
+    fn evaluate(&self, eval_context: &mut EvaluationContext) -> Result<ExpansionTree, CalyxError> {
+        let expansion = self.expression.evaluate(eval_context)?;
+        let mut text = expansion.flatten();
+
+        for mapping in &self.mappings {
+            text = eval_context.apply_mapping(mapping, text)?;
+        }
+
+        Ok(ExpansionTree::chain(
+            ExpansionType::AffixTable,
+            expansion.with_text(text),
         ))
     }
 }
@@ -59,7 +89,12 @@ impl Production for MemoNode {
     fn evaluate(&self, eval_context: &mut EvaluationContext) -> Result<ExpansionTree, CalyxError> {
         let tree = eval_context.memoize_expansion(&self.symbol)?;
 
-        Ok(ExpansionTree::chain(ExpansionType::Memo, tree))
+        Ok(ExpansionTree::chain(
+            ExpansionType::Memo {
+                rule_name: self.symbol.clone(),
+            },
+            tree,
+        ))
     }
 }
 
@@ -71,7 +106,12 @@ impl Production for UniqueNode {
     fn evaluate(&self, eval_context: &mut EvaluationContext) -> Result<ExpansionTree, CalyxError> {
         let tree = eval_context.unique_expansion(&self.symbol)?;
 
-        Ok(ExpansionTree::chain(ExpansionType::Unique, tree))
+        Ok(ExpansionTree::chain(
+            ExpansionType::Unique {
+                rule_name: self.symbol.clone(),
+            },
+            tree,
+        ))
     }
 }
 
@@ -113,6 +153,8 @@ mod unique_tests {
 }
 
 pub(crate) struct TemplateNode {
+    source: String,
+    segments: Vec<Segment>,
     concat_nodes: Vec<Box<dyn Production>>,
 }
 
@@ -133,159 +175,135 @@ impl Production for TemplateNode {
 }
 
 impl TemplateNode {
-    pub(crate) fn parse(raw: &String) -> Result<TemplateNode, CalyxError> {
-        let fragments = Self::fragment_string(raw);
+    /// Parses a template, checking the arguments of any filters that are already defined.
+    pub(crate) fn parse(
+        raw: &str,
+        filters: &HashMap<String, Filter>,
+    ) -> Result<TemplateNode, CalyxError> {
+        let segments = parser::parse_template(raw)?;
 
         let mut concat_nodes: Vec<Box<dyn Production>> = Vec::new();
 
-        for fragment in fragments {
-            if fragment.is_empty() {
-                continue;
-            }
-
-            if fragment.starts_with("{") && fragment.ends_with("}") {
-                let raw_expression: String = fragment
-                    .get(1..fragment.len() - 1)
-                    .ok_or_else(|| CalyxError::InvalidExpression {
-                        expression: fragment.clone(),
-                    })?
-                    .to_string();
-
-                let expression = Self::parse_expression(raw_expression)?;
-                concat_nodes.push(expression)
-            } else {
-                concat_nodes.push(Box::new(AtomNode { atom: fragment }))
+        for segment in segments.iter().cloned() {
+            match segment {
+                Segment::Text(atom) => concat_nodes.push(Box::new(AtomNode { atom })),
+                Segment::Expression(expression) => {
+                    concat_nodes.push(Self::build_expression(expression, filters)?)
+                }
             }
         }
 
-        Ok(TemplateNode { concat_nodes })
+        Ok(TemplateNode {
+            source: raw.to_string(),
+            segments,
+            concat_nodes,
+        })
     }
 
-    fn parse_expression(raw_expression: String) -> Result<Box<dyn Production>, CalyxError> {
-        let components = raw_expression
-            .split(".")
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
-
-        if components.len() < 2 {
-            Self::parse_simple_expression(raw_expression)
-        } else {
-            Self::parse_expression_chain(components)
-        }
+    /// The raw template this node was parsed from.
+    pub(crate) fn source(&self) -> &str {
+        &self.source
     }
 
-    fn parse_simple_expression(raw_expression: String) -> Result<Box<dyn Production>, CalyxError> {
-        let sigil = raw_expression
-            .chars()
-            .nth(0)
-            .ok_or_else(|| CalyxError::InvalidExpression {
-                expression: raw_expression.clone(),
-            })?;
-
-        let mut raw_expression = raw_expression;
-
-        match sigil {
-            '@' => {
-                raw_expression.remove(0);
-                Ok(Box::new(MemoNode {
-                    symbol: raw_expression,
-                }))
-            }
-            '$' => {
-                raw_expression.remove(0);
-                Ok(Box::new(UniqueNode {
-                    symbol: raw_expression,
-                }))
-            }
-            _ => Ok(Box::new(ExpressionNode {
-                reference: raw_expression,
-            })),
-        }
+    /// Evaluates one piece of literal text or expression of this template, by its index.
+    pub(crate) fn evaluate_segment(
+        &self,
+        index: usize,
+        eval_context: &mut EvaluationContext,
+    ) -> Result<ExpansionTree, CalyxError> {
+        self.concat_nodes
+            .get(index)
+            .ok_or(CalyxError::ExpandedEmptyBranch)?
+            .evaluate(eval_context)
     }
 
-    fn parse_expression_chain(
-        mut raw_chain: Vec<String>,
-    ) -> Result<Box<dyn Production>, CalyxError> {
-        let expression_name = raw_chain.remove(0);
-        let expression_rule = Self::parse_simple_expression(expression_name)?;
+    /// The literal text and expressions of this template, in the order they appear.
+    pub(crate) fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
 
-        Ok(Box::new(ExpressionChain {
-            expression_rule,
-            filter_names: raw_chain,
-        }))
+    /// The expressions of this template, in the order they appear.
+    pub(crate) fn expressions(&self) -> impl Iterator<Item = &Expression> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Expression(expression) => Some(expression),
+            Segment::Text(_) => None,
+        })
     }
 
-    fn fragment_string(raw: &str) -> Vec<String> {
-        let mut fragments = Vec::new();
-        let mut current = String::new();
-
-        for ch in raw.chars() {
-            match ch {
-                '{' => {
-                    if !current.is_empty() {
-                        fragments.push(std::mem::take(&mut current));
-                    }
-                    current.push(ch);
-                }
-                '}' => {
-                    current.push(ch);
-                    fragments.push(std::mem::take(&mut current));
-                }
-                _ => current.push(ch),
-            }
+    fn build_expression(
+        expression: Expression,
+        filters: &HashMap<String, Filter>,
+    ) -> Result<Box<dyn Production>, CalyxError> {
+        let symbol = expression.symbol;
+
+        let mut node: Box<dyn Production> = match expression.sigil {
+            Sigil::Memo => Box::new(MemoNode { symbol }),
+            Sigil::Unique => Box::new(UniqueNode { symbol }),
+            Sigil::None => Box::new(ExpressionNode { reference: symbol }),
+        };
+
+        if !expression.mappings.is_empty() {
+            node = Box::new(AffixNode {
+                expression: node,
+                mappings: expression.mappings,
+            });
+        }
+
+        if expression.filters.is_empty() {
+            return Ok(node);
         }
 
-        if !current.is_empty() {
-            fragments.push(current);
+        // filters registered after this template is parsed are checked during evaluation instead
+        for call in &expression.filters {
+            if let Some(filter) = filters.get(&call.name)
+                && !filter.accepts(call.arguments.len())
+            {
+                let message = filter.arity_message(call.arguments.len());
+                return Err(invalid_arguments(&call.name, &message));
+            }
         }
 
-        fragments
+        Ok(Box::new(ExpressionChain {
+            expression_rule: node,
+            filters: expression.filters,
+        }))
     }
 }
 
 #[cfg(test)]
-mod template_tests {
-    use crate::generation::production::node::TemplateNode;
+mod filter_call_tests {
+    use crate::generation::{CalyxError, Grammar};
 
     #[test]
-    fn frag_with_no_delimiters() {
-        let frags = TemplateNode::fragment_string("One Two Three");
-        assert_eq!(vec!["One Two Three"], frags)
+    fn wrong_argument_count_fails_at_parse_time() {
+        let mut grammar = Grammar::new();
+
+        let result = grammar.start_single(String::from("{title.truncate(1, 2)}"));
+        assert!(matches!(
+            result,
+            Err(CalyxError::InvalidFilterArguments { ref filter_name, .. }) if filter_name == "truncate"
+        ));
     }
 
     #[test]
-    fn frag_is_just_expansion() {
-        let frags = TemplateNode::fragment_string("{One Two Three}");
-        assert_eq!(vec!["{One Two Three}"], frags)
-    }
-
-    #[test]
-    fn two_adjacent_expansions() {
-        let frags = TemplateNode::fragment_string("{One}{Two}");
-        assert_eq!(vec!["{One}", "{Two}"], frags)
-    }
-
-    #[test]
-    fn frag_with_single_expansion() {
-        let frags = TemplateNode::fragment_string("{One} Two Three");
-        assert_eq!(vec!["{One}", " Two Three"], frags)
-    }
+    fn evaluate_filters_with_arguments() {
+        let mut grammar = Grammar::new();
 
-    #[test]
-    fn frag_starts_with_expansion() {
-        let frags = TemplateNode::fragment_string("{One} Two");
-        assert_eq!(vec!["{One}", " Two"], frags)
-    }
-
-    #[test]
-    fn frag_ends_with_expansion() {
-        let frags = TemplateNode::fragment_string("One {Two}");
-        assert_eq!(vec!["One ", "{Two}"], frags)
-    }
+        assert!(
+            grammar
+                .start_single(String::from("[{word.uppercase.pad_left(6, '.')}]"))
+                .is_ok()
+        );
+        assert!(
+            grammar
+                .single_rule(String::from("word"), String::from("abc"))
+                .is_ok()
+        );
 
-    #[test]
-    fn frag_with_multiple_expansion() {
-        let frags = TemplateNode::fragment_string("{One} Two {Three} Four");
-        assert_eq!(vec!["{One}", " Two ", "{Three}", " Four"], frags)
+        let text = grammar
+            .generate()
+            .expect("Error during generation")
+            .flatten();
+        assert_eq!(text, "[...ABC]");
     }
 }
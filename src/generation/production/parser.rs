@@ -0,0 +1,530 @@
+use crate::generation::diagnostic::Diagnostic;
+use crate::generation::filter::FilterArgument;
+use std::ops::Range;
+
+/// A piece of a parsed template: either literal text, or a `{}` delimited expression.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Segment {
+    Text(String),
+    Expression(Expression),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Sigil {
+    None,
+    Memo,
+    Unique,
+}
+
+/// An expression such as `{@noun>plural.uppercase}`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Expression {
+    pub(crate) sigil: Sigil,
+    pub(crate) symbol: String,
+    pub(crate) mappings: Vec<String>,
+    pub(crate) filters: Vec<FilterCall>,
+    pub(crate) span: Range<usize>,
+}
+
+/// A filter applied to an expression, such as `.truncate(20)`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct FilterCall {
+    pub(crate) name: String,
+    pub(crate) arguments: Vec<FilterArgument>,
+    pub(crate) span: Range<usize>,
+}
+
+/// Parses a template into literal text and expressions.
+///
+/// Literal braces are written as `{{` and `}}`, or escaped as `\{` and `\}`, and a literal
+/// backslash may be escaped as `\\`. Braces inside quoted filter arguments do not delimit
+/// expressions.
+///
+/// # Errors
+///
+/// Returns a [Diagnostic] pointing at the first problem in the template, such as an empty
+/// expression, an unmatched brace, an empty filter name, or an invalid sigil.
+pub(crate) fn parse_template(template: &str) -> Result<Vec<Segment>, Diagnostic> {
+    TemplateParser {
+        template,
+        position: 0,
+    }
+    .parse()
+}
+
+struct TemplateParser<'a> {
+    template: &'a str,
+    position: usize,
+}
+
+impl<'a> TemplateParser<'a> {
+    fn parse(mut self) -> Result<Vec<Segment>, Diagnostic> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+
+        while let Some(ch) = self.peek() {
+            match (ch, self.peek_second()) {
+                ('\\', Some(escaped @ ('{' | '}' | '\\'))) | ('{', Some(escaped @ '{')) => {
+                    self.position += ch.len_utf8() + escaped.len_utf8();
+                    text.push(escaped);
+                }
+                ('}', Some('}')) => {
+                    self.position += 2;
+                    text.push('}');
+                }
+                ('{', _) => {
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+
+                    segments.push(Segment::Expression(self.parse_expression()?));
+                }
+                ('}', _) => {
+                    return Err(self.error_at("unmatched closing brace", self.position));
+                }
+                _ => {
+                    self.position += ch.len_utf8();
+                    text.push(ch);
+                }
+            }
+        }
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+
+        Ok(segments)
+    }
+
+    fn parse_expression(&mut self) -> Result<Expression, Diagnostic> {
+        let start = self.position;
+        self.position += 1;
+
+        if self.peek() == Some('}') {
+            self.position += 1;
+            return Err(self.error("empty expression", start..self.position));
+        }
+
+        let sigil = match self.peek() {
+            Some('@') => Sigil::Memo,
+            Some('$') => Sigil::Unique,
+            _ => Sigil::None,
+        };
+
+        if sigil != Sigil::None {
+            self.position += 1;
+        }
+
+        if let Some(ch) = self.peek()
+            && Self::is_invalid_sigil(ch)
+        {
+            return Err(self.error_at(&format!("invalid sigil `{}`", ch), self.position));
+        }
+
+        let symbol = self.take_name();
+        if symbol.is_empty() {
+            return Err(self.error_at("missing rule name", self.position));
+        }
+
+        let mut mappings = Vec::new();
+        while self.peek() == Some('>') {
+            let operator = self.position;
+            self.position += 1;
+
+            let mapping = self.take_name();
+            if mapping.is_empty() {
+                return Err(self.error("empty mapping name", operator..self.position));
+            }
+
+            mappings.push(mapping);
+        }
+
+        let mut filters = Vec::new();
+        while self.peek() == Some('.') {
+            filters.push(self.parse_filter_call()?);
+        }
+
+        match self.peek() {
+            Some('}') => {
+                self.position += 1;
+                Ok(Expression {
+                    sigil,
+                    symbol,
+                    mappings,
+                    filters,
+                    span: start..self.position,
+                })
+            }
+            None => Err(self.error("unclosed expression", start..self.template.len())),
+            Some('{') => Err(self.error_at("unexpected `{` inside expression", self.position)),
+            Some(ch) => {
+                Err(self.error_at(&format!("unexpected `{}` in expression", ch), self.position))
+            }
+        }
+    }
+
+    fn parse_filter_call(&mut self) -> Result<FilterCall, Diagnostic> {
+        let start = self.position;
+        self.position += 1;
+
+        let name = self.take_name();
+        if name.is_empty() {
+            return Err(self.error("empty filter name", start..self.position));
+        }
+
+        let arguments = if self.peek() == Some('(') {
+            self.parse_arguments()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(FilterCall {
+            name,
+            arguments,
+            span: start + 1..self.position,
+        })
+    }
+
+    fn parse_arguments(&mut self) -> Result<Vec<FilterArgument>, Diagnostic> {
+        let open = self.position;
+        self.position += 1;
+
+        let mut arguments = Vec::new();
+
+        loop {
+            self.skip_whitespace();
+
+            match self.peek() {
+                Some(')') if arguments.is_empty() => {
+                    self.position += 1;
+                    return Ok(arguments);
+                }
+                Some(quote @ ('\'' | '"')) => arguments.push(self.parse_string(quote)?),
+                Some(ch) if ch == '-' || ch.is_ascii_digit() => {
+                    arguments.push(self.parse_integer()?)
+                }
+                Some(_) => {
+                    return Err(
+                        self.error_at("expected a string or integer argument", self.position)
+                    );
+                }
+                None => {
+                    return Err(self.error("unclosed argument list", open..self.template.len()));
+                }
+            }
+
+            self.skip_whitespace();
+
+            match self.peek() {
+                Some(',') => self.position += 1,
+                Some(')') => {
+                    self.position += 1;
+                    return Ok(arguments);
+                }
+                Some(_) => return Err(self.error_at("expected `,` or `)`", self.position)),
+                None => {
+                    return Err(self.error("unclosed argument list", open..self.template.len()));
+                }
+            }
+        }
+    }
+
+    fn parse_string(&mut self, quote: char) -> Result<FilterArgument, Diagnostic> {
+        let start = self.position;
+        self.position += 1;
+
+        let mut literal = String::new();
+
+        loop {
+            match self.peek() {
+                Some('\\') if self.peek_second().is_some() => {
+                    self.position += 1;
+                    let escaped = self.peek().unwrap_or_default();
+                    self.position += escaped.len_utf8();
+                    literal.push(escaped);
+                }
+                Some(ch) if ch == quote => {
+                    self.position += 1;
+                    return Ok(FilterArgument::String(literal));
+                }
+                Some(ch) => {
+                    self.position += ch.len_utf8();
+                    literal.push(ch);
+                }
+                None => {
+                    return Err(self.error("unterminated string", start..self.template.len()));
+                }
+            }
+        }
+    }
+
+    fn parse_integer(&mut self) -> Result<FilterArgument, Diagnostic> {
+        let start = self.position;
+
+        if self.peek() == Some('-') {
+            self.position += 1;
+        }
+
+        while self.peek().is_some_and(|ch| ch.is_ascii_digit()) {
+            self.position += 1;
+        }
+
+        self.template[start..self.position]
+            .parse()
+            .map(FilterArgument::Integer)
+            .map_err(|_| self.error("invalid integer", start..self.position.max(start + 1)))
+    }
+
+    /// Takes the name of a rule, mapping or filter, which ends at any character with a meaning
+    /// inside an expression.
+    fn take_name(&mut self) -> String {
+        let start = self.position;
+
+        while let Some(ch) = self.peek() {
+            if matches!(ch, '{' | '}' | '.' | '>' | '(' | ')' | ',' | '\'' | '"') {
+                break;
+            }
+
+            self.position += ch.len_utf8();
+        }
+
+        self.template[start..self.position].to_string()
+    }
+
+    fn is_invalid_sigil(ch: char) -> bool {
+        ch.is_ascii_punctuation() && !matches!(ch, '_' | '.' | '>' | '{' | '}')
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(ch) = self.peek().filter(|ch| ch.is_whitespace()) {
+            self.position += ch.len_utf8();
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.template[self.position..].chars().next()
+    }
+
+    fn peek_second(&self) -> Option<char> {
+        self.template[self.position..].chars().nth(1)
+    }
+
+    fn error(&self, message: &str, span: Range<usize>) -> Diagnostic {
+        Diagnostic::new(message.to_string(), self.template, span)
+    }
+
+    /// Creates a diagnostic that underlines the single character at a position.
+    fn error_at(&self, message: &str, position: usize) -> Diagnostic {
+        let width = self.template[position..]
+            .chars()
+            .next()
+            .map_or(1, char::len_utf8);
+
+        self.error(message, position..position + width)
+    }
+}
+
+#[cfg(test)]
+mod parser_tests {
+    use crate::generation::filter::FilterArgument;
+    use crate::generation::production::parser::{Segment, Sigil, parse_template};
+
+    fn parse(template: &str) -> Vec<Segment> {
+        parse_template(template).expect("Invalid template")
+    }
+
+    fn text(text: &str) -> Segment {
+        Segment::Text(text.to_string())
+    }
+
+    /// Describes each segment as either its text, or its expression in braces.
+    fn describe(template: &str) -> Vec<String> {
+        parse(template)
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.clone(),
+                Segment::Expression(expression) => format!("{{{}}}", expression.symbol),
+            })
+            .collect()
+    }
+
+    fn error(template: &str) -> (String, String) {
+        let diagnostic = parse_template(template).expect_err("Expected an invalid template");
+        (
+            diagnostic.message().to_string(),
+            diagnostic.fragment().to_string(),
+        )
+    }
+
+    #[test]
+    fn frag_with_no_delimiters() {
+        assert_eq!(describe("One Two Three"), vec!["One Two Three"]);
+    }
+
+    #[test]
+    fn frag_is_just_expansion() {
+        assert_eq!(describe("{One Two Three}"), vec!["{One Two Three}"]);
+    }
+
+    #[test]
+    fn two_adjacent_expansions() {
+        assert_eq!(describe("{One}{Two}"), vec!["{One}", "{Two}"]);
+    }
+
+    #[test]
+    fn frag_with_single_expansion() {
+        assert_eq!(describe("{One} Two Three"), vec!["{One}", " Two Three"]);
+    }
+
+    #[test]
+    fn frag_starts_with_expansion() {
+        assert_eq!(describe("{One} Two"), vec!["{One}", " Two"]);
+    }
+
+    #[test]
+    fn frag_ends_with_expansion() {
+        assert_eq!(describe("One {Two}"), vec!["One ", "{Two}"]);
+    }
+
+    #[test]
+    fn frag_with_multiple_expansion() {
+        assert_eq!(
+            describe("{One} Two {Three} Four"),
+            vec!["{One}", " Two ", "{Three}", " Four"]
+        );
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(
+            describe("{{\"key\": {value}}}"),
+            vec!["{\"key\": ", "{value}", "}"]
+        );
+    }
+
+    #[test]
+    fn escaped_braces_are_literal() {
+        assert_eq!(
+            describe(r"\{x\} in {set} C:\path \\{y}"),
+            vec!["{x} in ", "{set}", r" C:\path \", "{y}"]
+        );
+    }
+
+    #[test]
+    fn expression_records_sigil_mappings_filters_and_span() {
+        let segments = parse("Hi {@noun>plural.truncate(20).pad_left(8, '-')}!");
+
+        let Segment::Expression(expression) = &segments[1] else {
+            panic!("Expected an expression");
+        };
+
+        assert_eq!(expression.sigil, Sigil::Memo);
+        assert_eq!(expression.symbol, "noun");
+        assert_eq!(expression.mappings, vec!["plural"]);
+        assert_eq!(expression.span, 3..47);
+
+        let names: Vec<&str> = expression.filters.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["truncate", "pad_left"]);
+        assert_eq!(expression.filters[0].span, 17..29);
+        assert_eq!(
+            expression.filters[1].arguments,
+            vec![
+                FilterArgument::Integer(8),
+                FilterArgument::String(String::from("-"))
+            ]
+        );
+        assert_eq!(segments[2], text("!"));
+    }
+
+    #[test]
+    fn unique_sigil() {
+        let segments = parse("{$medal}");
+        assert!(matches!(&segments[0], Segment::Expression(e) if e.sigil == Sigil::Unique));
+    }
+
+    #[test]
+    fn string_arguments_with_escapes_and_braces() {
+        let segments = parse(r#"{list.join("}{").join('it\'s')}"#);
+
+        let Segment::Expression(expression) = &segments[0] else {
+            panic!("Expected an expression");
+        };
+
+        assert_eq!(
+            expression.filters[0].arguments,
+            vec![FilterArgument::String(String::from("}{"))]
+        );
+        assert_eq!(
+            expression.filters[1].arguments,
+            vec![FilterArgument::String(String::from("it's"))]
+        );
+    }
+
+    #[test]
+    fn empty_expression_fails() {
+        assert_eq!(
+            error("Hello {} world"),
+            ("empty expression".into(), "{}".into())
+        );
+    }
+
+    #[test]
+    fn unmatched_braces_fail() {
+        assert_eq!(error("{One"), ("unclosed expression".into(), "{One".into()));
+        assert_eq!(
+            error("One}"),
+            ("unmatched closing brace".into(), "}".into())
+        );
+        assert_eq!(
+            error("{One}}"),
+            ("unmatched closing brace".into(), "}".into())
+        );
+        assert_eq!(
+            error("{One {Two}}"),
+            ("unexpected `{` inside expression".into(), "{".into())
+        );
+    }
+
+    #[test]
+    fn empty_names_fail() {
+        assert_eq!(error("{a..b}"), ("empty filter name".into(), ".".into()));
+        assert_eq!(error("{a.}"), ("empty filter name".into(), ".".into()));
+        assert_eq!(error("{a>}"), ("empty mapping name".into(), ">".into()));
+        assert_eq!(error("{.upper}"), ("missing rule name".into(), ".".into()));
+        assert_eq!(error("{@}"), ("missing rule name".into(), "}".into()));
+    }
+
+    #[test]
+    fn invalid_sigils_fail() {
+        assert_eq!(error("{#name}"), ("invalid sigil `#`".into(), "#".into()));
+        assert_eq!(error("{@$name}"), ("invalid sigil `$`".into(), "$".into()));
+    }
+
+    #[test]
+    fn malformed_arguments_fail() {
+        assert_eq!(
+            error("{a.truncate(20}"),
+            ("expected `,` or `)`".into(), "}".into())
+        );
+        assert_eq!(
+            error("{a.truncate(twenty)}"),
+            ("expected a string or integer argument".into(), "t".into())
+        );
+        assert_eq!(
+            error("{a.truncate(1,)}"),
+            ("expected a string or integer argument".into(), ")".into())
+        );
+        assert_eq!(
+            error("{a.join('a)}"),
+            ("unterminated string".into(), "'a)}".into())
+        );
+        assert_eq!(
+            error("{a.truncate(-)}"),
+            ("invalid integer".into(), "-".into())
+        );
+        assert_eq!(
+            error("{a.truncate(1)x}"),
+            ("unexpected `x` in expression".into(), "x".into())
+        );
+    }
+}
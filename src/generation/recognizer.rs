@@ -0,0 +1,569 @@
+use crate::generation::chart::Chart;
+use crate::generation::choice_log::Choice;
+use crate::generation::count::DerivationCount;
+use crate::generation::enumeration::next_prefix;
+use crate::generation::evaluation::{EvaluationContext, Registry};
+use crate::generation::expansion_tree::{ExpansionTree, ExpansionType};
+use crate::generation::probability::has_no_output;
+use crate::generation::production::Production;
+use crate::generation::production::node::TemplateNode;
+use crate::generation::production::parser::{Expression, Segment, Sigil};
+use crate::generation::{CalyxError, Grammar};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// The most derivations that the rule of an expression with filters or mappings may have for
+/// the expression to be parsed, as each of its outputs is generated to be matched against text.
+const REWRITE_LIMIT: u128 = 1 << 12;
+
+/// The most parses kept for any piece of text, so that ambiguous grammars stay tractable.
+const MAX_PARSES: usize = 1 << 10;
+
+/// Finds every derivation of a rule that generates a text, as the trees generation would
+/// return for them.
+pub(crate) fn parse(
+    grammar: &mut Grammar,
+    rule_name: &str,
+    text: &str,
+) -> Result<Vec<ExpansionTree>, CalyxError> {
+    // the outputs of expressions with filters or mappings are only generated once the parser
+    // has to match them, after which it parses the text again
+    let mut rewritten = RewrittenOutputs::new();
+    let (candidates, unsupported) = loop {
+        let (candidates, missing, unsupported) = {
+            let mut parser =
+                Parser::new(&grammar.registry, grammar.options.strict, text, &rewritten);
+            let end = parser.chart.end();
+            let (parses, _) = parser.rule(rule_name, 0, end);
+
+            // different parses of memoized expressions may make the same choices
+            let mut candidates: Vec<Vec<Choice>> = Vec::new();
+            for parse in parses.iter() {
+                let mut choices = Vec::new();
+                parse.linearize(&mut HashSet::new(), &mut choices);
+
+                if !candidates.contains(&choices) {
+                    candidates.push(choices);
+                }
+            }
+            (candidates, parser.missing, parser.unsupported)
+        };
+
+        if missing.is_empty() {
+            break (candidates, unsupported);
+        }
+
+        for (source, symbol) in missing {
+            let outputs = rewritten_outputs(grammar, &source, &symbol)?;
+            rewritten.insert(source, outputs);
+        }
+    };
+
+    // replaying each candidate checks what the parser cannot, such as memoized expressions
+    // expanding the same way everywhere, and unique expressions not repeating templates
+    let mut trees = Vec::new();
+    for choices in candidates {
+        let context = HashMap::new();
+        let mut eval_context = EvaluationContext::replay(grammar, &context, &choices);
+
+        let tree = match eval_context.expand_and_evaluate(&rule_name.to_string()) {
+            Ok(tree) => tree,
+            Err(
+                CalyxError::ChoiceLogMismatch { .. } | CalyxError::RecursionLimitExceeded { .. },
+            ) => {
+                continue;
+            }
+            Err(error) => return Err(error),
+        };
+
+        if eval_context.choices().len() == choices.len()
+            && eval_context.probability() > 0.0
+            && tree.flatten() == text
+        {
+            let rule_name = rule_name.to_string();
+            trees.push(ExpansionTree::chain(
+                ExpansionType::Result { rule_name },
+                tree,
+            ));
+        }
+    }
+
+    if trees.is_empty()
+        && let Some(expression) = unsupported
+    {
+        return Err(CalyxError::Unsupported {
+            expression,
+            message: format!(
+                "filters and mappings can only be undone for rules with at most {} derivations",
+                REWRITE_LIMIT
+            ),
+        });
+    }
+
+    Ok(trees)
+}
+
+/// Generates every output of an expression with filters or mappings, along with the choices
+/// made to generate it. Returns [None] if the rule of the expression has infinitely many
+/// derivations, or more than [REWRITE_LIMIT].
+fn rewritten_outputs(
+    grammar: &mut Grammar,
+    source: &str,
+    symbol: &str,
+) -> Result<Option<Outputs>, CalyxError> {
+    match grammar.count_derivations(symbol) {
+        DerivationCount::Finite(count) | DerivationCount::AtMost(count)
+            if count <= REWRITE_LIMIT =>
+        {
+            generate_every_output(grammar, source).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Generates every output of a single expression, along with the choices made to generate it.
+fn generate_every_output(grammar: &mut Grammar, source: &str) -> Result<Outputs, CalyxError> {
+    let node = TemplateNode::parse(source, &HashMap::new())?;
+    let context = HashMap::new();
+    let mut outputs = Vec::new();
+    let mut prefix = Some(Vec::new());
+
+    while let Some(current) = prefix.take() {
+        let mut eval_context = EvaluationContext::enumerate(grammar, &context, &current, None);
+        let result = node.evaluate(&mut eval_context);
+        prefix = next_prefix(eval_context.enumerated());
+
+        match result {
+            Ok(tree) => {
+                let choices = eval_context.choices().to_vec();
+                outputs.push((tree.flatten(), Rc::new(Parse::Rewritten(choices))));
+            }
+            Err(error) if has_no_output(&error) => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(outputs)
+}
+
+fn is_rewritten(expression: &Expression) -> bool {
+    !expression.filters.is_empty() || !expression.mappings.is_empty()
+}
+
+/// The outputs of each expression with filters or mappings, by the source of the expression, or
+/// [None] for those with too many outputs to generate.
+type RewrittenOutputs = HashMap<String, Option<Outputs>>;
+
+/// The outputs of an expression, along with the choices made to generate each.
+type Outputs = Vec<(String, Rc<Parse<'static>>)>;
+
+/// The ways that a rule expands to a piece of text.
+type Parses<'a> = Rc<Vec<Rc<Parse<'a>>>>;
+
+/// The parse of each expression of a template, in order.
+type ExpressionParses<'a> = Vec<(&'a Expression, Rc<Parse<'a>>)>;
+
+/// The ways that segments of a template expand to a piece of text.
+type Combinations<'a> = Rc<Vec<ExpressionParses<'a>>>;
+
+/// The segments of a template from a position onwards, expanding to the text between two
+/// boundaries.
+type TemplateSpan = (*const TemplateNode, usize, usize, usize);
+
+/// One way that a rule expands to a piece of text.
+enum Parse<'a> {
+    /// A template chosen for a rule, along with a parse of each of its expressions in order.
+    Template {
+        rule_name: &'a str,
+        index: usize,
+        expressions: ExpressionParses<'a>,
+    },
+    /// An undefined rule, which expands to the empty string without making a choice.
+    Undefined,
+    /// The choices made to generate an expression with filters or mappings.
+    Rewritten(Vec<Choice>),
+}
+
+impl Parse<'_> {
+    /// Lists the choices made to generate this parse, in the order generation makes them.
+    fn linearize<'p>(&'p self, memoized: &mut HashSet<&'p str>, choices: &mut Vec<Choice>) {
+        match self {
+            Parse::Template {
+                rule_name,
+                index,
+                expressions,
+            } => {
+                choices.push(Choice::new(rule_name.to_string(), *index));
+
+                for (expression, parse) in expressions {
+                    // later memoized expressions repeat the first, so make no choices
+                    if expression.sigil == Sigil::Memo && !memoized.insert(&expression.symbol) {
+                        continue;
+                    }
+                    parse.linearize(memoized, choices);
+                }
+            }
+            Parse::Undefined => {}
+            Parse::Rewritten(rewritten) => choices.extend(rewritten.iter().cloned()),
+        }
+    }
+}
+
+/// Parses pieces of a text top down, remembering the parses of each rule for each piece.
+///
+/// Memoized and unique expressions are parsed as plain expressions, so some of the parses found
+/// may not be possible to generate. A rule is never parsed again for the same piece of text while
+/// it is being parsed for it, which leaves out derivations that only repeat a rule without
+/// generating any more text.
+struct Parser<'a> {
+    chart: Chart<'a, Parses<'a>>,
+    rewritten: &'a RewrittenOutputs,
+    // the expressions with filters or mappings met whose outputs are not generated yet, by
+    // source, along with their rules
+    missing: HashMap<String, String>,
+    // an expression met with too many outputs to generate, which is left unmatched
+    unsupported: Option<String>,
+    // the ways that the segments of each template from each position onwards expand to each
+    // piece, so that splitting a template across a piece takes polynomial time
+    tails: HashMap<TemplateSpan, Combinations<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(
+        registry: &'a Registry,
+        strict: bool,
+        text: &'a str,
+        rewritten: &'a RewrittenOutputs,
+    ) -> Self {
+        Parser {
+            chart: Chart::new(registry, strict, text),
+            rewritten,
+            missing: HashMap::new(),
+            unsupported: None,
+            tails: HashMap::new(),
+        }
+    }
+
+    /// The parses of a rule for the text between two boundaries, and whether they are complete.
+    /// Parses found while the rule is being parsed for an enclosing piece of text may be missing
+    /// some, so are not remembered.
+    fn rule(&mut self, symbol: &'a str, start: usize, end: usize) -> (Parses<'a>, bool) {
+        let span = (symbol, start, end);
+        if let Some(parses) = self.chart.get(&span) {
+            return (parses.clone(), true);
+        }
+
+        let Some(rule) = self.chart.rule(symbol) else {
+            let parses = if self.chart.undefined_matches(start, end) {
+                vec![Rc::new(Parse::Undefined)]
+            } else {
+                Vec::new()
+            };
+            return (Rc::new(parses), true);
+        };
+
+        if !self.chart.begin(span) {
+            return (Rc::new(Vec::new()), false);
+        }
+
+        let mut parses = Vec::new();
+        let mut complete = true;
+
+        for (index, template) in rule.templates().into_iter().enumerate() {
+            let (combinations, template_complete) = self.segments(template, 0, start, end);
+            complete &= template_complete;
+
+            for expressions in combinations.iter() {
+                parses.push(Rc::new(Parse::Template {
+                    rule_name: symbol,
+                    index,
+                    expressions: expressions.clone(),
+                }));
+            }
+        }
+        parses.truncate(MAX_PARSES);
+
+        self.chart.finish(&span);
+        let parses = Rc::new(parses);
+        if complete {
+            self.chart.insert(span, parses.clone());
+        }
+
+        (parses, complete)
+    }
+
+    /// The ways that the segments of a template from a position onwards can expand to the text
+    /// between two boundaries, each as a parse of every expression in order, and whether they are
+    /// complete. As for rules, only complete combinations are remembered.
+    fn segments(
+        &mut self,
+        template: &'a TemplateNode,
+        position: usize,
+        start: usize,
+        end: usize,
+    ) -> (Combinations<'a>, bool) {
+        let key = (template as *const TemplateNode, position, start, end);
+        if let Some(combinations) = self.tails.get(&key) {
+            return (combinations.clone(), true);
+        }
+
+        let (combinations, complete) = self.split(template, position, start, end);
+        if complete {
+            self.tails.insert(key, combinations.clone());
+        }
+
+        (combinations, complete)
+    }
+
+    /// Splits the text between two boundaries into the first segment of a template from a
+    /// position onwards, and the segments after it.
+    fn split(
+        &mut self,
+        template: &'a TemplateNode,
+        position: usize,
+        start: usize,
+        end: usize,
+    ) -> (Combinations<'a>, bool) {
+        let Some(segment) = template.segments().get(position) else {
+            let combinations = if start == end {
+                vec![Vec::new()]
+            } else {
+                Vec::new()
+            };
+            return (Rc::new(combinations), true);
+        };
+
+        match segment {
+            Segment::Text(literal) => match self.chart.literal(literal, start, end) {
+                Some(next) => self.segments(template, position + 1, next, end),
+                None => (Rc::new(Vec::new()), true),
+            },
+            Segment::Expression(expression) => {
+                let mut combinations = Vec::new();
+                let mut complete = true;
+
+                for split in start..=end {
+                    let (parses, expression_complete) =
+                        self.expression(template, expression, start, split);
+                    complete &= expression_complete;
+                    if parses.is_empty() {
+                        continue;
+                    }
+
+                    let (tails, tails_complete) = self.segments(template, position + 1, split, end);
+                    complete &= tails_complete;
+
+                    for parse in parses.iter() {
+                        for tail in tails.iter() {
+                            let mut expressions = Vec::with_capacity(tail.len() + 1);
+                            expressions.push((expression, parse.clone()));
+                            expressions.extend(tail.iter().cloned());
+                            combinations.push(expressions);
+                        }
+                    }
+
+                    if combinations.len() >= MAX_PARSES {
+                        combinations.truncate(MAX_PARSES);
+                        break;
+                    }
+                }
+
+                (Rc::new(combinations), complete)
+            }
+        }
+    }
+
+    /// The parses of an expression for the text between two boundaries.
+    fn expression(
+        &mut self,
+        template: &'a TemplateNode,
+        expression: &'a Expression,
+        start: usize,
+        end: usize,
+    ) -> (Parses<'a>, bool) {
+        if !is_rewritten(expression) {
+            return self.rule(&expression.symbol, start, end);
+        }
+
+        let source = &template.source()[expression.span.clone()];
+        let outputs = match self.rewritten.get(source) {
+            Some(Some(outputs)) => outputs,
+            Some(None) => {
+                self.unsupported.get_or_insert_with(|| source.to_string());
+                return (Rc::new(Vec::new()), true);
+            }
+            // the parses found without its outputs are missing some, so are not remembered
+            None => {
+                self.missing
+                    .insert(source.to_string(), expression.symbol.clone());
+                return (Rc::new(Vec::new()), false);
+            }
+        };
+
+        let piece = self.chart.piece(start, end);
+        let parses = outputs
+            .iter()
+            .filter(|(output, _)| output == piece)
+            .map(|(_, parse)| parse.clone())
+            .collect();
+
+        (Rc::new(parses), true)
+    }
+}
+
+#[cfg(test)]
+mod recognizer_tests {
+    use crate::generation::test_support::{define, grammar};
+    use crate::generation::{CalyxError, Grammar, Options};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn parse_count(grammar: &mut Grammar, text: &str) -> usize {
+        let trees = grammar.parse(text).expect("Error during parsing");
+        assert!(trees.iter().all(|tree| tree.flatten() == text));
+        trees.len()
+    }
+
+    #[test]
+    fn finds_every_derivation() {
+        let mut grammar = grammar(&[
+            ("start", &["{a}{b}", "xy"]),
+            ("a", &["x", ""]),
+            ("b", &["y", "xy"]),
+        ]);
+
+        assert_eq!(parse_count(&mut grammar, "xy"), 3);
+        assert_eq!(parse_count(&mut grammar, "y"), 1);
+        assert_eq!(parse_count(&mut grammar, "yx"), 0);
+        assert_eq!(parse_count(&mut grammar, ""), 0);
+    }
+
+    #[test]
+    fn recursive_rules() {
+        let mut grammar = grammar(&[("start", &["({start})", "{start}", "x"])]);
+
+        // repeating the rule without generating more text is left out
+        assert_eq!(parse_count(&mut grammar, "x"), 1);
+        assert_eq!(parse_count(&mut grammar, "((x))"), 1);
+        assert_eq!(parse_count(&mut grammar, "((x)"), 0);
+    }
+
+    #[test]
+    fn splits_of_many_expressions_are_shared() {
+        let template = format!("{}X", "{w}".repeat(20));
+        let mut grammar = grammar(&[("start", &[template.as_str()]), ("w", &["a", "aa", ""])]);
+
+        // without sharing the splits of the later expressions, this takes exponential time
+        assert_eq!(parse_count(&mut grammar, &"a".repeat(40)), 0);
+        assert_eq!(
+            parse_count(&mut grammar, &format!("{}X", "a".repeat(40))),
+            1
+        );
+    }
+
+    #[test]
+    fn memoized_and_unique_expressions() {
+        let mut grammar = grammar(&[
+            ("start", &["{@name} and {@name}", "{$name} {$name}"]),
+            ("name", &["ada", "grace", "ada"]),
+        ]);
+
+        assert_eq!(parse_count(&mut grammar, "ada and ada"), 2);
+        assert_eq!(parse_count(&mut grammar, "ada and grace"), 0);
+        assert_eq!(parse_count(&mut grammar, "ada ada"), 2);
+        assert_eq!(parse_count(&mut grammar, "grace ada"), 2);
+        assert_eq!(parse_count(&mut grammar, "grace grace"), 0);
+    }
+
+    #[test]
+    fn filters_and_mappings_of_finite_rules() {
+        let mut grammar = grammar(&[
+            ("start", &["{name.uppercase}!", "{@name} {@name.uppercase}"]),
+            ("name", &["ada", "{title} grace"]),
+            ("title", &["dr", "prof"]),
+        ]);
+
+        let trees = grammar.parse("DR GRACE!").expect("Error during parsing");
+        assert_eq!(trees.len(), 1);
+        assert_eq!(trees[0].flatten(), "DR GRACE!");
+
+        assert_eq!(parse_count(&mut grammar, "prof grace PROF GRACE"), 1);
+        assert_eq!(parse_count(&mut grammar, "ada GRACE"), 0);
+    }
+
+    #[test]
+    fn filters_of_infinite_rules_are_unsupported() {
+        let mut grammar = grammar(&[
+            ("start", &["{number.uppercase}"]),
+            ("number", &["1", "{number}1"]),
+        ]);
+
+        assert!(matches!(
+            grammar.parse("11"),
+            Err(CalyxError::Unsupported { ref expression, .. }) if expression == "{number.uppercase}"
+        ));
+        assert_eq!(grammar.parse_from("number", "11").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn filters_of_infinite_rules_are_only_matched_when_needed() {
+        let mut either = grammar(&[
+            ("start", &["x", "{num.uppercase}"]),
+            ("num", &["a", "a{num}"]),
+        ]);
+
+        assert_eq!(parse_count(&mut either, "x"), 1);
+        assert!(matches!(
+            either.parse("AA"),
+            Err(CalyxError::Unsupported { ref expression, .. }) if expression == "{num.uppercase}"
+        ));
+
+        // the outputs of expressions are only generated once they are met
+        let mut unreachable = grammar(&[
+            ("start", &["x{finite.uppercase}", "y{num.uppercase}"]),
+            ("finite", &["a", "b"]),
+            ("num", &["a", "a{num}"]),
+        ]);
+        assert_eq!(parse_count(&mut unreachable, "xB"), 1);
+    }
+
+    #[test]
+    fn undefined_rules() {
+        let rules: &[(&str, &[&str])] = &[("start", &["a{missing}b"])];
+
+        let mut lenient = grammar(rules);
+        assert_eq!(parse_count(&mut lenient, "ab"), 1);
+
+        let mut strict = Grammar::with_options(Options::new(true, StdRng::seed_from_u64(0)));
+        define(&mut strict, rules);
+        assert_eq!(parse_count(&mut strict, "ab"), 0);
+    }
+
+    #[test]
+    fn recovers_generated_derivations() {
+        let mut grammar = grammar(&[
+            ("start", &["{@hero} met {villain}. {@hero} won{ending}"]),
+            ("hero", &["{title} {name}", "{name}"]),
+            ("villain", &["the {adjective} {monster}", "{name}"]),
+            ("title", &["Sir", "Dame"]),
+            ("name", &["Ada", "Grace", "Alan"]),
+            ("adjective", &["dark", "old", ""]),
+            ("monster", &["dragon", "wyrm"]),
+            ("ending", &[".", "!", "{ending}{ending}"]),
+        ]);
+
+        for _ in 0..20 {
+            let tree = grammar.generate().expect("Error during generation");
+            let (text, spans) = tree.flatten_with_spans();
+
+            let trees = grammar.parse(&text).expect("Error during parsing");
+            assert!(
+                trees
+                    .iter()
+                    .any(|parsed| parsed.flatten_with_spans().1 == spans),
+                "{:?} was not parsed back",
+                text
+            );
+        }
+    }
+}
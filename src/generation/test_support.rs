@@ -0,0 +1,18 @@
+use crate::generation::Grammar;
+use rand::SeedableRng;
+use rand::prelude::StdRng;
+
+/// Creates a lenient grammar with a seeded generator, made of uniform rules.
+pub(crate) fn grammar(rules: &[(&str, &[&str])]) -> Grammar {
+    let mut grammar = Grammar::with_rng(StdRng::seed_from_u64(12345));
+    define(&mut grammar, rules);
+    grammar
+}
+
+/// Adds uniform rules to a grammar.
+pub(crate) fn define(grammar: &mut Grammar, rules: &[(&str, &[&str])]) {
+    for (term, templates) in rules {
+        let templates: Vec<String> = templates.iter().map(|t| t.to_string()).collect();
+        assert!(grammar.uniform_rule(term.to_string(), &templates).is_ok());
+    }
+}